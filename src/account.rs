@@ -0,0 +1,301 @@
+use imap_codec::imap_types::secret::Secret;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::{Duration, Instant};
+use crate::api::{self, MailboxId};
+use crate::auth::UserId;
+use crate::lock;
+use crate::state::{self, AccountState};
+
+/// Ce que toutes les connexions d'un compte partagent : la session
+/// EcoleDirecte (un seul jeton pour toutes), les dossiers et la
+/// dernière liste des messages de chaque dossier. Chaque connexion compare ce
+/// qu'elle a montré au client à cette liste pour lui envoyer les changements
+/// faits par les autres.
+pub struct Session {
+    pub id: UserId,
+    // Derniers identifiant et mot de passe acceptés par EcoleDirecte, pour se
+    // reconnecter quand le jeton n'est plus valable
+    credentials: Mutex<(String, Secret<String>)>,
+    token: Mutex<String>,
+    // Pris pendant une reconnexion : les autres connexions attendent le
+    // nouveau jeton
+    relogin: Mutex<()>,
+    pub state: Arc<Mutex<AccountState>>,
+    folders: Mutex<Option<HashMap<String, MailboxId>>>,
+    mailboxes: Mutex<HashMap<MailboxId, Arc<Mailbox>>>,
+}
+
+// Un dossier : sa dernière liste, et un verrou pris pendant les requêtes à
+// EcoleDirecte sur ses messages (pas celui de la liste, pour ne pas bloquer
+// les autres connexions qui ne font que la lire)
+#[derive(Default)]
+struct Mailbox {
+    requests: Mutex<()>,
+    snapshot: Mutex<Snapshot>,
+}
+
+#[derive(Default)]
+struct Snapshot {
+    // Date de la dernière interrogation (None : à refaire)
+    polled: Option<Instant>,
+    messages: Vec<(u32, Value)>,
+    // UIDs des messages marqués \Deleted (EcoleDirecte ne connaît pas ce
+    // drapeau, il n'existe que jusqu'à EXPUNGE)
+    deleted: HashSet<u32>,
+}
+
+impl Session {
+    /// Jeton EcoleDirecte actuel.
+    pub fn token(&self) -> String {
+        lock(&self.token).clone()
+    }
+
+    /// Requête à EcoleDirecte avec le jeton de la session. S'il n'est plus
+    /// valable, on se reconnecte et on réessaie une fois.
+    pub fn call<T, E, F>(&self, client: &api::Client, request: F) -> Result<T, E>
+    where
+        E: api::Expiry,
+        F: Fn(&str) -> Result<T, E>,
+    {
+        let token = self.token();
+        match request(&token) {
+            Err(error) if error.token_expired() => match self.relogin(client, &token) {
+                Ok(token) => request(&token),
+                Err(login_error) => {
+                    tracing::warn!(error = %login_error, "login after token expiry failed");
+                    Err(error)
+                }
+            },
+            result => result,
+        }
+    }
+
+    // Nouveau jeton, sauf si une autre connexion a remplacé `expired` entre-temps
+    fn relogin(&self, client: &api::Client, expired: &str) -> Result<String, api::Error> {
+        let _relogin = lock(&self.relogin);
+        let token = self.token();
+        if token != expired {
+            return Ok(token);
+        }
+        let (username, password) = lock(&self.credentials).clone();
+        let (_, token) = api::login(client, &username, password.declassify())?;
+        *lock(&self.token) = token.clone();
+        Ok(token)
+    }
+
+    fn mailbox(&self, mailbox_id: &MailboxId) -> Arc<Mailbox> {
+        lock(&self.mailboxes).entry(*mailbox_id).or_default().clone()
+    }
+
+    fn snapshot<R, F: FnOnce(&mut Snapshot) -> R>(&self, mailbox_id: &MailboxId, f: F) -> R {
+        f(&mut lock(&self.mailbox(mailbox_id).snapshot))
+    }
+
+    /// Dossiers du compte, demandés avec `get_folders` la première fois ou si
+    /// `refresh`.
+    pub fn folders<E, F>(&self, refresh: bool, get_folders: F) -> Result<HashMap<String, MailboxId>, E>
+    where
+        F: FnOnce() -> Result<HashMap<String, MailboxId>, E>,
+    {
+        if let (Some(folders), false) = (&*lock(&self.folders), refresh) {
+            return Ok(folders.clone());
+        }
+        let folders = get_folders()?;
+        *lock(&self.folders) = Some(folders.clone());
+        Ok(folders)
+    }
+
+    /// Dossier d'un nom, parmi ceux qu'on connaît déjà.
+    pub fn mailbox_id(&self, name: &str) -> Option<MailboxId> {
+        lock(&self.folders).as_ref()?.get(name).copied()
+    }
+
+    /// Messages d'un dossier, redemandés avec `get_messages` si la dernière
+    /// liste a plus de `max_age` (toujours sans). Les connexions qui
+    /// regardent le même dossier en même temps attendent la même réponse.
+    pub fn messages<E, F>(&self, mailbox_id: &MailboxId, max_age: Option<Duration>, get_messages: F) -> Result<Vec<(u32, Value)>, E>
+    where
+        F: FnOnce() -> Result<Vec<(u32, Value)>, E>,
+    {
+        let mailbox = self.mailbox(mailbox_id);
+        let recent = |snapshot: &Snapshot| match (snapshot.polled, max_age) {
+            (Some(polled), Some(max_age)) if polled.elapsed() < max_age => Some(snapshot.messages.clone()),
+            _ => None,
+        };
+        if let Some(messages) = recent(&lock(&mailbox.snapshot)) {
+            return Ok(messages);
+        }
+        let _requests = lock(&mailbox.requests);
+        // Une autre connexion vient peut-être de la demander
+        if let Some(messages) = recent(&lock(&mailbox.snapshot)) {
+            return Ok(messages);
+        }
+        let messages = get_messages()?;
+        let mut snapshot = lock(&mailbox.snapshot);
+        snapshot.messages = messages.clone();
+        snapshot.polled = Some(Instant::now());
+        Ok(messages)
+    }
+
+    /// Remplace la liste d'un dossier par une qu'on vient d'obtenir autrement.
+    pub fn update(&self, mailbox_id: &MailboxId, messages: Vec<(u32, Value)>) {
+        self.snapshot(mailbox_id, |snapshot| {
+            snapshot.messages = messages;
+            snapshot.polled = Some(Instant::now());
+        });
+    }
+
+    /// La liste devra être redemandée (après un changement fait par une
+    /// connexion, pour que les autres le voient).
+    pub fn invalidate(&self, mailbox_id: &MailboxId) {
+        self.snapshot(mailbox_id, |snapshot| snapshot.polled = None);
+    }
+
+    pub fn deleted(&self, mailbox_id: &MailboxId) -> HashSet<u32> {
+        self.snapshot(mailbox_id, |snapshot| snapshot.deleted.clone())
+    }
+
+    pub fn is_deleted(&self, mailbox_id: &MailboxId, uid: u32) -> bool {
+        self.snapshot(mailbox_id, |snapshot| snapshot.deleted.contains(&uid))
+    }
+
+    /// Marque des messages \Deleted ou non. Ceux qui changent ont un nouveau
+    /// MODSEQ.
+    pub fn set_deleted(&self, mailbox_id: &MailboxId, uids: &[u32], deleted: bool) {
+        let changed: Vec<u32> = self.snapshot(mailbox_id, |snapshot| {
+            uids.iter()
+                .filter(|uid| if deleted { snapshot.deleted.insert(**uid) } else { snapshot.deleted.remove(*uid) })
+                .copied()
+                .collect()
+        });
+        lock(&self.state).touch(mailbox_id, &changed);
+    }
+
+    /// Donne les messages marqués \Deleted à EXPUNGE, qui retire ceux qu'il
+    /// a supprimés. Un seul EXPUNGE à la fois par dossier, mais les autres
+    /// connexions peuvent lire et changer les drapeaux pendant ce temps.
+    pub fn expunge<R, F: FnOnce(&mut HashSet<u32>) -> R>(&self, mailbox_id: &MailboxId, expunge: F) -> R {
+        let mailbox = self.mailbox(mailbox_id);
+        let _requests = lock(&mailbox.requests);
+        let before = lock(&mailbox.snapshot).deleted.clone();
+        let mut deleted = before.clone();
+        let result = expunge(&mut deleted);
+        let mut snapshot = lock(&mailbox.snapshot);
+        snapshot.polled = None;
+        for uid in before.difference(&deleted) {
+            snapshot.deleted.remove(uid);
+        }
+        result
+    }
+}
+
+/// Session d'un compte : celle des autres connexions s'il en a déjà une,
+/// sinon une nouvelle. Le mot de passe est vérifié par EcoleDirecte avec
+/// `login` à chaque fois. La session disparaît avec la dernière connexion qui
+/// l'utilise.
+pub fn log_in<F>(state_dir: &Path, username: &str, password: &str, login: F) -> Result<Arc<Session>, api::Error>
+where
+    F: FnOnce() -> Result<(UserId, String), api::Error>,
+{
+    static SESSIONS: OnceLock<Mutex<HashMap<UserId, Weak<Session>>>> = OnceLock::new();
+
+    let (id, token) = login()?;
+    let credentials = (username.to_string(), Secret::new(password.to_string()));
+    let mut sessions = lock(SESSIONS.get_or_init(Default::default));
+    sessions.retain(|_, session| session.strong_count() > 0);
+    if let Some(session) = sessions.get(&id).and_then(Weak::upgrade) {
+        *lock(&session.credentials) = credentials;
+        *lock(&session.token) = token;
+        return Ok(session);
+    }
+    let session = Arc::new(Session {
+        id,
+        credentials: Mutex::new(credentials),
+        token: Mutex::new(token),
+        relogin: Mutex::new(()),
+        state: state::open(state_dir, id),
+        folders: Mutex::new(None),
+        mailboxes: Mutex::new(HashMap::new()),
+    });
+    sessions.insert(id, Arc::downgrade(&session));
+    Ok(session)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::cell::Cell;
+    use std::env;
+    use std::process;
+    use crate::api::tests::server;
+
+    // Session avec le jeton "ancien", dans un dossier d'état à part
+    fn session(id: u32) -> Arc<Session> {
+        let state_dir = env::temp_dir().join(format!("ecoledirecte-imap-test-account-{}", process::id()));
+        log_in(&state_dir, "jean", "secret", || Ok((UserId::Eleve(id), String::from("ancien")))).unwrap()
+    }
+
+    // Requête qui n'accepte que le jeton "nouveau"
+    fn request(token: &str) -> Result<String, api::Error> {
+        if token == "nouveau" {
+            Ok(token.to_string())
+        } else {
+            Err(api::Error::Token)
+        }
+    }
+
+    #[test]
+    fn relogin_after_token_expiry() {
+        let (client, requests) = server(|route, _, _| {
+            assert!(route.starts_with("/v3/login.awp"), "{}", route);
+            json!({ "code": 200, "token": "nouveau", "data": { "accounts": [{ "id": 1, "typeCompte": "E" }] } })
+        });
+        let session = session(1);
+
+        assert_eq!(session.call(&client, request).unwrap(), "nouveau");
+        assert_eq!(session.token(), "nouveau");
+        assert_eq!(requests.lock().unwrap()[0].1, json!({ "identifiant": "jean", "motdepasse": "secret" }));
+        // Une autre connexion avait encore l'ancien jeton : pas besoin de se
+        // reconnecter une deuxième fois
+        assert_eq!(session.relogin(&client, "ancien").unwrap(), "nouveau");
+        assert_eq!(session.call(&client, request).unwrap(), "nouveau");
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn failed_relogin() {
+        let (client, requests) = server(|_, _, _| json!({ "code": 505, "message": "Mot de passe invalide" }));
+        let session = session(2);
+
+        // L'erreur est celle de la requête, pas celle de la reconnexion
+        assert!(matches!(session.call(&client, request), Err(api::Error::Token)));
+        assert_eq!(session.token(), "ancien");
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn messages_are_shared_until_invalidated() {
+        let session = session(3);
+        let inbox = MailboxId::Received(0);
+        let polls = Cell::new(0);
+        let get_messages = || {
+            polls.set(polls.get() + 1);
+            Ok::<_, ()>(vec![(1, json!({ "id": polls.get() }))])
+        };
+        let max_age = Some(Duration::from_secs(60));
+
+        assert_eq!(session.messages(&inbox, max_age, get_messages).unwrap()[0].1["id"], 1);
+        assert_eq!(session.messages(&inbox, max_age, get_messages).unwrap()[0].1["id"], 1);
+        // Sans durée, la liste est toujours redemandée
+        assert_eq!(session.messages(&inbox, None, get_messages).unwrap()[0].1["id"], 2);
+        session.invalidate(&inbox);
+        assert_eq!(session.messages(&inbox, max_age, get_messages).unwrap()[0].1["id"], 3);
+        // Les autres dossiers ont leur propre liste
+        assert_eq!(session.messages(&MailboxId::Sent, max_age, get_messages).unwrap()[0].1["id"], 4);
+        assert_eq!(polls.get(), 4);
+    }
+}
@@ -1,20 +1,140 @@
-use reqwest::{
-    blocking::{Client, RequestBuilder},
-    header::USER_AGENT,
-    Url,
-};
-use serde_json::{json, Value};
-use std::collections::HashMap;
 use crate::auth::UserId;
 use crate::auth::UserId::{Eleve, Famille};
+use crate::config;
+use reqwest::{blocking::RequestBuilder, Proxy, Url};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::thread;
+use std::time::Duration;
+
+pub const API_VERSION: &str = "4.43.0";
+
+// Nombre maximal de requêtes envoyées en même temps pour un compte
+const PARALLEL_REQUESTS: usize = 8;
+
+/// Client HTTP pour EcoleDirecte, avec l'adresse de l'API et les réglages
+/// (proxy, délais...) de la configuration.
+pub struct Client {
+    http: reqwest::blocking::Client,
+    base_url: Url,
+}
+
+impl Client {
+    pub fn new(config: &config::Api) -> Result<Client, String> {
+        let mut base_url = Url::parse(&config.url).map_err(|error| error.to_string())?;
+        // Les routes sont ajoutées au chemin de l'adresse de base
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&format!("{}/", base_url.path()));
+        }
+        let mut builder = reqwest::blocking::Client::builder()
+            .user_agent(&config.user_agent)
+            .timeout(Duration::from_secs(config.timeout))
+            .connect_timeout(Duration::from_secs(config.connect_timeout));
+        if let Some(proxy) = &config.proxy {
+            builder = builder.proxy(Proxy::all(proxy).map_err(|error| error.to_string())?);
+        }
+        let http = builder.build().map_err(|error| error.to_string())?;
+        Ok(Client { http, base_url })
+    }
+}
+
+/// Échec d'une requête à EcoleDirecte.
+#[derive(Clone, Debug)]
+pub enum Error {
+    /// Le jeton n'est plus valable : il faut se reconnecter
+    Token,
+    /// Requête refusée, avec la raison si EcoleDirecte en donne une
+    Refused(Option<String>),
+    /// Pas de réponse (délai dépassé, réseau...) ou une réponse illisible
+    Request(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Token => f.write_str("session expired"),
+            Error::Refused(Some(message)) => f.write_str(message),
+            Error::Refused(None) => f.write_str("request refused"),
+            Error::Request(message) => f.write_str(message),
+        }
+    }
+}
+
+// Pour les réponses qui donnent la raison d'un échec quand il y en a une
+impl From<Error> for Option<String> {
+    fn from(error: Error) -> Option<String> {
+        match error {
+            Error::Refused(message) => message,
+            error => Some(error.to_string()),
+        }
+    }
+}
+
+/// Erreur qui peut venir d'un jeton expiré (voir `account::Session::call`).
+pub trait Expiry {
+    fn token_expired(&self) -> bool;
+}
+
+impl Expiry for Error {
+    fn token_expired(&self) -> bool {
+        matches!(self, Error::Token)
+    }
+}
+
+// Échecs message par message
+impl<T> Expiry for Vec<(T, Error)> {
+    fn token_expired(&self) -> bool {
+        self.iter().any(|(_, error)| error.token_expired())
+    }
+}
+
+// Les réponses IMAP n'acceptent que de l'ASCII sur une seule ligne : les
+// messages d'EcoleDirecte perdent leurs accents et leurs retours à la ligne
+fn text(message: &str) -> Option<String> {
+    let text: String = message
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+        .map(|c| match c {
+            ' '..='~' => c,
+            'à' | 'â' | 'ä' => 'a',
+            'À' | 'Â' | 'Ä' => 'A',
+            'é' | 'è' | 'ê' | 'ë' => 'e',
+            'É' | 'È' | 'Ê' | 'Ë' => 'E',
+            'î' | 'ï' => 'i',
+            'Î' | 'Ï' => 'I',
+            'ô' | 'ö' => 'o',
+            'Ô' | 'Ö' => 'O',
+            'ù' | 'û' | 'ü' => 'u',
+            'Ù' | 'Û' | 'Ü' => 'U',
+            'ç' => 'c',
+            'Ç' => 'C',
+            '’' => '\'',
+            _ => '?',
+        })
+        .collect();
+    (!text.is_empty()).then_some(text)
+}
 
-const API_VERSION: &str = "4.43.0";
+fn request_error(error: reqwest::Error) -> Error {
+    Error::Request(
+        text(&error.without_url().to_string()).unwrap_or_else(|| String::from("request failed")),
+    )
+}
+
+fn invalid_response() -> Error {
+    Error::Request("Invalid response from EcoleDirecte".to_string())
+}
 
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub enum MailboxId {
     Received(u32),
     Sent,
     Draft,
     Archived,
+    Deleted,
 }
 
 fn build_request<'a>(
@@ -25,22 +145,32 @@ fn build_request<'a>(
     json_params: Value,
     token: &str,
 ) -> RequestBuilder {
-    let BASE_URL = Url::parse("https://api.ecoledirecte.com/").unwrap();
     qs_params.insert("verbe", verbe);
     qs_params.insert("v", API_VERSION);
-    let url = Url::parse_with_params(BASE_URL.join(route).unwrap().as_str(), qs_params).unwrap();
+    let route = route.trim_start_matches('/');
+    let url =
+        Url::parse_with_params(client.base_url.join(route).unwrap().as_str(), qs_params).unwrap();
     client
+        .http
         .post(url)
-        .header(USER_AGENT, "ecoledirecte-imap")
         .header("X-Token", token)
         .body("data=".to_owned() + &json_params.to_string())
 }
 
-pub fn login(
-    client: &Client,
-    username: &str,
-    password: &str,
-) -> Result<(UserId, String), Option<String>> {
+// Réponse d'EcoleDirecte, si elle a accepté la requête
+fn send(request: RequestBuilder) -> Result<Value, Error> {
+    let response: Value = request
+        .send()
+        .and_then(|response| response.json())
+        .map_err(request_error)?;
+    match response["code"].as_u64() {
+        Some(200) => Ok(response),
+        Some(520 | 525) => Err(Error::Token),
+        _ => Err(Error::Refused(response["message"].as_str().and_then(text))),
+    }
+}
+
+pub fn login(client: &Client, username: &str, password: &str) -> Result<(UserId, String), Error> {
     let request = build_request(
         client,
         "",
@@ -52,34 +182,33 @@ pub fn login(
         }),
         "",
     );
-    let response: Value = request.send().unwrap().json().unwrap();
-
-    if response["code"] == json!(200) {
-        let user_id = response["data"]["accounts"][0]["id"]
-            .as_u64()
-            .unwrap()
-            .try_into()
-            .unwrap();
-        let user = if response["data"]["accounts"][0]["typeCompte"] == "1" {
-            Famille(user_id)
-        } else {
-            Eleve(user_id)
-        };
-        Ok((
-            user,
-            response["token"].as_str().unwrap().to_string(),
-        ))
+    let response = send(request)?;
+
+    let user_id = response["data"]["accounts"][0]["id"]
+        .as_u64()
+        .and_then(|id| id.try_into().ok())
+        .ok_or_else(invalid_response)?;
+    let user = if response["data"]["accounts"][0]["typeCompte"] == "1" {
+        Famille(user_id)
     } else {
-        Err(response["message"].as_str().map(|s: &str| s.to_string()))
-    }
+        Eleve(user_id)
+    };
+    let token = response["token"].as_str().ok_or_else(invalid_response)?;
+    Ok((user, token.to_string()))
 }
 
-pub fn get_folder_info(client: &Client, mailbox_id: &MailboxId, user_id: UserId, token: &str) -> Value {
+pub fn get_folder_info(
+    client: &Client,
+    mailbox_id: &MailboxId,
+    user_id: UserId,
+    token: &str,
+) -> Result<Value, Error> {
     let (type_recuperation, classeur_id) = match mailbox_id {
         MailboxId::Received(id) => (if *id == 0 { "received" } else { "classeur" }, *id),
         MailboxId::Sent => ("sent", 0),
         MailboxId::Draft => ("draft", 0),
         MailboxId::Archived => ("archived", 0),
+        MailboxId::Deleted => ("deleted", 0),
     };
     let classeur_id = classeur_id.to_string();
     let url = match user_id {
@@ -100,42 +229,60 @@ pub fn get_folder_info(client: &Client, mailbox_id: &MailboxId, user_id: UserId,
         json!({}),
         token,
     );
-    request.send().unwrap().json::<Value>().unwrap()["data"].take()
+    Ok(send(request)?["data"].take())
 }
 
-pub fn get_messages(client: &Client, id: UserId, token: &str, mailbox_id: &MailboxId) -> Vec<(u32, serde_json::Value)> {
+// Les messages mal formés sont ignorés, mais sans liste on ne sait rien du
+// dossier
+pub fn folder_messages(
+    folder: &Value,
+    mailbox_id: &MailboxId,
+) -> Result<Vec<(u32, serde_json::Value)>, Error> {
     let category = match mailbox_id {
         MailboxId::Received(_id) => "received",
         MailboxId::Sent => "sent",
         MailboxId::Draft => "draft",
         MailboxId::Archived => "archived",
+        MailboxId::Deleted => "deleted",
     };
-    get_folder_info(client, mailbox_id, id, token)["messages"][category]
+    Ok(folder["messages"][category]
         .as_array()
-        .unwrap()
-        .into_iter()
-        .map(|message| {
-            (
-                message["id"].as_u64().unwrap() as u32,
-                message.clone(),
-            )
-        })
-        .collect()
+        .ok_or_else(invalid_response)?
+        .iter()
+        .filter_map(|message| Some((message["id"].as_u64()? as u32, message.clone())))
+        .collect())
+}
+
+pub fn get_messages(
+    client: &Client,
+    id: UserId,
+    token: &str,
+    mailbox_id: &MailboxId,
+) -> Result<Vec<(u32, serde_json::Value)>, Error> {
+    folder_messages(&get_folder_info(client, mailbox_id, id, token)?, mailbox_id)
 }
 
-pub fn get_message(client: &Client, user_id: UserId, token: &str, mailbox_id: &MailboxId, message_id: u32) -> serde_json::Value {
+fn message_request(
+    client: &Client,
+    user_id: UserId,
+    token: &str,
+    mailbox_id: &MailboxId,
+    message_id: u32,
+) -> RequestBuilder {
     let message_id = message_id.to_string();
     let url = match user_id {
         Eleve(user_id) => format!("/v3/eleves/{user_id}/messages/{message_id}.awp"),
         Famille(user_id) => format!("/v3/familles/{user_id}/messages/{message_id}.awp"),
     };
+    // Les archives et la corbeille ne contiennent que des messages reçus
     let mode = match mailbox_id {
         MailboxId::Received(_) => "destinataire",
         MailboxId::Sent => "expediteur",
         MailboxId::Draft => "expediteur",
-        MailboxId::Archived => todo!(),
+        MailboxId::Archived => "destinataire",
+        MailboxId::Deleted => "destinataire",
     };
-    let request = build_request(
+    build_request(
         client,
         "get",
         &url,
@@ -146,17 +293,31 @@ pub fn get_message(client: &Client, user_id: UserId, token: &str, mailbox_id: &M
         },
         json!({}),
         token,
-    );
-    request.send().unwrap().json::<Value>().unwrap()["data"].take()
+    )
+}
+
+pub fn get_message(
+    client: &Client,
+    user_id: UserId,
+    token: &str,
+    mailbox_id: &MailboxId,
+    message_id: u32,
+) -> Result<serde_json::Value, Error> {
+    let request = message_request(client, user_id, token, mailbox_id, message_id);
+    Ok(send(request)?["data"].take())
 }
 
-pub fn get_attachment(client: &Client, token: &str, attachment_id: u32) -> bytes::Bytes {
+pub fn get_attachment(
+    client: &Client,
+    token: &str,
+    attachment_id: u32,
+) -> Result<bytes::Bytes, Error> {
     let attachment_id = attachment_id.to_string();
     let url = "/v3/telechargement.awp";
     let request = build_request(
         client,
         "get",
-        &url,
+        url,
         {
             let mut qs = HashMap::<&str, &str>::new();
             qs.insert("fichierId", attachment_id.as_str());
@@ -166,49 +327,333 @@ pub fn get_attachment(client: &Client, token: &str, attachment_id: u32) -> bytes
         json!({}),
         token,
     );
-    request.send().unwrap().bytes().unwrap()
+    request
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.bytes())
+        .map_err(request_error)
 }
 
-pub fn get_folders(client: &Client, user_id: UserId, token: &str) -> Vec<(String, u32)> {
-    get_folder_info(client, &MailboxId::Received(0), user_id, token)["classeurs"]
-        .as_array()
-        .unwrap()
-        .into_iter()
-        .map(|classeur| {
-            (
-                classeur["libelle"].as_str().unwrap().to_string(),
-                classeur["id"].as_u64().unwrap() as u32,
-            )
+pub fn get_folders(
+    client: &Client,
+    user_id: UserId,
+    token: &str,
+) -> Result<Vec<(String, u32)>, Error> {
+    Ok(
+        get_folder_info(client, &MailboxId::Received(0), user_id, token)?["classeurs"]
+            .as_array()
+            .ok_or_else(invalid_response)?
+            .iter()
+            .filter_map(|classeur| {
+                Some((
+                    classeur["libelle"].as_str()?.to_string(),
+                    classeur["id"].as_u64()? as u32,
+                ))
+            })
+            .collect(),
+    )
+}
+
+fn messages_action(
+    client: &Client,
+    user_id: UserId,
+    token: &str,
+    action: Value,
+) -> Result<(), Error> {
+    let url = match user_id {
+        Eleve(user_id) => format!("/v3/eleves/{user_id}/messages.awp"),
+        Famille(user_id) => format!("/v3/familles/{user_id}/messages.awp"),
+    };
+    let request = build_request(client, "put", &url, HashMap::new(), action, token);
+    send(request).map(drop)
+}
+
+// Ouvrir un message le marque lu : un message à la fois, mais plusieurs en
+// même temps
+fn read_messages(
+    client: &Client,
+    user_id: UserId,
+    token: &str,
+    mailbox_id: &MailboxId,
+    message_ids: &[u32],
+) -> Result<(), Vec<(u32, Error)>> {
+    let read = |message_id: u32| -> Result<(), Error> {
+        let request = message_request(client, user_id, token, mailbox_id, message_id);
+        send(request).map(drop)
+    };
+    let failures: Vec<(u32, Error)> = message_ids
+        .chunks(PARALLEL_REQUESTS)
+        .flat_map(|chunk| {
+            thread::scope(|s| {
+                let reads: Vec<_> = chunk
+                    .iter()
+                    .map(|&message_id| (message_id, s.spawn(move || read(message_id))))
+                    .collect();
+                reads
+                    .into_iter()
+                    .filter_map(|(message_id, read)| match read.join() {
+                        Ok(Ok(())) => None,
+                        Ok(Err(message)) => Some((message_id, message)),
+                        Err(_) => Some((message_id, Error::Refused(None))),
+                    })
+                    .collect::<Vec<_>>()
+            })
         })
-        .collect()
+        .collect();
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(failures)
+    }
 }
 
-pub fn set_read_status(client: &Client, user_id: UserId, token: &str, read_status: bool, message_ids: &Vec<u32>) {
-    match read_status {
-        true => {
-            // a) only way to mark a message as read is to request it
-            // b) only messages in INBOX can be marked as unread
-            let _ = message_ids
-                .iter()
-                .map(|message_id| get_message(client, user_id, token, &MailboxId::Received(0), *message_id));
-        },
-        false => {
-            let url = match user_id {
-                Eleve(user_id) => format!("/v3/eleves/{user_id}/messages.awp"),
-                Famille(user_id) => format!("/v3/familles/{user_id}/messages.awp"),
-            };
-            let request = build_request(
+/// Marque des messages du dossier `mailbox_id` lus ou non lus. En cas
+/// d'échec, renvoie les messages qui n'ont pas changé, avec la raison.
+pub fn set_read_status(
+    client: &Client,
+    user_id: UserId,
+    token: &str,
+    mailbox_id: &MailboxId,
+    read_status: bool,
+    message_ids: &[u32],
+) -> Result<(), Vec<(u32, Error)>> {
+    let action = if read_status {
+        "marquerCommeLu"
+    } else {
+        "marquerCommeNonLu"
+    };
+    match messages_action(
+        client,
+        user_id,
+        token,
+        json!({
+            "action": action,
+            "ids": message_ids,
+        }),
+    ) {
+        Ok(()) => Ok(()),
+        // Sans l'action, il reste à ouvrir les messages
+        Err(error) if read_status && !error.token_expired() => {
+            read_messages(client, user_id, token, mailbox_id, message_ids)
+        }
+        Err(error) => Err(message_ids
+            .iter()
+            .map(|message_id| (*message_id, error.clone()))
+            .collect()),
+    }
+}
+
+// Les messages ne changent pas d'id quand ils changent de dossier.
+pub fn move_messages(
+    client: &Client,
+    user_id: UserId,
+    token: &str,
+    from: &MailboxId,
+    to: &MailboxId,
+    message_ids: &[u32],
+) -> Result<(), Error> {
+    match (from, to) {
+        (MailboxId::Received(_), MailboxId::Archived) => messages_action(
+            client,
+            user_id,
+            token,
+            json!({
+                "action": "archiver",
+                "ids": message_ids,
+            }),
+        ),
+        (MailboxId::Archived, MailboxId::Received(classeur_id)) => {
+            messages_action(
                 client,
-                "put",
-                &url,
-                HashMap::new(),
+                user_id,
+                token,
                 json!({
-                    "action": "marquerCommeNonLu",
+                    "action": "desarchiver",
                     "ids": message_ids,
                 }),
-                token,
-            );
-            request.send().unwrap();
+            )?;
+            // desarchiver remet les messages dans la boîte de réception
+            if *classeur_id != 0 {
+                move_messages(
+                    client,
+                    user_id,
+                    token,
+                    &MailboxId::Received(0),
+                    to,
+                    message_ids,
+                )
+            } else {
+                Ok(())
+            }
         }
+        (MailboxId::Received(_), MailboxId::Received(classeur_id)) => messages_action(
+            client,
+            user_id,
+            token,
+            json!({
+                "action": "deplacer",
+                "idClasseur": classeur_id,
+                "ids": message_ids,
+            }),
+        ),
+        // Comme un EXPUNGE : les messages vont dans la corbeille
+        (from, MailboxId::Deleted) if *from != MailboxId::Deleted => {
+            delete_messages(client, user_id, token, from, message_ids)
+        }
+        _ => Err(Error::Refused(Some(
+            "EcoleDirecte can't move messages between these folders".to_string(),
+        ))),
+    }
+}
+
+// Depuis la corbeille la suppression est définitive, sinon les messages
+// vont dans la corbeille.
+pub fn delete_messages(
+    client: &Client,
+    user_id: UserId,
+    token: &str,
+    mailbox_id: &MailboxId,
+    message_ids: &[u32],
+) -> Result<(), Error> {
+    let action = match mailbox_id {
+        MailboxId::Deleted => "supprimerDefinitivement",
+        _ => "supprimer",
+    };
+    messages_action(
+        client,
+        user_id,
+        token,
+        json!({
+            "action": action,
+            "ids": message_ids,
+        }),
+    )
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    /// Requêtes reçues par un faux EcoleDirecte : route (avec les paramètres)
+    /// et données
+    pub type Requests = Arc<Mutex<Vec<(String, Value)>>>;
+
+    /// Faux EcoleDirecte : `reply` donne la réponse à chaque requête, d'après
+    /// sa route, son jeton et ses données.
+    pub fn server<F: Fn(&str, &str, &Value) -> Value + Send + 'static>(
+        reply: F,
+    ) -> (Client, Requests) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let requests = Requests::default();
+        let received = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = BufReader::new(stream.unwrap());
+                let mut line = String::new();
+                stream.read_line(&mut line).unwrap();
+                let route = line.split(' ').nth(1).unwrap_or_default().to_string();
+                let (mut length, mut token) = (0, String::new());
+                loop {
+                    line.clear();
+                    stream.read_line(&mut line).unwrap();
+                    // Le jeton est vide pour la connexion
+                    match line.trim_end().split_once(':') {
+                        Some((name, value)) if name.eq_ignore_ascii_case("content-length") => {
+                            length = value.trim().parse().unwrap()
+                        }
+                        Some((name, value)) if name.eq_ignore_ascii_case("x-token") => {
+                            token = value.trim().to_string()
+                        }
+                        Some(_) => (),
+                        None => break,
+                    }
+                }
+                let mut body = vec![0; length];
+                stream.read_exact(&mut body).unwrap();
+                let data = serde_json::from_slice(body.strip_prefix(b"data=").unwrap_or_default())
+                    .unwrap_or_default();
+                let response = reply(&route, &token, &data).to_string();
+                received.lock().unwrap().push((route, data));
+                let mut stream = stream.into_inner();
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{response}",
+                    response.len(),
+                )
+                .unwrap();
+            }
+        });
+        let client = Client::new(&config::Api {
+            url,
+            ..config::Api::default()
+        })
+        .unwrap();
+        (client, requests)
+    }
+
+    #[test]
+    fn move_to_trash() {
+        let (client, requests) = server(|_, _, _| json!({ "code": 200, "data": {} }));
+        let user_id = Eleve(1);
+        move_messages(
+            &client,
+            user_id,
+            "token",
+            &MailboxId::Received(0),
+            &MailboxId::Deleted,
+            &[3, 5],
+        )
+        .unwrap();
+        move_messages(
+            &client,
+            user_id,
+            "token",
+            &MailboxId::Archived,
+            &MailboxId::Deleted,
+            &[7],
+        )
+        .unwrap();
+        assert!(move_messages(
+            &client,
+            user_id,
+            "token",
+            &MailboxId::Deleted,
+            &MailboxId::Deleted,
+            &[7]
+        )
+        .is_err());
+        assert!(move_messages(
+            &client,
+            user_id,
+            "token",
+            &MailboxId::Deleted,
+            &MailboxId::Received(0),
+            &[7]
+        )
+        .is_err());
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].0.starts_with("/v3/eleves/1/messages.awp?"));
+        assert_eq!(
+            requests[0].1,
+            json!({ "action": "supprimer", "ids": [3, 5] })
+        );
+        assert_eq!(requests[1].1, json!({ "action": "supprimer", "ids": [7] }));
+    }
+
+    #[test]
+    fn messages_for_imap() {
+        assert_eq!(text("Erreur\r\ninterne").as_deref(), Some("Erreur interne"));
+        assert_eq!(
+            text("Mot de passe  erroné, réessayez ").as_deref(),
+            Some("Mot de passe errone, reessayez")
+        );
+        assert_eq!(text("Accès refusé ✋").as_deref(), Some("Acces refuse ?"));
+        assert_eq!(text(" \r\n"), None);
     }
 }
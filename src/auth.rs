@@ -1,4 +1,5 @@
 use imap_codec::imap_types::{
+    auth::AuthMechanism,
     core::Tag,
     response::{Code, Response, Status},
     secret::Secret,
@@ -7,62 +8,114 @@ use imap_codec::imap_types::{
 use std::str;
 
 use crate::capabilities;
+use crate::tls::Encryption;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub enum UserId {
     Eleve(u32),
     Famille(u32),
 }
 
-#[derive(Clone)]
-pub struct User {
-    pub id: UserId,
-    pub token: String,
+/// Une étape d'un échange SASL.
+pub enum Step {
+    /// Défi à envoyer au client, qui doit y répondre
+    Challenge(Vec<u8>),
+    /// Le client a donné ses identifiants
+    Done { username: String, password: Secret<String> },
+    /// Échange refusé, avec le message pour le client
+    Failed(&'static str),
 }
 
-pub fn parse_plain_message<'a, 'b>(
-    secret: Secret<&'a [u8]>,
-    tag: Tag<'b>,
-) -> Result<(&'a str, &'a str), Vec<Response<'b>>> {
-    let parts: Vec<_> = secret.declassify().split(|c| *c == 0).collect();
-    if parts.len() != 3 {
-        return Err(vec![Response::Status(
-            Status::no(Some(tag), None, "Invalid challenge string").unwrap(),
-        )]);
+/// Un mécanisme SASL de AUTHENTICATE. Les identifiants finissent toujours
+/// chez EcoleDirecte, donc seuls les mécanismes qui donnent le mot de passe
+/// en clair sont possibles.
+pub trait Mechanism: Send {
+    /// Étape suivante, après une réponse du client (None au début de
+    /// l'échange si le client n'a pas envoyé de réponse initiale, RFC 4959).
+    fn step(&mut self, response: Option<&[u8]>) -> Step;
+}
+
+/// PLAIN (RFC 4616) : identité, identifiant et mot de passe en une fois.
+pub struct Plain;
+
+impl Mechanism for Plain {
+    fn step(&mut self, response: Option<&[u8]>) -> Step {
+        let Some(response) = response else {
+            return Step::Challenge(Vec::new());
+        };
+        let parts: Vec<_> = response.split(|c| *c == 0).collect();
+        if parts.len() != 3 {
+            return Step::Failed("Invalid challenge string");
+        }
+
+        let identity = parts[0];
+        let username = parts[1];
+        let password = parts[2];
+
+        if identity != "".as_bytes() && identity != username {
+            return Step::Failed("Invalid identity");
+        }
+
+        match (str::from_utf8(username), str::from_utf8(password)) {
+            (Ok(u), Ok(p)) => Step::Done { username: u.to_string(), password: Secret::new(p.to_string()) },
+            _ => Step::Failed("Challenge must be valid UTF-8"),
+        }
     }
+}
 
-    let identity = parts[0];
-    let username = parts[1];
-    let password = parts[2];
+/// LOGIN (draft-murchison-sasl-login) : l'identifiant puis le mot de passe,
+/// chacun en réponse à une invite. L'identifiant peut venir en réponse
+/// initiale.
+#[derive(Default)]
+pub struct Login {
+    username: Option<String>,
+}
 
-    if identity != "".as_bytes() && identity != username {
-        return Err(vec![Response::Status(
-            Status::no(Some(tag), None, "Invalid identity").unwrap(),
-        )]);
+impl Mechanism for Login {
+    fn step(&mut self, response: Option<&[u8]>) -> Step {
+        let Some(response) = response else {
+            return Step::Challenge(b"Username:".to_vec());
+        };
+        let Ok(response) = str::from_utf8(response) else {
+            return Step::Failed("Credentials must be valid UTF-8");
+        };
+        match self.username.take() {
+            None => {
+                self.username = Some(response.to_string());
+                Step::Challenge(b"Password:".to_vec())
+            }
+            Some(username) => Step::Done { username, password: Secret::new(response.to_string()) },
+        }
     }
+}
+
+/// Mécanismes proposés, dans l'ordre de CAPABILITY.
+pub fn mechanisms() -> [AuthMechanism<'static>; 2] {
+    [AuthMechanism::Plain, AuthMechanism::Login]
+}
 
-    match (str::from_utf8(username), str::from_utf8(password)) {
-        (Ok(u), Ok(p)) => Ok((u, p)),
-        _ => Err(vec![Response::Status(
-            Status::no(Some(tag), None, "Challenge must be valid UTF-8").unwrap(),
-        )]),
+pub fn mechanism(mechanism: &AuthMechanism) -> Option<Box<dyn Mechanism>> {
+    match mechanism {
+        AuthMechanism::Plain => Some(Box::new(Plain)),
+        AuthMechanism::Login => Some(Box::new(Login::default())),
+        _ => None,
     }
 }
 
 // Pas sûr de comment il faut nommer cette fonction puisqu'elle ne fait que
 // traduire le résultat de l'API en action concrètes dans le système.
 pub fn translate(
-    authentification_result: Result<(UserId, String), Option<String>>,
-    tag: Tag,
-) -> (State<'static>, Option<User>, Vec<Response<'_>>) {
+    authentification_result: Result<(), Option<String>>,
+    tag: Tag<'_>,
+    encryption: Encryption,
+) -> (State<'static>, Vec<Response<'_>>) {
     match authentification_result {
-        Ok((id, token)) => (
+        Ok(()) => (
             State::Authenticated,
-            Some(User { id, token }),
             vec![Response::Status(
                 Status::ok(
                     Some(tag),
-                    Some(Code::Capability(capabilities())),
+                    Some(Code::Capability(capabilities(encryption))),
                     "Authentication completed",
                 )
                 .unwrap(),
@@ -70,7 +123,6 @@ pub fn translate(
         ),
         Err(message) => (
             State::NotAuthenticated,
-            None,
             vec![Response::Status(
                 Status::no(
                     Some(tag),
@@ -85,3 +137,40 @@ pub fn translate(
         ),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn done(step: Step) -> (String, String) {
+        match step {
+            Step::Done { username, password } => (username, password.declassify().clone()),
+            _ => panic!("identifiants attendus"),
+        }
+    }
+
+    #[test]
+    fn plain() {
+        // Sans réponse initiale, un défi vide
+        assert!(matches!(Plain.step(None), Step::Challenge(challenge) if challenge.is_empty()));
+        // Avec SASL-IR, tout arrive d'un coup
+        assert_eq!(done(Plain.step(Some(b"\0jean\0secret"))), ("jean".into(), "secret".into()));
+        assert_eq!(done(Plain.step(Some(b"jean\0jean\0secret"))), ("jean".into(), "secret".into()));
+        assert!(matches!(Plain.step(Some(b"paul\0jean\0secret")), Step::Failed(_)));
+        // Réponse initiale vide (`=`)
+        assert!(matches!(Plain.step(Some(b"")), Step::Failed(_)));
+    }
+
+    #[test]
+    fn login() {
+        let mut login = Login::default();
+        assert!(matches!(login.step(None), Step::Challenge(challenge) if challenge == b"Username:"));
+        assert!(matches!(login.step(Some(b"jean")), Step::Challenge(challenge) if challenge == b"Password:"));
+        assert_eq!(done(login.step(Some(b"secret"))), ("jean".into(), "secret".into()));
+
+        // L'identifiant en réponse initiale, même vide
+        let mut login = Login::default();
+        assert!(matches!(login.step(Some(b"")), Step::Challenge(challenge) if challenge == b"Password:"));
+        assert_eq!(done(login.step(Some(b"secret"))), (String::new(), "secret".into()));
+    }
+}
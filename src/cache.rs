@@ -0,0 +1,189 @@
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use crate::auth::UserId;
+use crate::lock;
+
+// Messages gardés par compte, en mémoire et sur le disque : au-delà, les plus
+// anciens sont oubliés
+const MAX_MESSAGES: usize = 500;
+const MAX_SAVED: usize = 5000;
+
+/// Messages complets (avec leur contenu) déjà téléchargés. Le contenu d'un
+/// message ne change pas, donc on garde les derniers pour toutes les
+/// connexions d'un compte (FETCH, SEARCH BODY, etc.), et sur le disque si on a
+/// un dossier pour ça.
+#[derive(Default)]
+pub struct Contents {
+    messages: Mutex<Messages>,
+    dir: Option<PathBuf>,
+    // Messages gardés sur le disque, du plus ancien au plus récent
+    saved: Mutex<VecDeque<u32>>,
+}
+
+#[derive(Default)]
+struct Messages {
+    contents: HashMap<u32, Value>,
+    // Ordre d'arrivée, pour oublier les plus anciens
+    order: VecDeque<u32>,
+}
+
+impl Messages {
+    fn insert(&mut self, message_id: u32, message: Value) {
+        if self.contents.insert(message_id, message).is_none() {
+            self.order.push_back(message_id);
+        }
+        while self.order.len() > MAX_MESSAGES {
+            if let Some(oldest) = self.order.pop_front() {
+                self.contents.remove(&oldest);
+            }
+        }
+    }
+}
+
+impl Contents {
+    // L'ordre des messages déjà sur le disque n'est lu qu'une fois
+    fn open(dir: Option<PathBuf>) -> Contents {
+        let mut saved: Vec<_> = dir
+            .iter()
+            .filter_map(|dir| fs::read_dir(dir).ok())
+            .flatten()
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let message_id = entry.file_name().to_str()?.strip_suffix(".json")?.parse().ok()?;
+                Some((entry.metadata().ok()?.modified().ok()?, message_id))
+            })
+            .collect();
+        saved.sort();
+        let saved = saved.into_iter().map(|(_, message_id)| message_id).collect();
+        Contents { messages: Mutex::default(), dir, saved: Mutex::new(saved) }
+    }
+
+    fn path(&self, message_id: u32) -> Option<PathBuf> {
+        Some(self.dir.as_ref()?.join(format!("{message_id}.json")))
+    }
+
+    /// Contenu d'un message, téléchargé avec `get_message` s'il n'est pas
+    /// encore là (un échec n'est pas gardé).
+    pub fn get<E, F: FnOnce() -> Result<Value, E>>(&self, message_id: u32, get_message: F) -> Result<Value, E> {
+        if let Some(message) = lock(&self.messages).contents.get(&message_id) {
+            return Ok(message.clone());
+        }
+        let saved = self
+            .path(message_id)
+            .and_then(|path| fs::read(path).ok())
+            .and_then(|data| serde_json::from_slice(&data).ok());
+        let message = match saved {
+            Some(message) => message,
+            None => {
+                // Pas de verrou pendant le téléchargement pour ne pas bloquer les autres
+                let message = get_message()?;
+                // Le cache n'est qu'une optimisation : tant pis s'il ne s'écrit pas
+                let _ = self.save(message_id, &message);
+                message
+            }
+        };
+        lock(&self.messages).insert(message_id, message.clone());
+        Ok(message)
+    }
+
+    // Les messages sont en clair : seul l'utilisateur du serveur peut les lire
+    fn save(&self, message_id: u32, message: &Value) -> io::Result<()> {
+        let (Some(dir), Some(path)) = (&self.dir, self.path(message_id)) else {
+            return Ok(());
+        };
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+        let new = !path.exists();
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&path)?
+            .write_all(message.to_string().as_bytes())?;
+
+        // On oublie les plus anciens
+        let forgotten: Vec<u32> = {
+            let mut saved = lock(&self.saved);
+            if new {
+                saved.push_back(message_id);
+            }
+            let excess = saved.len().saturating_sub(MAX_SAVED);
+            saved.drain(..excess).collect()
+        };
+        for message_id in forgotten {
+            if let Some(path) = self.path(message_id) {
+                match fs::remove_file(path) {
+                    Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+                    _ => (),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Contenus d'un compte, gardés dans `dir` (un sous-dossier par compte) s'il
+/// est donné.
+pub fn contents(dir: Option<&Path>, user_id: UserId) -> Arc<Contents> {
+    static CONTENTS: OnceLock<Mutex<HashMap<UserId, Arc<Contents>>>> = OnceLock::new();
+
+    lock(CONTENTS.get_or_init(Default::default))
+        .entry(user_id)
+        .or_insert_with(|| {
+            let dir = dir.map(|dir| {
+                dir.join(match user_id {
+                    UserId::Eleve(id) => format!("eleve-{id}"),
+                    UserId::Famille(id) => format!("famille-{id}"),
+                })
+            });
+            Arc::new(Contents::open(dir))
+        })
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    #[test]
+    fn oldest_messages_are_forgotten() {
+        let contents = Contents::default();
+        for message_id in 0..=MAX_MESSAGES as u32 {
+            contents.get(message_id, || Ok::<_, ()>(Value::from(message_id))).unwrap();
+        }
+        // Déjà là : pas d'appel
+        contents.get(MAX_MESSAGES as u32, || -> Result<_, ()> { unreachable!() }).unwrap();
+        // Un échec n'est pas gardé
+        assert!(contents.get(MAX_MESSAGES as u32 + 1, || Err(())).is_err());
+        assert_eq!(contents.get(MAX_MESSAGES as u32 + 1, || Ok::<_, ()>(Value::Null)), Ok(Value::Null));
+        let messages = lock(&contents.messages);
+        assert_eq!(messages.contents.len(), MAX_MESSAGES);
+        assert!(!messages.contents.contains_key(&0));
+    }
+    #[test]
+    fn oldest_files_are_removed() {
+        let dir = env::temp_dir().join(format!("ecoledirecte-imap-test-cache-{}", process::id()));
+        let contents = Contents::open(Some(dir.clone()));
+        for message_id in 0..=MAX_SAVED as u32 {
+            contents.get(message_id, || Ok::<_, ()>(Value::from(message_id))).unwrap();
+        }
+        let files = || fs::read_dir(&dir).unwrap().count();
+        assert_eq!(files(), MAX_SAVED);
+        assert!(!dir.join("0.json").exists());
+
+        // L'ordre est relu depuis le disque
+        let contents = Contents::open(Some(dir.clone()));
+        assert_eq!(lock(&contents.saved).front(), Some(&1));
+        contents.get(MAX_SAVED as u32 + 1, || Ok::<_, ()>(Value::Null)).unwrap();
+        assert_eq!(files(), MAX_SAVED);
+        assert!(!dir.join("1.json").exists());
+
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,701 @@
+use imap_codec::{
+    decode::Decoder,
+    imap_types::{
+        bounded_static::IntoBoundedStatic,
+        command::{Command, CommandBody},
+        core::{Charset, LiteralMode, Tag},
+        fetch::MacroOrMessageDataItemNames,
+        flag::{Flag, StoreResponse, StoreType},
+        mailbox::Mailbox,
+        search::SearchKey,
+        sequence::SequenceSet,
+        status::StatusDataItemName,
+    },
+    CommandCodec,
+};
+use std::str;
+
+/// Résultats demandés par SEARCH RETURN (...) (RFC 4731 et RFC 5182).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SearchReturn {
+    Min,
+    Max,
+    Count,
+    All,
+    Save,
+}
+
+/// Critères de tri de SORT (RFC 5256).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SortCriterion {
+    Arrival,
+    Cc,
+    Date,
+    From,
+    Size,
+    Subject,
+    To,
+}
+
+/// Algorithmes de THREAD (RFC 5256).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ThreadAlgorithm {
+    OrderedSubject,
+    References,
+}
+
+/// Paramètre QRESYNC de SELECT (RFC 7162) : ce que le client connaît déjà
+/// du dossier.
+pub struct Qresync {
+    pub uid_validity: u32,
+    pub modseq: u64,
+    pub known_uids: Option<SequenceSet>,
+}
+
+/// Commandes d'extensions que imap-codec ne sait pas décoder.
+pub enum ExtendedCommand<'a> {
+    UidExpunge {
+        tag: Tag<'a>,
+        sequence_set: SequenceSet,
+    },
+    /// SEARCH RETURN (...) et/ou avec le critère MODSEQ (au premier niveau)
+    Search {
+        tag: Tag<'a>,
+        charset: Option<Charset<'a>>,
+        criteria: SearchKey<'a>,
+        uid: bool,
+        // None : réponse SEARCH classique
+        options: Option<Vec<SearchReturn>>,
+        modseq: Option<u64>,
+    },
+    Sort {
+        tag: Tag<'a>,
+        // (REVERSE, critère)
+        sort_criteria: Vec<(bool, SortCriterion)>,
+        charset: Charset<'a>,
+        criteria: SearchKey<'a>,
+        uid: bool,
+    },
+    Thread {
+        tag: Tag<'a>,
+        algorithm: ThreadAlgorithm,
+        charset: Charset<'a>,
+        criteria: SearchKey<'a>,
+        uid: bool,
+    },
+    /// SELECT (CONDSTORE) ou SELECT (QRESYNC (...))
+    Select {
+        tag: Tag<'a>,
+        mailbox: Mailbox<'a>,
+        condstore: bool,
+        qresync: Option<Qresync>,
+    },
+    /// FETCH avec l'élément MODSEQ ou les modificateurs CHANGEDSINCE et VANISHED
+    Fetch {
+        tag: Tag<'a>,
+        sequence_set: SequenceSet,
+        macro_or_item_names: MacroOrMessageDataItemNames<'a>,
+        uid: bool,
+        modseq: bool,
+        changed_since: Option<u64>,
+        vanished: bool,
+    },
+    /// STORE (UNCHANGEDSINCE n) : seulement les messages qui n'ont pas changé
+    /// depuis
+    Store {
+        tag: Tag<'a>,
+        sequence_set: SequenceSet,
+        kind: StoreType,
+        response: StoreResponse,
+        flags: Vec<Flag<'a>>,
+        uid: bool,
+        unchanged_since: u64,
+    },
+    /// STATUS avec HIGHESTMODSEQ (en plus des éléments standards)
+    Status {
+        tag: Tag<'a>,
+        mailbox: Mailbox<'a>,
+        item_names: Vec<StatusDataItemName>,
+    },
+    Namespace {
+        tag: Tag<'a>,
+    },
+    /// ID : l'identité du client n'est pas gardée
+    Id {
+        tag: Tag<'a>,
+    },
+    /// Commande qui porte sur le résultat sauvegardé `$` alors qu'il est vide :
+    /// il n'y a rien à faire
+    NoMessages {
+        tag: Tag<'a>,
+    },
+    /// Commande standard qui utilise le résultat sauvegardé `$`
+    Standard(Command<'a>),
+}
+
+/// Tag au début d'une commande qu'on n'a pas pu décoder, s'il est valide,
+/// pour pouvoir quand même y répondre.
+pub fn tag(line: &[u8]) -> Option<Tag<'static>> {
+    let end = line.iter().position(|c| *c == b' ')?;
+    let tag = Tag::try_from(str::from_utf8(&line[..end]).ok()?).ok()?;
+    Some(tag.into_static())
+}
+
+/// Littéral annoncé à la fin d'une ligne ({42} ou {42+}).
+pub fn literal(line: &[u8]) -> Option<(u32, LiteralMode)> {
+    // En octets : la ligne peut commencer au milieu d'un caractère (voir `skip`)
+    let line = line.strip_suffix(b"}\r\n")?;
+    let start = line.iter().rposition(|c| *c == b'{')?;
+    let (length, mode) = match line[start + 1..].strip_suffix(b"+") {
+        Some(length) => (length, LiteralMode::NonSync),
+        None => (&line[start + 1..], LiteralMode::Sync),
+    };
+    if length.is_empty() || !length.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    Some((str::from_utf8(length).ok()?.parse().ok()?, mode))
+}
+
+/// Ce qu'on sait de la commande au début de `data`, en suivant ses lignes et
+/// en passant par-dessus les littéraux annoncés à leur fin.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Extent {
+    /// La commande finit juste avant cette position
+    Complete(usize),
+    /// Il manque des octets du dernier littéral annoncé
+    Literal(usize, LiteralMode),
+    /// Il manque la fin de la ligne qui commence à cette position
+    Line(usize),
+    /// La ligne qui finit à `line_end` annonce un littéral de plus de
+    /// `max_literal` octets
+    TooBig { line_end: usize, length: u32, mode: LiteralMode },
+}
+
+pub fn extent(data: &[u8], max_literal: u32) -> Extent {
+    let mut start = 0;
+    loop {
+        let Some(end) = data[start..].windows(2).position(|window| window == b"\r\n") else {
+            return Extent::Line(start);
+        };
+        let line_end = start + end + 2;
+        match literal(&data[start..line_end]) {
+            None => return Extent::Complete(line_end),
+            Some((length, mode)) if length > max_literal => return Extent::TooBig { line_end, length, mode },
+            Some((length, mode)) => {
+                start = line_end + length as usize;
+                if start > data.len() {
+                    return Extent::Literal(start - data.len(), mode);
+                }
+            }
+        }
+    }
+}
+
+// Découpe "tag NOM arguments\r\n" en (tag, NOM, arguments)
+fn split(line: &[u8]) -> Option<(&str, String, &str)> {
+    let line = str::from_utf8(line).ok()?.strip_suffix("\r\n")?;
+    let (tag, line) = line.split_once(' ')?;
+    let (name, arguments) = line.split_once(' ').unwrap_or((line, ""));
+    let name = name.to_ascii_uppercase();
+    if name == "UID" {
+        let (name, arguments) = arguments.split_once(' ').unwrap_or((arguments, ""));
+        Some((tag, format!("UID {}", name.to_ascii_uppercase()), arguments))
+    } else {
+        Some((tag, name, arguments))
+    }
+}
+
+// On réécrit la commande en une commande standard qui a les mêmes arguments
+// pour profiter du décodeur d'imap-codec (ensembles de séquences, etc.)
+fn decode_as(line: String) -> Option<Command<'static>> {
+    match CommandCodec::default().decode(line.as_bytes()) {
+        Ok(([], command)) => Some(command.into_static()),
+        _ => None,
+    }
+}
+
+// Remplace les `$` (résultat sauvegardé de SEARCHRES) qui ne sont pas dans
+// une chaîne entre guillemets. Un résultat vide (`None`) devient un critère de
+// recherche qui ne correspond à aucun message, "UID $" compris.
+fn substitute(arguments: &str, saved_result: Option<&str>) -> Option<String> {
+    let mut substituted = String::new();
+    let mut found = false;
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut previous = ' ';
+    let mut chars = arguments.chars().peekable();
+    while let Some(c) = chars.next() {
+        if quoted {
+            quoted = escaped || c != '"';
+            escaped = !escaped && c == '\\';
+        } else if c == '"' {
+            quoted = true;
+        } else if c == '$' && matches!(previous, ' ' | '(') && matches!(chars.peek(), None | Some(' ' | ')')) {
+            match saved_result {
+                Some(saved_result) => substituted.push_str(saved_result),
+                None => {
+                    let uid = substituted.len().checked_sub(4).filter(|start| {
+                        substituted[*start..].eq_ignore_ascii_case("UID ")
+                            && substituted[..*start].chars().next_back().is_none_or(|c| matches!(c, ' ' | '('))
+                    });
+                    if let Some(start) = uid {
+                        substituted.truncate(start);
+                    }
+                    substituted.push_str("(NOT ALL)");
+                }
+            }
+            found = true;
+            previous = c;
+            continue;
+        }
+        substituted.push(c);
+        previous = c;
+    }
+    found.then_some(substituted)
+}
+
+// RETURN (options) critères
+fn search_return(arguments: &str) -> Option<(Vec<SearchReturn>, &str)> {
+    let (keyword, arguments) = arguments.split_once(' ')?;
+    if !keyword.eq_ignore_ascii_case("RETURN") {
+        return None;
+    }
+    let (options, criteria) = arguments.strip_prefix('(')?.split_once(')')?;
+    let options = options
+        .split_whitespace()
+        .map(|option| match option.to_ascii_uppercase().as_str() {
+            "MIN" => Some(SearchReturn::Min),
+            "MAX" => Some(SearchReturn::Max),
+            "COUNT" => Some(SearchReturn::Count),
+            "ALL" => Some(SearchReturn::All),
+            "SAVE" => Some(SearchReturn::Save),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some((options, criteria.trim_start()))
+}
+
+// (critères de tri) charset critères de recherche
+fn sort_criteria(arguments: &str) -> Option<(Vec<(bool, SortCriterion)>, &str)> {
+    let (criteria, arguments) = arguments.strip_prefix('(')?.split_once(')')?;
+    let mut sort_criteria = Vec::new();
+    let mut reverse = false;
+    for criterion in criteria.split_whitespace() {
+        let criterion = match criterion.to_ascii_uppercase().as_str() {
+            "REVERSE" if !reverse => {
+                reverse = true;
+                continue;
+            }
+            "ARRIVAL" => SortCriterion::Arrival,
+            "CC" => SortCriterion::Cc,
+            "DATE" => SortCriterion::Date,
+            "FROM" => SortCriterion::From,
+            "SIZE" => SortCriterion::Size,
+            "SUBJECT" => SortCriterion::Subject,
+            "TO" => SortCriterion::To,
+            _ => return None,
+        };
+        sort_criteria.push((reverse, criterion));
+        reverse = false;
+    }
+    if sort_criteria.is_empty() || reverse {
+        return None;
+    }
+    Some((sort_criteria, arguments.trim_start()))
+}
+
+// "(...)" au début, avec ce qu'il y a après
+fn parenthesized(arguments: &str) -> Option<(&str, &str)> {
+    let arguments = arguments.strip_prefix('(')?;
+    let mut depth = 0;
+    for (pos, c) in arguments.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return Some((&arguments[..pos], arguments[pos + 1..].trim_start())),
+            ')' => depth -= 1,
+            _ => (),
+        }
+    }
+    None
+}
+
+// "... (...)" : ce qu'il y a avant la dernière liste, et son contenu
+fn trailing_list(arguments: &str) -> Option<(&str, &str)> {
+    let inner = arguments.strip_suffix(')')?;
+    let mut depth = 0;
+    for (pos, c) in inner.char_indices().rev() {
+        match c {
+            ')' => depth += 1,
+            '(' if depth == 0 => return Some((inner[..pos].trim_end(), &inner[pos + 1..])),
+            '(' => depth -= 1,
+            _ => (),
+        }
+    }
+    None
+}
+
+// Enlève un mot (MODSEQ, HIGHESTMODSEQ) d'une liste d'éléments, en gardant
+// les parenthèses. Renvoie None s'il n'y était pas.
+fn remove_item(items: &str, item: &str) -> Option<String> {
+    let mut found = false;
+    let words: Vec<String> = items
+        .split(' ')
+        .map(|word| {
+            let start = word.len() - word.trim_start_matches('(').len();
+            let end = word.trim_end_matches(')').len();
+            if start <= end && word[start..end].eq_ignore_ascii_case(item) {
+                found = true;
+                format!("{}{}", &word[..start], &word[end..])
+            } else {
+                word.to_string()
+            }
+        })
+        .filter(|word| !word.is_empty())
+        .collect();
+    found.then(|| words.join(" ").replace("( ", "(").replace(" )", ")"))
+}
+
+// Un mod-sequence tient sur 63 bits (RFC 7162)
+fn mod_sequence(value: &str) -> Option<u64> {
+    value.parse().ok().filter(|modseq| *modseq <= i64::MAX as u64)
+}
+
+// (CONDSTORE) ou (QRESYNC (uidvalidity modseq [uids connus] [...]))
+fn select_parameters(mut parameters: &str) -> Option<(bool, Option<Qresync>)> {
+    let mut condstore = false;
+    let mut qresync = None;
+    while !parameters.is_empty() {
+        let (name, rest) = parameters.split_once(' ').unwrap_or((parameters, ""));
+        if name.eq_ignore_ascii_case("CONDSTORE") {
+            condstore = true;
+            parameters = rest.trim_start();
+        } else if name.eq_ignore_ascii_case("QRESYNC") {
+            let (known, rest) = parenthesized(rest.trim_start())?;
+            // On ignore les correspondances numéros de séquence/UIDs
+            let known = known.split('(').next().unwrap_or_default();
+            let mut known = known.split_whitespace();
+            qresync = Some(Qresync {
+                uid_validity: known.next()?.parse().ok().filter(|uid_validity| *uid_validity > 0)?,
+                modseq: mod_sequence(known.next()?)?,
+                known_uids: match known.next() {
+                    Some(uids) => Some(SequenceSet::try_from(uids).ok()?),
+                    None => None,
+                },
+            });
+            parameters = rest;
+        } else {
+            return None;
+        }
+    }
+    Some((condstore, qresync))
+}
+
+// Positions des mots d'une liste de critères, une liste entre parenthèses ou
+// une chaîne entre guillemets comptant pour un seul mot
+fn words(criteria: &str) -> Vec<(usize, usize)> {
+    let mut words = Vec::new();
+    let mut start = None;
+    let (mut depth, mut quoted, mut escaped) = (0, false, false);
+    for (pos, c) in criteria.char_indices() {
+        if quoted {
+            quoted = escaped || c != '"';
+            escaped = !escaped && c == '\\';
+        } else {
+            match c {
+                '"' => quoted = true,
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                ' ' if depth == 0 => {
+                    if let Some(start) = start.take() {
+                        words.push((start, pos));
+                    }
+                    continue;
+                }
+                _ => (),
+            }
+        }
+        start.get_or_insert(pos);
+    }
+    if let Some(start) = start {
+        words.push((start, criteria.len()));
+    }
+    words
+}
+
+// Critère MODSEQ au premier niveau d'une recherche (RFC 7162) : sa valeur et
+// les autres critères. Le nom et le type d'entrée éventuels sont ignorés, il
+// n'y a pas de MODSEQ par drapeau. Ailleurs (dans OR, NOT...), imap-codec le
+// refusera.
+fn search_modseq(criteria: &str) -> Option<(u64, String)> {
+    let words = words(criteria);
+    words.iter().enumerate().find_map(|(index, (start, end))| {
+        if !criteria[*start..*end].eq_ignore_ascii_case("MODSEQ") {
+            return None;
+        }
+        let entry = words.get(index + 1).is_some_and(|(start, _)| criteria[*start..].starts_with('"'));
+        let (value_start, value_end) = words.get(index + if entry { 3 } else { 1 })?;
+        let modseq = mod_sequence(&criteria[*value_start..*value_end])?;
+        let rest = format!("{} {}", criteria[..*start].trim_end(), criteria[*value_end..].trim_start());
+        let rest = rest.trim();
+        // Il faut au moins un critère, après CHARSET s'il y en a un
+        let charset_only = rest.split(' ').count() == 2 && rest.get(..8).is_some_and(|start| start.eq_ignore_ascii_case("CHARSET "));
+        Some((modseq, if rest.is_empty() || charset_only { format!("{rest} ALL").trim_start().to_string() } else { rest.to_string() }))
+    })
+}
+
+// SORT et THREAD ont un charset obligatoire suivi des critères de SEARCH
+fn decode_search(tag: &str, uid: bool, arguments: &str) -> Option<(Tag<'static>, Charset<'static>, SearchKey<'static>)> {
+    let (charset, criteria) = arguments.split_once(' ')?;
+    let name = if uid { "UID SEARCH" } else { "SEARCH" };
+    match decode_as(format!("{tag} {name} CHARSET {charset} {criteria}\r\n"))? {
+        Command {
+            tag,
+            body: CommandBody::Search { charset, criteria, .. },
+        } => Some((tag, charset?, criteria)),
+        _ => None,
+    }
+}
+
+/// `saved_result` donne l'ensemble à mettre à la place de `$`, en UIDs ou en
+/// numéros de séquence, ou `None` s'il est vide.
+pub fn decode<F: Fn(bool) -> Option<String>>(line: &[u8], saved_result: F) -> Option<ExtendedCommand<'static>> {
+    let (tag, name, arguments) = split(line)?;
+    let uid = name.starts_with("UID ");
+    let saved_result = if arguments.contains('$') { Some(saved_result(uid)) } else { None };
+    let substituted = saved_result.as_ref().and_then(|saved_result| substitute(arguments, saved_result.as_deref()));
+    // Ailleurs que dans des critères de recherche, `$` est l'ensemble des
+    // messages sur lesquels porte la commande
+    let criteria = matches!(name.trim_start_matches("UID "), "SEARCH" | "SORT" | "THREAD");
+    if substituted.is_some() && saved_result == Some(None) && !criteria {
+        return Some(ExtendedCommand::NoMessages { tag: Tag::try_from(tag).ok()?.into_static() });
+    }
+    let arguments = substituted.as_deref().unwrap_or(arguments);
+    match name.as_str() {
+        "UID EXPUNGE" => match decode_as(format!("{tag} UID FETCH {arguments} UID\r\n"))? {
+            Command {
+                tag,
+                body: CommandBody::Fetch { sequence_set, .. },
+            } => Some(ExtendedCommand::UidExpunge { tag, sequence_set }),
+            _ => None,
+        },
+        "SEARCH" | "UID SEARCH" if search_return(arguments).is_some() || search_modseq(arguments).is_some() => {
+            let (options, criteria) = match search_return(arguments) {
+                Some((options, criteria)) => (Some(options), criteria),
+                None => (None, arguments),
+            };
+            let (modseq, criteria) = match search_modseq(criteria) {
+                Some((modseq, criteria)) => (Some(modseq), criteria),
+                None => (None, criteria.to_string()),
+            };
+            match decode_as(format!("{tag} {name} {criteria}\r\n"))? {
+                Command {
+                    tag,
+                    body: CommandBody::Search { charset, criteria, uid },
+                } => Some(ExtendedCommand::Search { tag, charset, criteria, uid, options, modseq }),
+                _ => None,
+            }
+        }
+        "SORT" | "UID SORT" => {
+            let (sort_criteria, arguments) = sort_criteria(arguments)?;
+            let (tag, charset, criteria) = decode_search(tag, uid, arguments)?;
+            Some(ExtendedCommand::Sort { tag, sort_criteria, charset, criteria, uid })
+        }
+        "THREAD" | "UID THREAD" => {
+            let (algorithm, arguments) = arguments.split_once(' ')?;
+            let algorithm = match algorithm.to_ascii_uppercase().as_str() {
+                "ORDEREDSUBJECT" => ThreadAlgorithm::OrderedSubject,
+                "REFERENCES" => ThreadAlgorithm::References,
+                _ => return None,
+            };
+            let (tag, charset, criteria) = decode_search(tag, uid, arguments)?;
+            Some(ExtendedCommand::Thread { tag, algorithm, charset, criteria, uid })
+        }
+        "NAMESPACE" if arguments.is_empty() => Some(ExtendedCommand::Namespace { tag: Tag::try_from(tag).ok()?.into_static() }),
+        // ID NIL ou ID ("champ" "valeur" ...)
+        "ID" if arguments.eq_ignore_ascii_case("NIL") || parenthesized(arguments).is_some_and(|(_, rest)| rest.is_empty()) => {
+            Some(ExtendedCommand::Id { tag: Tag::try_from(tag).ok()?.into_static() })
+        }
+        "SELECT" => {
+            let (mailbox, parameters) = trailing_list(arguments)?;
+            let (condstore, qresync) = select_parameters(parameters)?;
+            match decode_as(format!("{tag} SELECT {mailbox}\r\n"))? {
+                Command {
+                    tag,
+                    body: CommandBody::Select { mailbox },
+                } => Some(ExtendedCommand::Select { tag, mailbox, condstore, qresync }),
+                _ => None,
+            }
+        }
+        "FETCH" | "UID FETCH" => {
+            let (mut arguments, mut changed_since, mut vanished) = (arguments.to_string(), None, false);
+            if let Some((rest, modifiers)) = trailing_list(&arguments) {
+                let mut modifiers = modifiers.split_whitespace();
+                match modifiers.next() {
+                    Some(first) if first.eq_ignore_ascii_case("CHANGEDSINCE") || first.eq_ignore_ascii_case("VANISHED") => {
+                        let mut modifier = Some(first);
+                        while let Some(name) = modifier {
+                            match name.to_ascii_uppercase().as_str() {
+                                "CHANGEDSINCE" => changed_since = Some(mod_sequence(modifiers.next()?)?),
+                                "VANISHED" => vanished = true,
+                                _ => return None,
+                            }
+                            modifier = modifiers.next();
+                        }
+                        arguments = rest.to_string();
+                    }
+                    _ => (),
+                }
+            }
+            let (sequence_set, items) = arguments.split_once(' ')?;
+            let (items, modseq) = match remove_item(items, "MODSEQ") {
+                Some(items) if items.is_empty() => (String::from("UID"), true),
+                Some(items) if items == "()" => (String::from("(UID)"), true),
+                Some(items) => (items, true),
+                None => (items.to_string(), false),
+            };
+            // FETCH standard, peut-être avec `$`
+            if !modseq && changed_since.is_none() && !vanished {
+                return substituted.and_then(|_| decode_as(format!("{tag} {name} {arguments}\r\n"))).map(ExtendedCommand::Standard);
+            }
+            match decode_as(format!("{tag} {name} {sequence_set} {items}\r\n"))? {
+                Command {
+                    tag,
+                    body: CommandBody::Fetch { sequence_set, macro_or_item_names, uid },
+                } => Some(ExtendedCommand::Fetch { tag, sequence_set, macro_or_item_names, uid, modseq, changed_since, vanished }),
+                _ => None,
+            }
+        }
+        // La liste de modificateurs vient juste après l'ensemble de messages
+        "STORE" | "UID STORE" if arguments.split_once(' ').is_some_and(|(_, rest)| rest.starts_with('(')) => {
+            let (sequence_set, rest) = arguments.split_once(' ')?;
+            let (modifiers, rest) = parenthesized(rest)?;
+            let unchanged_since = match modifiers.split_whitespace().collect::<Vec<_>>()[..] {
+                [name, modseq] if name.eq_ignore_ascii_case("UNCHANGEDSINCE") => mod_sequence(modseq)?,
+                _ => return None,
+            };
+            match decode_as(format!("{tag} {name} {sequence_set} {rest}\r\n"))? {
+                Command {
+                    tag,
+                    body: CommandBody::Store { sequence_set, kind, response, flags, uid },
+                } => Some(ExtendedCommand::Store { tag, sequence_set, kind, response, flags, uid, unchanged_since }),
+                _ => None,
+            }
+        }
+        "STATUS" => {
+            let (mailbox, items) = trailing_list(arguments)?;
+            let items = remove_item(items, "HIGHESTMODSEQ")?;
+            // Il faut au moins un élément à imap-codec
+            let standard = items != "()" && !items.is_empty();
+            let items = if standard { format!("({items})") } else { String::from("(MESSAGES)") };
+            match decode_as(format!("{tag} STATUS {mailbox} {items}\r\n"))? {
+                Command {
+                    tag,
+                    body: CommandBody::Status { mailbox, item_names },
+                } => Some(ExtendedCommand::Status {
+                    tag,
+                    mailbox,
+                    item_names: if standard { item_names.into_owned() } else { vec![] },
+                }),
+                _ => None,
+            }
+        }
+        _ if substituted.is_some() => decode_as(format!("{tag} {name} {arguments}\r\n")).map(ExtendedCommand::Standard),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search_modseq_criterion() {
+        assert_eq!(search_modseq("MODSEQ 42").unwrap(), (42, String::from("ALL")));
+        assert_eq!(search_modseq("CHARSET UTF-8 MODSEQ 4").unwrap(), (4, String::from("CHARSET UTF-8 ALL")));
+        assert_eq!(
+            search_modseq("SUBJECT \"MODSEQ 1\" MODSEQ \"/flags/\\\\draft\" all 7 SEEN").unwrap(),
+            (7, String::from("SUBJECT \"MODSEQ 1\" SEEN")),
+        );
+        assert_eq!(search_modseq("OR (MODSEQ 1) SEEN"), None);
+    }
+
+    #[test]
+    fn empty_saved_result() {
+        assert!(matches!(decode(b"a UID FETCH $ FLAGS\r\n", |_| None), Some(ExtendedCommand::NoMessages { .. })));
+        assert!(matches!(decode(b"a FETCH $ FLAGS\r\n", |_| Some(String::from("1:2"))), Some(ExtendedCommand::Standard(_))));
+        assert_eq!(substitute("OR $ UID $", None).unwrap(), "OR (NOT ALL) (NOT ALL)");
+        assert_eq!(substitute("SUBJECT \"$\" $", Some("1:3")).unwrap(), "SUBJECT \"$\" 1:3");
+        match decode(b"a SEARCH RETURN (ALL) UID $\r\n", |_| None) {
+            Some(ExtendedCommand::Search { criteria, .. }) => {
+                let nothing = [SearchKey::Not(Box::new(SearchKey::All))];
+                assert!(matches!(criteria, SearchKey::And(keys) if keys.as_ref() == nothing));
+            }
+            _ => panic!("SEARCH not decoded"),
+        }
+    }
+
+    #[test]
+    fn sort_and_thread() {
+        let none = |_| None;
+        assert!(matches!(decode(b"a UID SORT (REVERSE DATE) UTF-8 ALL\r\n", none), Some(ExtendedCommand::Sort { uid: true, .. })));
+        assert!(matches!(decode(b"a THREAD REFERENCES UTF-8 ALL\r\n", none), Some(ExtendedCommand::Thread { .. })));
+        assert!(decode(b"a THREAD FOO UTF-8 ALL\r\n", none).is_none());
+    }
+
+    #[test]
+    fn condstore_commands() {
+        let none = |_| None;
+        // Les commandes standard sont laissées à imap-codec
+        assert!(decode(b"a NOOP\r\n", none).is_none());
+        assert!(decode(b"a FETCH 1 FLAGS\r\n", none).is_none());
+        assert!(matches!(decode(b"a UID EXPUNGE 1:3\r\n", none), Some(ExtendedCommand::UidExpunge { .. })));
+        match decode(b"a FETCH 1:* (FLAGS) (CHANGEDSINCE 12 VANISHED)\r\n", none) {
+            Some(ExtendedCommand::Fetch { uid, modseq, changed_since, vanished, .. }) => {
+                assert_eq!((uid, modseq, changed_since, vanished), (false, false, Some(12), true));
+            }
+            _ => panic!("FETCH not decoded"),
+        }
+        assert!(matches!(
+            decode(b"a UID FETCH 1 (FLAGS MODSEQ)\r\n", none),
+            Some(ExtendedCommand::Fetch { uid: true, modseq: true, changed_since: None, .. }),
+        ));
+        assert!(matches!(
+            decode(b"a STORE 2 (UNCHANGEDSINCE 5) +FLAGS (\\Seen)\r\n", none),
+            Some(ExtendedCommand::Store { unchanged_since: 5, uid: false, .. }),
+        ));
+        assert!(decode(b"a STORE 2 (FOO 5) +FLAGS (\\Seen)\r\n", none).is_none());
+        assert!(matches!(
+            decode(b"a SELECT INBOX (CONDSTORE)\r\n", none),
+            Some(ExtendedCommand::Select { condstore: true, qresync: None, .. }),
+        ));
+        assert!(matches!(
+            decode(b"a STATUS INBOX (HIGHESTMODSEQ)\r\n", none),
+            Some(ExtendedCommand::Status { item_names, .. }) if item_names.is_empty(),
+        ));
+    }
+
+    #[test]
+    fn mod_sequences_fit_in_63_bits() {
+        let none = |_| None;
+        assert!(matches!(
+            decode(b"a FETCH 1 (FLAGS) (CHANGEDSINCE 9223372036854775807)\r\n", none),
+            Some(ExtendedCommand::Fetch { changed_since: Some(9223372036854775807), .. }),
+        ));
+        assert!(decode(b"a FETCH 1 (FLAGS) (CHANGEDSINCE 9223372036854775808)\r\n", none).is_none());
+        assert!(decode(b"a UID FETCH 1:* (FLAGS) (CHANGEDSINCE 18446744073709551615 VANISHED)\r\n", none).is_none());
+        assert!(decode(b"a STORE 1 (UNCHANGEDSINCE 9223372036854775808) +FLAGS (\\Seen)\r\n", none).is_none());
+        assert!(decode(b"a SELECT INBOX (QRESYNC (1 18446744073709551615))\r\n", none).is_none());
+        assert!(matches!(
+            decode(b"a SELECT INBOX (QRESYNC (1 9223372036854775807))\r\n", none),
+            Some(ExtendedCommand::Select { qresync: Some(Qresync { modseq: 9223372036854775807, .. }), .. }),
+        ));
+        assert_eq!(search_modseq("MODSEQ 9223372036854775808"), None);
+    }
+
+    #[test]
+    fn id_and_namespace() {
+        let none = |_| None;
+        assert!(matches!(decode(b"a ID NIL\r\n", none), Some(ExtendedCommand::Id { .. })));
+        assert!(matches!(decode(b"a NAMESPACE\r\n", none), Some(ExtendedCommand::Namespace { .. })));
+    }
+}
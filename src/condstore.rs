@@ -0,0 +1,164 @@
+use imap_codec::imap_types::{
+    bounded_static::IntoBoundedStatic,
+    fetch::{MacroOrMessageDataItemNames, MessageDataItem, MessageDataItemName},
+    flag::FlagFetch,
+    response::{Code, CodeOther, Data, Response, Status},
+};
+use crate::fetch;
+use crate::idle::View;
+use crate::Reply;
+
+/// `* OK [HIGHESTMODSEQ n]` pour SELECT (RFC 7162).
+pub fn highest_modseq(modseq: u64) -> Response<'static> {
+    Response::Status(
+        Status::ok(
+            None,
+            Some(Code::Other(CodeOther::unvalidated(format!("HIGHESTMODSEQ {modseq}").into_bytes()))),
+            "Highest",
+        )
+        .unwrap(),
+    )
+}
+
+/// Les éléments demandés, plus l'UID pour pouvoir retrouver le MODSEQ de
+/// chaque message dans les réponses.
+pub fn with_uid(macro_or_item_names: MacroOrMessageDataItemNames) -> MacroOrMessageDataItemNames {
+    let mut items = match macro_or_item_names {
+        MacroOrMessageDataItemNames::Macro(macro_name) => macro_name.expand().into_static(),
+        MacroOrMessageDataItemNames::MessageDataItemNames(items) => items,
+    };
+    if !items.contains(&MessageDataItemName::Uid) {
+        items.push(MessageDataItemName::Uid);
+    }
+    MacroOrMessageDataItemNames::MessageDataItemNames(items)
+}
+
+// UID d'une réponse FETCH : celui qu'elle donne, ou celui de la vue du client
+fn uid(seq: u32, items: &[MessageDataItem], view: &View) -> Option<u32> {
+    items
+        .iter()
+        .find_map(|item| match item {
+            MessageDataItem::Uid(uid) => Some(uid.get()),
+            _ => None,
+        })
+        .or_else(|| view.get(seq as usize - 1).map(|(uid, _)| *uid))
+}
+
+/// Ajoute MODSEQ à une réponse FETCH (toujours si `always`, sinon seulement
+/// si elle donne les drapeaux).
+pub fn with_modseq<'a, M: Fn(u32) -> u64>(response: Response<'a>, view: &View, modseq: &M, always: bool) -> Reply<'a> {
+    if let Response::Data(Data::Fetch { seq, items }) = &response {
+        let flags = items.as_ref().iter().any(|item| matches!(item, MessageDataItem::Flags(_)));
+        if always || flags {
+            if let Some(uid) = uid(seq.get(), items.as_ref(), view) {
+                return Reply::extend(&response, &format!("MODSEQ ({})", modseq(uid)));
+            }
+        }
+    }
+    response.into()
+}
+
+/// Ajoute le plus grand MODSEQ des messages trouvés à la réponse de SEARCH
+/// MODSEQ : `* SEARCH 2 5 (MODSEQ 42)` ou `* ESEARCH ... MODSEQ 42`.
+pub fn search_modseq(replies: Vec<Reply>, modseq: u64) -> Vec<Reply> {
+    replies
+        .into_iter()
+        .map(|reply| match reply {
+            Reply::Response(Response::Data(Data::Search(ids))) => {
+                let ids: Vec<String> = ids.iter().map(ToString::to_string).collect();
+                Reply::Untagged(format!("SEARCH {} (MODSEQ {modseq})", ids.join(" ")))
+            }
+            Reply::Untagged(esearch) if esearch.starts_with("ESEARCH ") => Reply::Untagged(format!("{esearch} MODSEQ {modseq}")),
+            reply => reply,
+        })
+        .collect()
+}
+
+/// `* VANISHED [(EARLIER)] uids`
+pub fn vanished(uids: &[u32], earlier: bool) -> Reply<'static> {
+    let earlier = if earlier { "(EARLIER) " } else { "" };
+    Reply::Untagged(format!("VANISHED {earlier}{}", fetch::sequence_set(uids)))
+}
+
+/// Adapte les réponses d'une commande au client qui a activé CONDSTORE :
+/// MODSEQ dans les FETCH qui donnent des drapeaux (dans toutes pour STORE
+/// .SILENT, avec `silent_store`) et, avec QRESYNC, VANISHED à la place des
+/// EXPUNGE. `view` est la vue du client avant la commande.
+pub fn adapt<'a, M: Fn(u32) -> u64>(replies: Vec<Reply<'a>>, view: &View, modseq: M, qresync: bool, silent_store: bool) -> Vec<Reply<'a>> {
+    let mut remaining: Vec<u32> = view.iter().map(|(uid, _)| *uid).collect();
+    let mut expunged = Vec::new();
+    let mut vanished_at = None;
+    let mut adapted = Vec::new();
+    for reply in replies {
+        match reply {
+            Reply::Response(Response::Data(Data::Expunge(seq))) if qresync => {
+                let pos = seq.get() as usize - 1;
+                if pos < remaining.len() {
+                    expunged.push(remaining.remove(pos));
+                }
+                vanished_at.get_or_insert(adapted.len());
+            }
+            Reply::Response(response @ Response::Data(Data::Fetch { .. })) => {
+                adapted.push(with_modseq(response, view, &modseq, silent_store));
+            }
+            reply => adapted.push(reply),
+        }
+    }
+    if let Some(pos) = vanished_at {
+        expunged.sort();
+        adapted.insert(pos, vanished(&expunged, false));
+    }
+    adapted
+}
+
+/// Ce qui a changé depuis `since` pour SELECT (QRESYNC ...) : les drapeaux
+/// des messages modifiés, avec leur MODSEQ.
+pub fn changes<M: Fn(u32) -> u64>(view: &View, since: u64, modseq: M) -> Vec<Reply<'static>> {
+    view.iter()
+        .enumerate()
+        .filter(|(_, (uid, _))| modseq(*uid) > since)
+        .map(|(pos, (uid, flags))| {
+            let response = Response::Data(
+                Data::fetch(
+                    pos as u32 + 1,
+                    vec![
+                        MessageDataItem::Uid((*uid).try_into().unwrap()),
+                        MessageDataItem::Flags(flags.iter().cloned().map(FlagFetch::Flag).collect()),
+                    ],
+                )
+                .unwrap(),
+            );
+            Reply::extend(&response, &format!("MODSEQ ({})", modseq(*uid)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use imap_codec::{encode::Encoder, imap_types::flag::Flag, ResponseCodec};
+
+    fn lines(replies: Vec<Reply>) -> Vec<String> {
+        replies
+            .into_iter()
+            .map(|reply| match reply {
+                Reply::Untagged(line) => line,
+                Reply::Response(response) => String::from_utf8(ResponseCodec::default().encode(&response).dump()).unwrap(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn modseq_in_fetch_responses() {
+        let view: View = vec![(3, vec![]), (5, vec![Flag::Seen])];
+        let flags = Response::Data(Data::fetch(2, vec![MessageDataItem::Flags(vec![FlagFetch::Flag(Flag::Seen)])]).unwrap());
+        let uid = Response::Data(Data::fetch(1, vec![MessageDataItem::Uid(3.try_into().unwrap())]).unwrap());
+        let modseq = |uid| uid as u64 * 10;
+
+        let adapted = adapt(vec![flags.clone().into(), uid.clone().into()], &view, modseq, false, false);
+        assert_eq!(lines(adapted), ["2 FETCH (FLAGS (\\Seen) MODSEQ (50))", "* 1 FETCH (UID 3)\r\n"]);
+        // STORE .SILENT n'envoie que l'UID, pour le MODSEQ
+        let adapted = adapt(vec![uid.into()], &view, modseq, false, true);
+        assert_eq!(lines(adapted), ["1 FETCH (UID 3 MODSEQ (30))"]);
+    }
+}
@@ -0,0 +1,295 @@
+use reqwest::Url;
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crate::logging;
+use crate::state;
+
+/// Configuration du serveur, lue dans un fichier TOML. Les options de la ligne
+/// de commande passent par-dessus.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Adresses d'écoute en clair (avec STARTTLS si TLS est configuré)
+    pub listen: Vec<String>,
+    /// Secondes entre deux interrogations d'EcoleDirecte pendant IDLE
+    pub poll_interval: u64,
+    /// Taille maximale d'une commande, littéraux compris
+    pub max_command_size: usize,
+    /// Secondes laissées aux sessions pour finir leur commande à l'arrêt
+    pub shutdown_timeout: u64,
+    /// Connexions simultanées, en tout et par compte
+    pub max_connections: usize,
+    pub max_connections_per_account: usize,
+    /// Secondes laissées au client pour s'authentifier
+    pub login_timeout: u64,
+    /// Secondes d'inactivité avant de fermer une session (hors IDLE)
+    pub autologout: u64,
+    /// Où garder les UIDs attribués (voir `state`)
+    pub state_dir: PathBuf,
+    /// Où garder le contenu des messages déjà téléchargés. Sans, il n'est
+    /// gardé qu'en mémoire.
+    pub cache_dir: Option<PathBuf>,
+    pub tls: Option<Tls>,
+    pub api: Api,
+    pub log: Log,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Tls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// Adresses d'écoute en IMAPS (TLS dès la connexion)
+    #[serde(default = "default_tls_listen")]
+    pub listen: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Api {
+    pub url: String,
+    pub user_agent: String,
+    /// Proxy HTTP(S) pour joindre EcoleDirecte. Sans, ceux des variables
+    /// d'environnement habituelles (HTTPS_PROXY...) sont utilisés.
+    pub proxy: Option<String>,
+    /// Secondes
+    pub timeout: u64,
+    pub connect_timeout: u64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Log {
+    /// Niveau, ou filtre comme RUST_LOG ("info", "ecoledirecte_imap=debug"...)
+    pub level: String,
+    pub format: LogFormat,
+    /// "stderr", "stdout" ou un fichier (on écrit à la fin)
+    pub output: String,
+    /// Trace des commandes et des réponses, sans les mots de passe
+    pub protocol: bool,
+    /// Longueur maximale d'une ligne de la trace
+    pub protocol_max_line: usize,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Adresses d'écoute IMAPS quand la configuration n'en donne pas.
+pub fn default_tls_listen() -> Vec<String> {
+    vec![String::from("localhost:1994")]
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            listen: vec![String::from("localhost:1993")],
+            poll_interval: 60,
+            max_command_size: 64 * 1024,
+            shutdown_timeout: 30,
+            max_connections: 100,
+            max_connections_per_account: 10,
+            login_timeout: 60,
+            // Le minimum de la RFC 3501
+            autologout: 30 * 60,
+            state_dir: state::default_dir(),
+            cache_dir: None,
+            tls: None,
+            api: Api::default(),
+            log: Log::default(),
+        }
+    }
+}
+
+impl Default for Api {
+    fn default() -> Api {
+        Api {
+            url: String::from("https://api.ecoledirecte.com/"),
+            user_agent: String::from("ecoledirecte-imap"),
+            proxy: None,
+            timeout: 30,
+            connect_timeout: 10,
+        }
+    }
+}
+
+impl Default for Log {
+    fn default() -> Log {
+        Log {
+            level: String::from("info"),
+            format: LogFormat::Text,
+            output: String::from("stderr"),
+            protocol: false,
+            protocol_max_line: 200,
+        }
+    }
+}
+
+/// `$XDG_CONFIG_HOME/ecoledirecte-imap/config.toml`, lu s'il existe quand on
+/// ne donne pas de fichier.
+pub fn default_path() -> Option<PathBuf> {
+    match (env::var_os("XDG_CONFIG_HOME"), env::var_os("HOME")) {
+        (Some(config), _) => Some(PathBuf::from(config).join("ecoledirecte-imap/config.toml")),
+        (None, Some(home)) => Some(PathBuf::from(home).join(".config/ecoledirecte-imap/config.toml")),
+        (None, None) => None,
+    }
+}
+
+// Les chemins relatifs d'un fichier de configuration partent de son dossier
+fn resolve(base: &Path, path: &mut PathBuf) {
+    if path.is_relative() {
+        *path = base.join(&*path);
+    }
+}
+
+/// Toutes les adresses d'une liste ("localhost:1993" donne en général une
+/// adresse IPv4 et une IPv6).
+pub fn resolve_addresses(addresses: &[String]) -> Result<Vec<SocketAddr>, String> {
+    let mut resolved = Vec::new();
+    for address in addresses {
+        let socket_addrs = address
+            .to_socket_addrs()
+            .map_err(|error| format!("invalid listen address {address:?}: {error}"))?;
+        for socket_addr in socket_addrs {
+            if !resolved.contains(&socket_addr) {
+                resolved.push(socket_addr);
+            }
+        }
+    }
+    Ok(resolved)
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let text = fs::read_to_string(path).map_err(|error| format!("{}: {error}", path.display()))?;
+        let mut config: Config = toml::from_str(&text).map_err(|error| format!("{}: {error}", path.display()))?;
+        let base = path.parent().unwrap_or(Path::new(""));
+        resolve(base, &mut config.state_dir);
+        if let Some(cache_dir) = &mut config.cache_dir {
+            resolve(base, cache_dir);
+        }
+        if !matches!(config.log.output.as_str(), "stderr" | "stdout") {
+            let mut output = PathBuf::from(&config.log.output);
+            resolve(base, &mut output);
+            config.log.output = output.to_string_lossy().into_owned();
+        }
+        if let Some(tls) = &mut config.tls {
+            resolve(base, &mut tls.cert);
+            resolve(base, &mut tls.key);
+        }
+        Ok(config)
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval)
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout)
+    }
+
+    pub fn login_timeout(&self) -> Duration {
+        Duration::from_secs(self.login_timeout)
+    }
+
+    pub fn autologout(&self) -> Duration {
+        Duration::from_secs(self.autologout)
+    }
+
+    /// Vérifie ce qu'on peut vérifier avant de démarrer.
+    pub fn validate(&self, min_command_size: usize) -> Result<(), String> {
+        let tls_listen = self.tls.as_ref().map(|tls| tls.listen.as_slice()).unwrap_or_default();
+        if self.listen.is_empty() && tls_listen.is_empty() {
+            return Err(String::from("no listen address"));
+        }
+        resolve_addresses(&self.listen)?;
+        resolve_addresses(tls_listen)?;
+        if self.poll_interval == 0 {
+            return Err(String::from("poll-interval must be at least 1 second"));
+        }
+        if self.max_connections == 0 || self.max_connections_per_account == 0 {
+            return Err(String::from("connection limits must be at least 1"));
+        }
+        if self.login_timeout == 0 {
+            return Err(String::from("login-timeout must be at least 1 second"));
+        }
+        if self.autologout < 30 * 60 {
+            return Err(String::from("autologout must be at least 1800 seconds (RFC 3501)"));
+        }
+        if self.max_command_size < min_command_size {
+            return Err(format!("max-command-size must be at least {min_command_size} bytes"));
+        }
+        match Url::parse(&self.api.url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") && url.has_host() => (),
+            _ => return Err(format!("invalid API URL {:?}", self.api.url)),
+        }
+        if let Some(proxy) = &self.api.proxy {
+            reqwest::Proxy::all(proxy).map_err(|error| format!("invalid proxy {proxy:?}: {error}"))?;
+        }
+        logging::filter(&self.log)?;
+        if self.api.timeout == 0 || self.api.connect_timeout == 0 {
+            return Err(String::from("API timeouts must be at least 1 second"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn defaults_are_valid() {
+        assert_eq!(Config::default().validate(1024), Ok(()));
+
+        // Ce que le fichier ne donne pas garde sa valeur par défaut
+        let config: Config = toml::from_str("poll-interval = 5\n[tls]\ncert = \"cert.pem\"\nkey = \"key.pem\"\n[api]\ntimeout = 3\n").unwrap();
+        assert_eq!(config.poll_interval, 5);
+        assert_eq!(config.listen, ["localhost:1993"]);
+        assert_eq!(config.tls.unwrap().listen, default_tls_listen());
+        assert_eq!((config.api.timeout, config.api.connect_timeout), (3, 10));
+        assert_eq!(config.log.level, "info");
+
+        assert!(toml::from_str::<Config>("poll_interval = 5\n").is_err());
+    }
+
+    #[test]
+    fn relative_paths() {
+        let dir = env::temp_dir().join(format!("ecoledirecte-imap-test-config-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        fs::write(&path, "state-dir = \"state\"\ncache-dir = \"/var/cache/imap\"\n[log]\noutput = \"imap.log\"\n").unwrap();
+
+        let config = Config::load(&path).unwrap();
+        assert_eq!(config.state_dir, dir.join("state"));
+        assert_eq!(config.cache_dir, Some(PathBuf::from("/var/cache/imap")));
+        assert_eq!(PathBuf::from(config.log.output), dir.join("imap.log"));
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn invalid_settings() {
+        let invalid = |change: fn(&mut Config)| {
+            let mut config = Config::default();
+            change(&mut config);
+            config.validate(1024).unwrap_err()
+        };
+        assert_eq!(invalid(|config| config.listen.clear()), "no listen address");
+        assert!(invalid(|config| config.listen = vec![String::from("localhost")]).starts_with("invalid listen address"));
+        assert_eq!(invalid(|config| config.poll_interval = 0), "poll-interval must be at least 1 second");
+        assert_eq!(invalid(|config| config.max_connections_per_account = 0), "connection limits must be at least 1");
+        assert_eq!(invalid(|config| config.autologout = 60), "autologout must be at least 1800 seconds (RFC 3501)");
+        assert_eq!(invalid(|config| config.max_command_size = 100), "max-command-size must be at least 1024 bytes");
+        assert_eq!(invalid(|config| config.api.url = String::from("ftp://example.com/")), "invalid API URL \"ftp://example.com/\"");
+        assert_eq!(invalid(|config| config.api.connect_timeout = 0), "API timeouts must be at least 1 second");
+    }
+}
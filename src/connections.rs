@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::lock;
+
+// Connexions en cours, pour les compter et pour pouvoir les terminer à
+// l'arrêt du serveur
+static CONNECTIONS: OnceLock<Mutex<HashMap<u64, Entry>>> = OnceLock::new();
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+struct Entry {
+    // Copie du socket (sans, on ne pourra pas réveiller la session à l'arrêt)
+    stream: Option<TcpStream>,
+    // Identifiant du compte, une fois authentifié
+    account: Option<String>,
+}
+
+fn connections() -> &'static Mutex<HashMap<u64, Entry>> {
+    CONNECTIONS.get_or_init(Default::default)
+}
+
+/// Une connexion enregistrée, retirée quand elle se termine.
+pub struct Registration(u64);
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        lock(connections()).remove(&self.0);
+    }
+}
+
+impl Registration {
+    /// Réserve une place pour le compte si elle a moins de `max` connexions
+    /// (authentifiées ou en train de s'authentifier). La place est prise avant
+    /// d'aller voir EcoleDirecte, pour que des connexions simultanées ne
+    /// passent pas toutes.
+    pub fn reserve(&self, account: &str, max: usize) -> bool {
+        let account = key(account);
+        let mut connections = lock(connections());
+        let count = connections
+            .iter()
+            .filter(|(id, entry)| **id != self.0 && entry.account.as_deref() == Some(account.as_str()))
+            .count();
+        if count >= max {
+            return false;
+        }
+        if let Some(entry) = connections.get_mut(&self.0) {
+            entry.account = Some(account);
+        }
+        true
+    }
+
+    /// Rend la place réservée (l'authentification a échoué).
+    pub fn release(&self) {
+        if let Some(entry) = lock(connections()).get_mut(&self.0) {
+            entry.account = None;
+        }
+    }
+}
+
+// EcoleDirecte ne distingue pas les majuscules dans les identifiants
+fn key(account: &str) -> String {
+    account.to_lowercase()
+}
+
+pub fn register(stream: &TcpStream) -> Registration {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let entry = Entry { stream: stream.try_clone().ok(), account: None };
+    lock(connections()).insert(id, entry);
+    Registration(id)
+}
+
+/// Nombre de connexions en cours.
+pub fn count() -> usize {
+    lock(connections()).len()
+}
+
+pub fn shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::SeqCst)
+}
+
+/// Commence l'arrêt : les nouvelles connexions sont refusées et les sessions
+/// qui attendent une commande (ou sont en IDLE) voient la fin de la lecture.
+/// Celles qui traitent une commande la terminent d'abord.
+pub fn shut_down() {
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+    for entry in lock(connections()).values() {
+        if let Some(stream) = &entry.stream {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+    }
+}
+
+/// Attend la fin des sessions, au plus `timeout`. Renvoie le nombre de
+/// sessions encore en cours.
+pub fn wait(timeout: Duration) -> usize {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = lock(connections()).len();
+        if remaining == 0 || Instant::now() >= deadline {
+            return remaining;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn account_places() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let first = register(&stream);
+        let second = register(&stream);
+
+        assert!(first.reserve("Places", 1));
+        assert!(!second.reserve("places", 1));
+        first.release();
+        assert!(second.reserve("PLACES", 1));
+        drop(second);
+        assert!(first.reserve("places", 1));
+    }
+}
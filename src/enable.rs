@@ -0,0 +1,67 @@
+use imap_codec::imap_types::{
+    core::{NonEmptyVec, Tag},
+    extensions::enable::CapabilityEnable,
+    response::{Data, Response, Status},
+};
+
+/// Extensions que le client a activées pour la session, avec ENABLE ou
+/// implicitement (SELECT (CONDSTORE), FETCH MODSEQ...).
+#[derive(Clone, Copy, Default)]
+pub struct Extensions {
+    pub condstore: bool,
+    pub qresync: bool,
+}
+
+// UTF8=ACCEPT n'est pas géré : imap-codec refuse l'UTF-8 dans les chaînes
+// entre guillemets, donc le client ne pourrait pas s'en servir. Comme le veut
+// la RFC 5161, les extensions inconnues sont ignorées.
+pub fn handle<'a>(tag: Tag<'a>, capabilities: NonEmptyVec<CapabilityEnable<'a>>, extensions: &mut Extensions) -> Vec<Response<'a>> {
+    let mut enabled = Vec::new();
+    for capability in capabilities.into_inner() {
+        match capability.to_string().to_ascii_uppercase().as_str() {
+            "CONDSTORE" => extensions.condstore = true,
+            // QRESYNC implique CONDSTORE
+            "QRESYNC" => {
+                extensions.condstore = true;
+                extensions.qresync = true;
+            }
+            _ => continue,
+        }
+        enabled.push(capability);
+    }
+
+    vec![
+        Response::Data(Data::Enabled { capabilities: enabled }),
+        Response::Status(Status::ok(Some(tag), None, "ENABLE completed").unwrap()),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use imap_codec::{encode::Encoder, ResponseCodec};
+
+    fn enable(names: &[&'static str], extensions: &mut Extensions) -> Vec<String> {
+        let capabilities = names.iter().map(|name| CapabilityEnable::try_from(*name).unwrap()).collect::<Vec<_>>();
+        handle(Tag::try_from("a").unwrap(), NonEmptyVec::try_from(capabilities).unwrap(), extensions)
+            .iter()
+            .map(|response| String::from_utf8(ResponseCodec::default().encode(response).dump()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn enabled_extensions() {
+        let mut extensions = Extensions::default();
+        // Les extensions inconnues et UTF8=ACCEPT sont ignorées
+        assert_eq!(enable(&["UTF8=ACCEPT", "X-FOO"], &mut extensions), ["* ENABLED\r\n", "a OK ENABLE completed\r\n"]);
+        assert!(!extensions.condstore && !extensions.qresync);
+
+        // Le nom est renvoyé comme le client l'a écrit
+        assert_eq!(enable(&["condstore"], &mut extensions), ["* ENABLED condstore\r\n", "a OK ENABLE completed\r\n"]);
+        assert!(extensions.condstore && !extensions.qresync);
+
+        let mut extensions = Extensions::default();
+        assert_eq!(enable(&["QRESYNC"], &mut extensions), ["* ENABLED QRESYNC\r\n", "a OK ENABLE completed\r\n"]);
+        assert!(extensions.condstore && extensions.qresync);
+    }
+}
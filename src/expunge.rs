@@ -0,0 +1,118 @@
+use imap_codec::imap_types::{
+    core::Tag,
+    response::{Data, Response, Status},
+    sequence::SequenceSet,
+};
+use std::collections::HashSet;
+use std::num::NonZeroU32;
+use crate::fetch;
+
+/// Supprime les messages marqués \Deleted (seulement ceux dont l'UID est
+/// dans `uids` pour UID EXPUNGE) et renvoie les réponses EXPUNGE.
+pub fn expunge<'a, F: Fn(&[u32]) -> Result<(), Option<String>>>(uids: Option<&SequenceSet>, messages: &[(u32, serde_json::Value)], deleted: &mut HashSet<u32>, delete_messages: F) -> Result<Vec<Response<'a>>, Option<String>> {
+    let largest = fetch::largest(messages, true);
+    let positions: Vec<(usize, u32)> = messages
+        .iter()
+        .enumerate()
+        .filter(|(_, message)| deleted.contains(&message.0))
+        .filter(|(_, message)| uids.is_none_or(|uids| fetch::matches(uids, message.0, largest)))
+        .map(|(pos, message)| (pos, message.0))
+        .collect();
+
+    if positions.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let message_ids: Vec<u32> = positions
+        .iter()
+        .map(|&(pos, _)| messages[pos].1["id"].as_u64().unwrap() as u32)
+        .collect();
+    delete_messages(&message_ids)?;
+    for (_, uid) in &positions {
+        deleted.remove(uid);
+    }
+
+    // Du plus grand au plus petit pour que les numéros restent valides
+    Ok(positions
+        .iter()
+        .rev()
+        .map(|&(pos, _)| Response::Data(Data::Expunge(NonZeroU32::new((pos + 1) as u32).unwrap())))
+        .collect())
+}
+
+pub fn handle<'a, F: Fn(&[u32]) -> Result<(), Option<String>>>(tag: Tag<'a>, uids: Option<&SequenceSet>, messages: Vec<(u32, serde_json::Value)>, deleted: &mut HashSet<u32>, delete_messages: F) -> Vec<Response<'a>> {
+    match expunge(uids, &messages, deleted, delete_messages) {
+        Ok(mut responses) => {
+            responses.push(Response::Status(Status::ok(Some(tag), None, "EXPUNGE completed").unwrap()));
+            responses
+        }
+        Err(message) => vec![Response::Status(
+            Status::no(
+                Some(tag),
+                None,
+                match message {
+                    Some(message) => format!("EXPUNGE failed: {}", message),
+                    None => String::from("EXPUNGE failed"),
+                },
+            )
+            .unwrap(),
+        )],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use imap_codec::{encode::Encoder, ResponseCodec};
+    use serde_json::{json, Value};
+    use std::cell::RefCell;
+
+    fn lines(responses: Vec<Response>) -> Vec<String> {
+        responses
+            .iter()
+            .map(|response| String::from_utf8(ResponseCodec::default().encode(response).dump()).unwrap())
+            .collect()
+    }
+
+    // UIDs 3, 5 et 9 pour les messages 30, 50 et 90
+    fn messages() -> Vec<(u32, Value)> {
+        [3, 5, 9].map(|uid| (uid, json!({ "id": uid * 10 }))).to_vec()
+    }
+
+    #[test]
+    fn only_deleted_messages() {
+        let tag = Tag::try_from("a").unwrap();
+        let deleted_ids = RefCell::new(Vec::new());
+        let delete_messages = |message_ids: &[u32]| {
+            deleted_ids.borrow_mut().push(message_ids.to_vec());
+            Ok(())
+        };
+
+        let mut deleted = HashSet::from([3, 9]);
+        let responses = handle(tag.clone(), None, messages(), &mut deleted, delete_messages);
+        assert_eq!(lines(responses), ["* 3 EXPUNGE\r\n", "* 1 EXPUNGE\r\n", "a OK EXPUNGE completed\r\n"]);
+        assert!(deleted.is_empty());
+
+        // UID EXPUNGE ne touche qu'aux UIDs demandés
+        let mut deleted = HashSet::from([3, 9]);
+        let uids = SequenceSet::try_from("4:*").unwrap();
+        let responses = handle(tag.clone(), Some(&uids), messages(), &mut deleted, delete_messages);
+        assert_eq!(lines(responses), ["* 3 EXPUNGE\r\n", "a OK EXPUNGE completed\r\n"]);
+        assert_eq!(deleted, HashSet::from([3]));
+
+        // Rien à supprimer : EcoleDirecte n'est pas sollicité
+        let responses = handle(tag, Some(&uids), messages(), &mut deleted, delete_messages);
+        assert_eq!(lines(responses), ["a OK EXPUNGE completed\r\n"]);
+        assert_eq!(*deleted_ids.borrow(), [vec![30, 90], vec![90]]);
+    }
+
+    #[test]
+    fn failed_expunge() {
+        let tag = Tag::try_from("a").unwrap();
+        let mut deleted = HashSet::from([5]);
+        let responses = handle(tag, None, messages(), &mut deleted, |_| Err(None));
+        assert_eq!(lines(responses), ["a NO EXPUNGE failed\r\n"]);
+        // Les messages restent marqués \Deleted
+        assert_eq!(deleted, HashSet::from([5]));
+    }
+}
@@ -8,13 +8,14 @@ use imap_codec::imap_types::{
 use std::num::NonZeroU32;
 use chrono::NaiveDateTime;
 use mime_sniffer::MimeTypeSniffer;
+use base64::Engine;
 use crate::NonEmptyVec;
 
 fn make_person(person: &serde_json::Value) -> String {
     format!("\"{}\" <>", person["name"].as_str().unwrap())
 }
 
-fn make_header(message: &serde_json::Value) -> String {
+pub fn make_header(message: &serde_json::Value) -> String {
     let date = NaiveDateTime::parse_from_str(message["date"].as_str().unwrap(), "%Y-%m-%d %H:%M:%S").unwrap();
     let date = date.and_local_timezone(chrono::Local).unwrap();
 
@@ -43,40 +44,54 @@ fn make_header(message: &serde_json::Value) -> String {
         format!("From: {}", make_person(&message["from"])),
         format!("Message-ID: <{}@>", message["id"].as_u64().unwrap())
     ];
-    if to.len() > 0 { headers.push(format!("To: {}", to.join(",\r\n "))) }
-    if cc.len() > 0 { headers.push(format!("Cc: {}", cc.join(",\r\n "))) }
-    if cci.len() > 0 { headers.push(format!("Cci: {}", cci.join(",\r\n "))) }
+    if !to.is_empty() { headers.push(format!("To: {}", to.join(",\r\n "))) }
+    if !cc.is_empty() { headers.push(format!("Cc: {}", cc.join(",\r\n "))) }
+    if !cci.is_empty() { headers.push(format!("Cci: {}", cci.join(",\r\n "))) }
     if response_id > 0 { headers.push(format!("In-Reply-To: <{}@>", response_id)) }
     if forward_id > 0 { headers.push(format!("Recent-Message-ID: <{}@>", forward_id)) }
 
     headers.join("\r\n")
 }
 
-fn get_item<'a, F: Fn(u32) -> serde_json::Value, G: Fn(u32) -> bytes::Bytes>(item: &MessageDataItemName, message: &serde_json::Value, get_message: F, get_attachment: G) -> Option<MessageDataItem<'a>> {
+/// Drapeaux EcoleDirecte d'un message, suivis de ceux qu'on garde localement.
+pub fn flags(message: &serde_json::Value, local_flags: Vec<Flag<'static>>) -> Vec<Flag<'static>> {
+    let mut flags = Vec::new();
+    if message["read"].as_bool().unwrap() {
+        flags.push(Flag::Seen);
+    }
+    if message["answered"].as_bool().unwrap() {
+        flags.push(Flag::Answered);
+    }
+    if message["brouillon"].as_bool().unwrap() {
+        flags.push(Flag::Draft);
+    }
+    flags.extend(local_flags);
+    flags
+}
+
+/// Les mots-clés ne tiennent pas compte de la casse.
+pub fn same_flag(a: &Flag, b: &Flag) -> bool {
+    match (a, b) {
+        (Flag::Keyword(a), Flag::Keyword(b)) => a.as_ref().eq_ignore_ascii_case(b.as_ref()),
+        _ => a == b,
+    }
+}
+
+fn get_item<'a, F: Fn(u32) -> serde_json::Value, G: Fn(u32) -> bytes::Bytes, H: Fn(u32) -> Vec<Flag<'static>>>(item: &MessageDataItemName, uid: u32, message: &serde_json::Value, get_message: F, get_attachment: G, local_flags: H) -> Option<MessageDataItem<'a>> {
     match item {
-        MessageDataItemName::Flags => {
-            let mut flags = Vec::new();
-            if message["read"].as_bool().unwrap() {
-                flags.push(FlagFetch::Flag(Flag::Seen));
-            }
-            if message["answered"].as_bool().unwrap() {
-                flags.push(FlagFetch::Flag(Flag::Answered));
-            }
-            if message["brouillon"].as_bool().unwrap() {
-                flags.push(FlagFetch::Flag(Flag::Draft));
-            }
-            Some(MessageDataItem::Flags(flags))
-        },
+        MessageDataItemName::Flags => Some(MessageDataItem::Flags(
+            flags(message, local_flags(uid)).into_iter().map(FlagFetch::Flag).collect(),
+        )),
         MessageDataItemName::Uid =>
-            Some(MessageDataItem::Uid(NonZeroU32::new(message["id"].as_u64().unwrap() as u32).unwrap())),
+            Some(MessageDataItem::Uid(NonZeroU32::new(uid).unwrap())),
         MessageDataItemName::Rfc822Size => None,
         MessageDataItemName::Rfc822Header =>
             Some(MessageDataItem::Rfc822Header(Literal::try_from(make_header(message)).unwrap().into())),
         MessageDataItemName::BodyStructure => None,
         MessageDataItemName::BodyExt { section: _, partial: _, peek: _ } => {
             let data = &get_message(message["id"].as_u64().unwrap() as u32)["content"];
-            let contents = data.as_str().unwrap();
-            let has_attachments = message["files"].as_array().unwrap().len() > 0;
+            let contents = data.as_str()?;
+            let has_attachments = !message["files"].as_array().unwrap().is_empty();
             let full_email = if has_attachments {
                 make_header(message) + "\r\nContent-Type: multipart/mixed; boundary=\"=PARTLIMIT\"\r\n\r\n"
                     + "--=PARTLIMIT\r\nContent-Disposition: inline\r\nContent-Type: text/html\r\nContent-Transfer-Encoding: base64\r\n\r\n" + contents
@@ -86,7 +101,7 @@ fn get_item<'a, F: Fn(u32) -> serde_json::Value, G: Fn(u32) -> bytes::Bytes>(ite
                             let name = attachment["libelle"].as_str().unwrap();
                             let data = &get_attachment(attachment["id"].as_u64().unwrap() as u32);
                             let content_type = data.sniff_mime_type().unwrap_or("application/octet-stream");
-                            format!("\r\n\r\n--=PARTLIMIT\r\nContent-Disposition: attachment; filename=\"{name}\"\r\nContent-Type: {content_type}; name=\"{name}\"\r\nContent-Transfer-Encoding: base64\r\nContent-Description: {name}\r\n\r\n") + &base64::encode(data)
+                            format!("\r\n\r\n--=PARTLIMIT\r\nContent-Disposition: attachment; filename=\"{name}\"\r\nContent-Type: {content_type}; name=\"{name}\"\r\nContent-Transfer-Encoding: base64\r\nContent-Description: {name}\r\n\r\n") + &base64::engine::general_purpose::STANDARD.encode(data)
                         })
                         .collect::<Vec<_>>()
                         .join("")
@@ -104,50 +119,86 @@ fn get_item<'a, F: Fn(u32) -> serde_json::Value, G: Fn(u32) -> bytes::Bytes>(ite
     }
 }
 
-pub fn handle<'a, F: Fn(u32) -> serde_json::Value, G: Fn(u32) -> bytes::Bytes>(tag: Tag<'a>, sequence_set: SequenceSet, macro_or_item_names: MacroOrMessageDataItemNames, uid: bool, messages: Vec<(u32, serde_json::Value)>, get_message: F, get_attachment: G) -> Vec<Response<'a>> {
-    let mut responses: Vec<Response> = messages
+/// Regroupe les numéros consécutifs : 1,2,3,7 -> 1:3,7
+pub fn sequence_set(ids: &[u32]) -> String {
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for &id in ids {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == id => *end = id,
+            _ => ranges.push((id, id)),
+        }
+    }
+    ranges
         .iter()
-        .enumerate()
-        .filter_map(|(pos, message)| {
-            let id = if uid { message.0 } else { (pos + 1) as u32 };
-            let good = sequence_set.0
-                .clone()
-                .into_iter()
-                .map(|sequence| {
-                    match sequence {
-                        Sequence::Single(seq) => match seq {
-                            SeqOrUid::Asterisk => true,
-                            SeqOrUid::Value(v) => v.get() == id,
-                        },
-                        Sequence::Range(start, end) => {
-                            (match start {
-                                SeqOrUid::Asterisk => true,
-                                SeqOrUid::Value(v) => v.get() <= id,
-                            }) && (match end {
-                                SeqOrUid::Asterisk => true,
-                                SeqOrUid::Value(v) => id <= v.get(),
-                            })
-                        }
-                    }
-                }).any(|x| x);
-            if good {
-                let mut items = match &macro_or_item_names {
-                    Macro(macro_name) => macro_name.expand(),
-                    MessageDataItemNames(items) => items.to_vec(),
-                };
-                if uid {
-                    items.push(MessageDataItemName::Uid);
+        .map(|&(start, end)| if start == end { start.to_string() } else { format!("{start}:{end}") })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Vrai si `id` est dans `sequence_set`, où `*` vaut `largest` (le numéro
+/// ou l'UID du dernier message).
+pub fn matches(sequence_set: &SequenceSet, id: u32, largest: u32) -> bool {
+    let value = |seq: &SeqOrUid| match seq {
+        SeqOrUid::Asterisk => largest,
+        SeqOrUid::Value(v) => v.get(),
+    };
+    sequence_set.0
+        .as_ref()
+        .iter()
+        .any(|sequence| {
+            match sequence {
+                Sequence::Single(seq) => value(seq) == id,
+                // 4:2 est la même chose que 2:4
+                Sequence::Range(start, end) => {
+                    let (start, end) = (value(start), value(end));
+                    start.min(end) <= id && id <= start.max(end)
                 }
-                Some(Response::Data(Data::fetch(NonZeroU32::new((pos + 1) as u32).unwrap(),
-                    NonEmptyVec::try_from(items
-                        .iter()
-                        .filter_map(|item| { get_item(&item, &message.1, &get_message, &get_attachment) })
-                        .collect::<Vec<_>>()
-                    ).unwrap()).unwrap()))
-            } else {
-                None
             }
         })
+}
+
+/// Numéro de séquence ou UID du dernier message (ce que vaut `*`), 0 si le
+/// dossier est vide.
+pub fn largest(messages: &[(u32, serde_json::Value)], uid: bool) -> u32 {
+    match messages.last() {
+        Some((last_uid, _)) if uid => *last_uid,
+        _ => messages.len() as u32,
+    }
+}
+
+/// Positions (à partir de 0) des messages désignés par `sequence_set`, en
+/// numéros de séquence ou en UIDs. `messages` est trié par UID.
+pub fn positions(sequence_set: &SequenceSet, uid: bool, messages: &[(u32, serde_json::Value)]) -> Vec<usize> {
+    let largest = largest(messages, uid);
+    messages
+        .iter()
+        .enumerate()
+        .filter(|(pos, message)| matches(sequence_set, if uid { message.0 } else { (*pos + 1) as u32 }, largest))
+        .map(|(pos, _)| pos)
+        .collect()
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn handle<'a, F: Fn(u32) -> serde_json::Value, G: Fn(u32) -> bytes::Bytes, H: Fn(u32) -> Vec<Flag<'static>>>(tag: Tag<'a>, sequence_set: SequenceSet, macro_or_item_names: MacroOrMessageDataItemNames, uid: bool, messages: Vec<(u32, serde_json::Value)>, get_message: F, get_attachment: G, local_flags: H) -> Vec<Response<'a>> {
+    let mut responses: Vec<Response> = positions(&sequence_set, uid, &messages)
+        .into_iter()
+        // Sans aucun élément (contenu indisponible...), pas de réponse FETCH
+        .filter_map(|pos| {
+            let message = &messages[pos];
+            let mut items = match &macro_or_item_names {
+                Macro(macro_name) => macro_name.expand(),
+                MessageDataItemNames(items) => items.to_vec(),
+            };
+            if uid {
+                items.push(MessageDataItemName::Uid);
+            }
+            Some(Response::Data(Data::fetch(NonZeroU32::new((pos + 1) as u32).unwrap(),
+                NonEmptyVec::try_from(items
+                    .iter()
+                    .filter_map(|item| { get_item(item, message.0, &message.1, &get_message, &get_attachment, &local_flags) })
+                    .collect::<Vec<_>>()
+                ).ok()?).unwrap()))
+        })
         .collect();
 
     responses.push(Response::Status(
@@ -157,3 +208,49 @@ pub fn handle<'a, F: Fn(u32) -> serde_json::Value, G: Fn(u32) -> bytes::Bytes>(t
     responses
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn set(sequence_set: &str) -> SequenceSet {
+        SequenceSet::try_from(sequence_set).unwrap()
+    }
+
+    // UIDs 3, 5 et 9 : numéros de séquence 1 à 3
+    fn messages() -> Vec<(u32, Value)> {
+        [3, 5, 9].map(|uid| (uid, Value::Null)).to_vec()
+    }
+
+    #[test]
+    fn ranges_and_asterisk() {
+        assert!(matches(&set("2:4"), 3, 10));
+        assert!(matches(&set("4:2"), 2, 10));
+        assert!(!matches(&set("4:2"), 5, 10));
+        assert!(matches(&set("*"), 10, 10));
+        assert!(matches(&set("8:*"), 12, 12));
+        // *:3 avec un dernier message plus petit que 3
+        assert!(matches(&set("*:3"), 2, 2));
+        assert!(!matches(&set("1,3"), 2, 10));
+    }
+
+    #[test]
+    fn largest_message() {
+        assert_eq!(largest(&messages(), false), 3);
+        assert_eq!(largest(&messages(), true), 9);
+        assert_eq!(largest(&[], false), 0);
+        assert_eq!(largest(&[], true), 0);
+    }
+
+    #[test]
+    fn positions_by_uid_or_sequence_number() {
+        assert_eq!(positions(&set("2:*"), false, &messages()), [1, 2]);
+        assert_eq!(positions(&set("5:*"), true, &messages()), [1, 2]);
+        // Des UIDs qui n'existent pas ne désignent rien
+        assert_eq!(positions(&set("1,4,6:8"), true, &messages()), [] as [usize; 0]);
+        assert_eq!(positions(&set("*"), true, &messages()), [2]);
+        assert_eq!(positions(&set("10:*"), true, &messages()), [2]);
+        assert_eq!(positions(&set("1:*"), false, &[]), [] as [usize; 0]);
+    }
+}
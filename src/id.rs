@@ -0,0 +1,46 @@
+use imap_codec::imap_types::{
+    core::Tag,
+    response::{Response, Status},
+};
+use crate::api;
+use crate::Reply;
+
+/// Identité du serveur (RFC 2971). Celle du client n'est pas utilisée.
+pub fn handle(tag: Tag) -> Vec<Reply> {
+    let fields = [
+        ("name", env!("CARGO_PKG_NAME")),
+        ("version", env!("CARGO_PKG_VERSION")),
+        ("ecoledirecte-api-version", api::API_VERSION),
+    ];
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|(field, value)| format!("\"{field}\" \"{value}\""))
+        .collect();
+
+    vec![
+        Reply::Untagged(format!("ID ({})", fields.join(" "))),
+        Response::Status(Status::ok(Some(tag), None, "ID completed").unwrap()).into(),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use imap_codec::{encode::Encoder, ResponseCodec};
+
+    #[test]
+    fn server_identity() {
+        let replies = handle(Tag::try_from("a").unwrap());
+        let Reply::Untagged(id) = &replies[0] else { panic!("réponse ID attendue") };
+        assert_eq!(
+            *id,
+            format!(
+                "ID (\"name\" \"ecoledirecte-imap\" \"version\" \"{}\" \"ecoledirecte-api-version\" \"{}\")",
+                env!("CARGO_PKG_VERSION"),
+                api::API_VERSION,
+            )
+        );
+        let Reply::Response(response) = &replies[1] else { panic!("réponse OK attendue") };
+        assert_eq!(ResponseCodec::default().encode(response).dump(), b"a OK ID completed\r\n");
+    }
+}
@@ -0,0 +1,110 @@
+use imap_codec::imap_types::{
+    bounded_static::IntoBoundedStatic,
+    core::NonEmptyVec,
+    fetch::MessageDataItem,
+    flag::{Flag, FlagFetch},
+    response::{Data, Response},
+};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroU32;
+use crate::fetch;
+
+/// Ce que le client connaît du dossier sélectionné : l'UID et les drapeaux
+/// de chaque message, dans l'ordre des numéros de séquence.
+pub type View = Vec<(u32, Vec<Flag<'static>>)>;
+
+pub fn view<F: Fn(u32) -> Vec<Flag<'static>>>(messages: &[(u32, Value)], local_flags: F) -> View {
+    messages
+        .iter()
+        .map(|(uid, message)| (*uid, fetch::flags(message, local_flags(*uid))))
+        .collect()
+}
+
+/// Met à jour la vue avec les EXPUNGE et les drapeaux (FETCH) qu'on vient
+/// d'envoyer au client.
+pub fn apply(view: &mut View, responses: &[Response]) {
+    for response in responses {
+        match response {
+            Response::Data(Data::Expunge(seq)) => {
+                let pos = seq.get() as usize - 1;
+                if pos < view.len() {
+                    view.remove(pos);
+                }
+            }
+            Response::Data(Data::Fetch { seq, items }) => {
+                let flags = items.as_ref().iter().find_map(|item| match item {
+                    MessageDataItem::Flags(flags) => Some(flags),
+                    _ => None,
+                });
+                if let (Some(flags), Some((_, known))) = (flags, view.get_mut(seq.get() as usize - 1)) {
+                    *known = flags
+                        .iter()
+                        .filter_map(|flag| match flag {
+                            FlagFetch::Flag(flag) => Some(flag.clone().into_static()),
+                            _ => None,
+                        })
+                        .collect();
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Réponses non sollicitées qui font passer le client de `old` à `new`.
+pub fn updates(old: &View, new: &View) -> Vec<Response<'static>> {
+    let new_flags: HashMap<u32, &Vec<Flag>> = new.iter().map(|(uid, flags)| (*uid, flags)).collect();
+
+    // Du plus grand au plus petit pour que les numéros restent valides
+    let mut responses: Vec<Response> = old
+        .iter()
+        .enumerate()
+        .rev()
+        .filter(|(_, (uid, _))| !new_flags.contains_key(uid))
+        .map(|(pos, _)| Response::Data(Data::Expunge(NonZeroU32::new(pos as u32 + 1).unwrap())))
+        .collect();
+
+    // Les UIDs sont attribués dans l'ordre croissant donc les messages qui
+    // restent gardent leur place et les nouveaux arrivent à la fin.
+    let remaining: Vec<&(u32, Vec<Flag>)> = old.iter().filter(|(uid, _)| new_flags.contains_key(uid)).collect();
+    for (pos, (uid, flags)) in remaining.iter().enumerate() {
+        let new = new_flags[uid];
+        if flags.iter().collect::<HashSet<_>>() != new.iter().collect::<HashSet<_>>() {
+            responses.push(Response::Data(Data::Fetch {
+                seq: NonZeroU32::new(pos as u32 + 1).unwrap(),
+                items: NonEmptyVec::try_from(vec![
+                    MessageDataItem::Uid(NonZeroU32::new(*uid).unwrap()),
+                    MessageDataItem::Flags(new.iter().cloned().map(FlagFetch::Flag).collect()),
+                ])
+                .unwrap(),
+            }));
+        }
+    }
+
+    if new.len() != remaining.len() {
+        responses.push(Response::Data(Data::Exists(new.len() as u32)));
+    }
+
+    responses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applied_responses_are_not_sent_again() {
+        let mut view: View = vec![(3, vec![]), (5, vec![Flag::Seen]), (9, vec![])];
+        let new: View = vec![(3, vec![Flag::Flagged]), (9, vec![])];
+        let flagged = Response::Data(Data::Fetch {
+            seq: NonZeroU32::new(1).unwrap(),
+            items: NonEmptyVec::try_from(vec![MessageDataItem::Flags(vec![FlagFetch::Flag(Flag::Flagged)])]).unwrap(),
+        });
+        let expunged = Response::Data(Data::Expunge(NonZeroU32::new(2).unwrap()));
+        assert_eq!(updates(&view, &new).len(), 2);
+        apply(&mut view, &[flagged, expunged]);
+        assert_eq!(view, new);
+        assert!(updates(&view, &new).is_empty());
+    }
+}
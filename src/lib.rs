@@ -1,14 +1,105 @@
+pub mod account;
 pub mod api;
 pub mod auth;
+pub mod cache;
+pub mod command;
+pub mod condstore;
+pub mod config;
+pub mod connections;
+pub mod enable;
+pub mod expunge;
 pub mod fetch;
+pub mod id;
+pub mod idle;
+pub mod logging;
 pub mod lsub;
 pub mod mailbox;
+pub mod r#move;
+pub mod namespace;
+pub mod search;
+pub mod sort;
+pub mod state;
 pub mod status;
 pub mod store;
+pub mod thread;
+pub mod tls;
 
-use imap_codec::imap_types::{core::NonEmptyVec, response::Capability};
+use imap_codec::{
+    encode::Encoder,
+    imap_types::{
+        core::{Atom, NonEmptyVec},
+        response::{Capability, Response},
+    },
+    ResponseCodec,
+};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use tls::Encryption;
 
-pub fn capabilities() -> NonEmptyVec<Capability<'static>> {
-    use imap_codec::imap_types::{auth::AuthMechanism::*, response::Capability::*};
-    NonEmptyVec::try_from(vec![Imap4Rev1, Auth(Plain)]).unwrap()
+/// Verrouille un mutex partagé, même si une session a paniqué en le tenant :
+/// les sessions sont isolées les unes des autres (voir `catch_unwind` dans
+/// main.rs) et ce qu'il protège reste utilisable.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Réponse à envoyer au client : soit une réponse qu'imap-codec sait encoder,
+/// soit une réponse non étiquetée d'une extension qu'il ne connaît pas (sans
+/// "* " ni CRLF).
+pub enum Reply<'a> {
+    Response(Response<'a>),
+    Untagged(String),
+}
+
+impl<'a> From<Response<'a>> for Reply<'a> {
+    fn from(response: Response<'a>) -> Reply<'a> {
+        Reply::Response(response)
+    }
+}
+
+impl Reply<'_> {
+    /// Ajoute des éléments qu'imap-codec ne connaît pas (MODSEQ,
+    /// HIGHESTMODSEQ...) à la fin de la liste d'une réponse non étiquetée
+    /// comme FETCH ou STATUS.
+    pub fn extend(response: &Response, items: &str) -> Reply<'static> {
+        let encoded = ResponseCodec::default().encode(response).dump();
+        let encoded = String::from_utf8_lossy(&encoded);
+        let line = encoded.strip_prefix("* ").unwrap_or(&encoded);
+        let line = line.strip_suffix(")\r\n").unwrap_or(line);
+        let separator = if line.ends_with('(') { "" } else { " " };
+        Reply::Untagged(format!("{line}{separator}{items})"))
+    }
+}
+
+pub fn capabilities(encryption: Encryption) -> NonEmptyVec<Capability<'static>> {
+    use imap_codec::imap_types::response::Capability::*;
+    let mut capabilities = vec![Imap4Rev1];
+    // Pas de mot de passe en clair quand on peut chiffrer la connexion
+    if encryption == Encryption::Available {
+        capabilities.extend([StartTls, LoginDisabled]);
+    } else {
+        capabilities.extend(auth::mechanisms().map(Auth));
+        capabilities.push(SaslIr);
+    }
+    capabilities.extend([
+        LiteralMinus,
+        Move,
+        Idle,
+        Enable,
+    ]);
+    capabilities.extend(
+        [
+            "UIDPLUS",
+            "ESEARCH",
+            "SEARCHRES",
+            "SORT",
+            "THREAD=ORDEREDSUBJECT",
+            "THREAD=REFERENCES",
+            "CONDSTORE",
+            "QRESYNC",
+            "NAMESPACE",
+            "ID",
+        ]
+        .map(|name| Capability::from(Atom::try_from(name).unwrap())),
+    );
+    NonEmptyVec::try_from(capabilities).unwrap()
 }
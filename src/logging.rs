@@ -0,0 +1,113 @@
+use imap_codec::{
+    encode::Encoder,
+    imap_types::command::{Command, CommandBody},
+    CommandCodec,
+};
+use std::fs::OpenOptions;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use tracing::Level;
+use tracing_subscriber::EnvFilter;
+use crate::config::{self, LogFormat};
+
+/// Cible des traces du protocole (commandes et réponses), au niveau TRACE.
+pub const PROTOCOL: &str = "protocol";
+
+// Longueur maximale d'une ligne de la trace du protocole
+static MAX_LINE: AtomicUsize = AtomicUsize::new(200);
+
+/// Filtre des journaux : le niveau demandé, plus la trace du protocole si
+/// elle est activée.
+pub fn filter(config: &config::Log) -> Result<EnvFilter, String> {
+    let mut directives = config.level.clone();
+    let directive = if config.protocol { "trace" } else { "off" };
+    directives += &format!(",{PROTOCOL}={directive}");
+    EnvFilter::try_new(&directives).map_err(|error| format!("invalid log level {:?}: {error}", config.level))
+}
+
+pub fn init(config: &config::Log) -> Result<(), String> {
+    MAX_LINE.store(config.protocol_max_line, Ordering::Relaxed);
+    let builder = tracing_subscriber::fmt().with_env_filter(filter(config)?);
+    let result = match (config.output.as_str(), config.format) {
+        ("stderr", LogFormat::Text) => builder.with_writer(io::stderr).try_init(),
+        ("stderr", LogFormat::Json) => builder.json().with_writer(io::stderr).try_init(),
+        ("stdout", LogFormat::Text) => builder.with_writer(io::stdout).try_init(),
+        ("stdout", LogFormat::Json) => builder.json().with_writer(io::stdout).try_init(),
+        (path, format) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|error| format!("{path}: {error}"))?;
+            let builder = builder.with_ansi(false).with_writer(Mutex::new(file));
+            match format {
+                LogFormat::Text => builder.try_init(),
+                LogFormat::Json => builder.json().try_init(),
+            }
+        }
+    };
+    result.map_err(|error| error.to_string())
+}
+
+// Une ligne sans son CRLF, coupée si elle est trop longue (les messages
+// entiers en base64 n'ont rien à faire dans les journaux)
+fn truncate(data: &[u8]) -> String {
+    let line = String::from_utf8_lossy(data);
+    let line = line.strip_suffix("\r\n").unwrap_or(&line);
+    let max = MAX_LINE.load(Ordering::Relaxed);
+    match line.char_indices().nth(max) {
+        Some((end, _)) => format!("{}... ({} bytes)", &line[..end], data.len()),
+        None => line.to_string(),
+    }
+}
+
+/// Trace une réponse envoyée au client, ou une ligne reçue qu'on n'a pas pu
+/// décoder normalement.
+pub fn protocol(direction: &str, data: &[u8]) {
+    if tracing::enabled!(target: PROTOCOL, Level::TRACE) {
+        tracing::trace!(target: PROTOCOL, direction, "{}", truncate(data));
+    }
+}
+
+/// Trace une commande, sans les mots de passe ni les données
+/// d'authentification.
+pub fn command(command: &Command) {
+    if tracing::enabled!(target: PROTOCOL, Level::TRACE) {
+        tracing::trace!(target: PROTOCOL, direction = "C", "{}", redacted(command));
+    }
+}
+
+fn redacted(command: &Command) -> String {
+    match &command.body {
+        CommandBody::Login { username, .. } => {
+            let username = String::from_utf8_lossy(username.as_ref());
+            format!("{} LOGIN {username:?} <redacted>", command.tag.inner())
+        }
+        CommandBody::Authenticate { mechanism, initial_response: Some(_) } => {
+            format!("{} AUTHENTICATE {mechanism} <redacted>", command.tag.inner())
+        }
+        _ => truncate(&CommandCodec::default().encode(command).dump()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use imap_codec::decode::Decoder;
+
+    fn line(command: &[u8]) -> String {
+        let (_, command) = CommandCodec::default().decode(command).unwrap();
+        redacted(&command)
+    }
+
+    #[test]
+    fn passwords_are_redacted() {
+        assert_eq!(line(b"a LOGIN alice s3cret\r\n"), "a LOGIN \"alice\" <redacted>");
+        assert_eq!(line(b"a LOGIN alice {6+}\r\ns3cret\r\n"), "a LOGIN \"alice\" <redacted>");
+        // "\0alice\0s3cret" en base64
+        assert_eq!(line(b"b AUTHENTICATE PLAIN AGFsaWNlAHMzY3JldA==\r\n"), "b AUTHENTICATE PLAIN <redacted>");
+        assert_eq!(line(b"c AUTHENTICATE PLAIN\r\n"), "c AUTHENTICATE PLAIN");
+        assert_eq!(line(b"d NOOP\r\n"), "d NOOP");
+    }
+}
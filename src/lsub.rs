@@ -1,11 +1,75 @@
 use imap_codec::imap_types::{
-    core::Tag,
+    core::{QuotedChar, Tag},
+    flag::FlagNameAttribute::Noinferiors,
     mailbox::{ListMailbox, Mailbox},
-    response::{Response, Status},
+    response::{Data, Response, Status},
 };
+use std::collections::HashMap;
+use crate::api::MailboxId;
+use crate::mailbox;
 
-pub fn handle<'a>(tag: Tag<'a>, _reference: Mailbox, _mailbox_wildcard: ListMailbox) -> Vec<Response<'a>> {
-    vec!(Response::Status(
+pub fn handle<'a, F: Fn(&MailboxId) -> bool>(tag: Tag<'a>, folders: &'a HashMap<String, MailboxId>, reference: Mailbox, mailbox_wildcard: ListMailbox, is_subscribed: F) -> Vec<Response<'a>> {
+    let mailbox_wildcard = match mailbox_wildcard {
+        ListMailbox::String(ref name) => name.as_ref(),
+        ListMailbox::Token(ref name) => name.as_ref(),
+    };
+
+    let mut responses: Vec<Response> = mailbox::matching(folders, &reference, mailbox_wildcard)
+        .into_iter()
+        .filter(|(_, mailbox_id)| is_subscribed(mailbox_id))
+        .map(|(folder, _)| {
+            Response::Data(Data::Lsub {
+                items: vec![Noinferiors],
+                delimiter: Some(QuotedChar::try_from(mailbox::DELIMITER).unwrap()),
+                mailbox: <Mailbox as TryFrom<&str>>::try_from(folder).unwrap(),
+            })
+        })
+        .collect();
+
+    responses.push(Response::Status(
         Status::ok(Some(tag), None, "LSUB completed").unwrap(),
-    ))
+    ));
+    responses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use imap_codec::{encode::Encoder, ResponseCodec};
+
+    fn lines(responses: Vec<Response>) -> Vec<String> {
+        responses
+            .iter()
+            .map(|response| String::from_utf8(ResponseCodec::default().encode(response).dump()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn only_subscribed_folders() {
+        let folders = mailbox::make_folders(vec![(String::from("Devoirs"), 3), (String::from("Sorties"), 4)]);
+        let unsubscribed = [MailboxId::Sent, MailboxId::Received(4)];
+        let lsub = |wildcard: &'static str| {
+            let responses = handle(
+                Tag::try_from("a").unwrap(),
+                &folders,
+                Mailbox::try_from("").unwrap(),
+                ListMailbox::try_from(wildcard).unwrap(),
+                |mailbox_id| !unsubscribed.contains(mailbox_id),
+            );
+            lines(responses)
+        };
+
+        assert_eq!(
+            lsub("*"),
+            [
+                "* LSUB (\\Noinferiors) \"/\" INBOX\r\n",
+                "* LSUB (\\Noinferiors) \"/\" Archive\r\n",
+                "* LSUB (\\Noinferiors) \"/\" Corbeille\r\n",
+                "* LSUB (\\Noinferiors) \"/\" Devoirs\r\n",
+                "* LSUB (\\Noinferiors) \"/\" Drafts\r\n",
+                "a OK LSUB completed\r\n",
+            ]
+        );
+        assert_eq!(lsub("S%"), ["a OK LSUB completed\r\n"]);
+    }
 }
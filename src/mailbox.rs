@@ -1,5 +1,5 @@
-use chrono::{Datelike, Local};
 use imap_codec::imap_types::{
+    core::QuotedChar,
     flag::{Flag, FlagPerm},
     mailbox::Mailbox,
     response::{Code, Data, Response, Status},
@@ -7,84 +7,191 @@ use imap_codec::imap_types::{
 use utf7_imap::encode_utf7_imap;
 use serde_json::Value;
 use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::str;
 use crate::api::MailboxId;
 
-pub fn make_folders(folders: Vec<(String, u32)>) -> HashMap<String, MailboxId> {
-    let mut map: HashMap<_, _> = folders.into_iter().map(|(name, id)| (encode_utf7_imap(name), MailboxId::Received(id))).collect();
-    map.insert("INBOX".into(), MailboxId::Received(0));
-    map.insert("Sent".into(), MailboxId::Sent);
-    map.insert("Trash".into(), MailboxId::Archived);
-    map.insert("Drafts".into(), MailboxId::Draft);
+pub fn name<'a>(mailbox: &'a Mailbox<'_>) -> &'a str {
+    match mailbox {
+        Mailbox::Inbox => "INBOX",
+        Mailbox::Other(mailbox) => str::from_utf8(mailbox.as_ref()).unwrap(),
+    }
+}
+
+const SPECIAL_FOLDERS: [(&str, MailboxId); 5] = [
+    ("INBOX", MailboxId::Received(0)),
+    ("Sent", MailboxId::Sent),
+    ("Archive", MailboxId::Archived),
+    ("Corbeille", MailboxId::Deleted),
+    ("Drafts", MailboxId::Draft),
+];
+
+pub const DELIMITER: char = '/';
+
+// Caractère d'échappement : ~XX avec XX le code hexadécimal du caractère
+const ESCAPE: char = '~';
+
+// Les caractères de contrôle, le délimiteur et les espaces au début et à la
+// fin du nom sont échappés. Les dossiers sont retrouvés par leur nom échappé
+// (voir `make_folders`), jamais par le libellé EcoleDirecte.
+fn escape(name: &str) -> String {
+    let last = name.chars().count().saturating_sub(1);
+    name.chars()
+        .enumerate()
+        .map(|(i, c)| {
+            if c.is_control() || c == DELIMITER || c == ESCAPE || (c == ' ' && (i == 0 || i == last)) {
+                // Tous les caractères échappés ont un code < 0x100
+                format!("{ESCAPE}{:02X}", c as u32)
+            } else {
+                c.to_string()
+            }
+        })
+        .collect()
+}
+
+// Deux classeurs peuvent avoir le même libellé, ou s'appeler comme un dossier
+// spécial. Dans ce cas on ajoute l'id du classeur au nom : le classeur le plus
+// ancien (id le plus petit) garde son nom, pour que les noms restent les mêmes
+// d'une session à l'autre.
+pub fn make_folders(mut folders: Vec<(String, u32)>) -> HashMap<String, MailboxId> {
+    let mut map: HashMap<String, MailboxId> = SPECIAL_FOLDERS
+        .iter()
+        .map(|(name, mailbox_id)| (name.to_string(), *mailbox_id))
+        .collect();
+
+    folders.sort_by_key(|(_, id)| *id);
+    for (name, id) in folders {
+        let name = escape(&name);
+        let mut candidate = if name.is_empty() { format!("({id})") } else { name.clone() };
+        while candidate.eq_ignore_ascii_case("INBOX") || map.contains_key(&encode_utf7_imap(candidate.clone())) {
+            candidate = format!("{candidate} ({id})");
+        }
+        map.insert(encode_utf7_imap(candidate), MailboxId::Received(id));
+    }
     map
 }
 
+// `*` correspond à n'importe quoi, `%` à n'importe quoi sauf le délimiteur
+fn matches_wildcard(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|i| matches_wildcard(rest, &name[i..])),
+        Some((b'%', rest)) => (0..=name.len())
+            .take_while(|&i| i == 0 || name[i - 1] != DELIMITER as u8)
+            .any(|i| matches_wildcard(rest, &name[i..])),
+        Some((c, rest)) => name.first() == Some(c) && matches_wildcard(rest, &name[1..]),
+    }
+}
+
+/// Dossiers qui correspondent au motif de LIST/LSUB, INBOX en premier puis
+/// par ordre alphabétique.
+pub fn matching<'a>(
+    folders: &'a HashMap<String, MailboxId>,
+    reference: &Mailbox<'_>,
+    mailbox_wildcard: &[u8],
+) -> Vec<(&'a String, &'a MailboxId)> {
+    let pattern = [name(reference).as_bytes(), mailbox_wildcard].concat();
+    let mut folders: Vec<_> = folders
+        .iter()
+        .filter(|(folder, _)| {
+            matches_wildcard(&pattern, folder.as_bytes())
+                // INBOX n'est pas sensible à la casse
+                || (*folder == "INBOX" && matches_wildcard(&pattern.to_ascii_uppercase(), folder.as_bytes()))
+        })
+        .collect();
+    folders.sort_by_key(|(folder, _)| (*folder != "INBOX", *folder));
+    folders
+}
+
 pub fn filter<'a>(
     folders: &'a HashMap<String, MailboxId>,
     reference: Mailbox<'_>,
     mailbox_wildcard: &[u8],
 ) -> Vec<Response<'a>> {
     use imap_codec::imap_types::flag::FlagNameAttribute::Noinferiors;
-    folders // TODO!!!
-        .keys()
-        .map(|folder| {
+    matching(folders, &reference, mailbox_wildcard)
+        .into_iter()
+        .map(|(folder, _)| {
             Response::Data(Data::List {
                 items: vec![Noinferiors],
-                delimiter: None,
+                delimiter: Some(QuotedChar::try_from(DELIMITER).unwrap()),
                 mailbox: <Mailbox as TryFrom<&str>>::try_from(folder).unwrap(),
             })
         })
         .collect()
 }
 
-pub fn mailbox_info<'a>(mailbox_id: &MailboxId, folder: Value) -> Vec<Response<'a>> {
-    let (existing_messages_count, unseen_messages_count) = match mailbox_id {
-        MailboxId::Received(_) => (&folder["pagination"]["messagesRecusCount"], folder["pagination"]["messagesRecusNotReadCount"].as_u64()),
-        MailboxId::Sent => (&folder["pagination"]["messagesEnvoyesCount"], None),
-        MailboxId::Draft => (&folder["pagination"]["messagesDraftCount"], None),
-        MailboxId::Archived => (&folder["pagination"]["messagesArchivesCount"], None),
-    };
-    let existing_messages_count = existing_messages_count.as_u64().unwrap() as u32;
-
-    let date = Local::now().date_naive();
-    // unwrap: Normalement on est après l'an 0
-    let school_year: u32 = match date.month() {
-        1..=8 => date.year() - 1,
-        9..=12 => date.year(),
-        _ => panic!("Month must be in the 1..=12 range"),
-    }
-    .try_into()
-    .unwrap();
+/// Réponses de SELECT pour `messages`, la liste qui fixe les numéros de
+/// séquence de la session. `keywords` sont les mots-clés déjà utilisés.
+pub fn mailbox_info<'a>(messages: &[(u32, Value)], keywords: Vec<Flag<'static>>, uid_validity: NonZeroU32, uid_next: NonZeroU32) -> Vec<Response<'a>> {
+    let existing_messages_count = messages.len() as u32;
+    // Numéro de séquence du premier message non lu
+    let first_unseen = messages.iter().position(|(_, message)| !message["read"].as_bool().unwrap());
 
     let mut response = vec![
-        Response::Data(Data::Flags(vec![Flag::Seen, Flag::Answered, Flag::Draft])),
+        Response::Data(Data::Flags([Flag::Seen, Flag::Answered, Flag::Draft, Flag::Deleted, Flag::Flagged].into_iter().chain(keywords).collect())),
         Response::Data(Data::Exists(existing_messages_count)),
         Response::Data(Data::Recent(0)),
         Response::Status(
             Status::ok(
                 None,
-                Some(Code::PermanentFlags(vec![FlagPerm::Flag(Flag::Seen)])),
+                // \Flagged et les mots-clés sont gardés localement (voir `state`)
+                Some(Code::PermanentFlags(vec![
+                    FlagPerm::Flag(Flag::Seen),
+                    FlagPerm::Flag(Flag::Deleted),
+                    FlagPerm::Flag(Flag::Flagged),
+                    FlagPerm::Asterisk,
+                ])),
                 "Flags",
             )
             .unwrap(),
         ),
         Response::Status(
-            Status::ok(
-                None,
-                Some(Code::UidValidity(school_year.try_into().unwrap())),
-                format!("Valide en {}-{}", school_year, school_year + 1),
-            )
-            .unwrap(),
+            Status::ok(None, Some(Code::UidValidity(uid_validity)), "UIDs valid").unwrap(),
+        ),
+        Response::Status(
+            Status::ok(None, Some(Code::UidNext(uid_next)), "Predicted next UID").unwrap(),
         ),
     ];
 
-    if let Some(count) = unseen_messages_count {
-        let count = count as u32;
-        if let Ok(count) = count.try_into() {
-            response.push(Response::Status(
-                Status::ok(None, Some(Code::Unseen(count)), "Unseen").unwrap(),
-            ));
-        }
+    if let Some(pos) = first_unseen {
+        response.push(Response::Status(
+            Status::ok(None, Some(Code::Unseen(NonZeroU32::new(pos as u32 + 1).unwrap())), "First unseen").unwrap(),
+        ));
     }
 
     response
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaped_names() {
+        assert_eq!(escape("Devoirs"), "Devoirs");
+        assert_eq!(escape("Maths/Physique"), "Maths~2FPhysique");
+        assert_eq!(escape(" Sorties ~ "), "~20Sorties ~7E~20");
+        assert_eq!(escape("a\r\nb"), "a~0D~0Ab");
+    }
+
+    #[test]
+    fn folder_names_are_unique() {
+        let folders = make_folders(vec![
+            (String::from("Devoirs"), 8),
+            (String::from("Devoirs"), 3),
+            (String::from("inbox"), 4),
+            (String::from("Sent"), 5),
+            (String::new(), 6),
+            (String::from("Élèves"), 7),
+        ]);
+        assert_eq!(folders["Devoirs"], MailboxId::Received(3));
+        assert_eq!(folders["Devoirs (8)"], MailboxId::Received(8));
+        assert_eq!(folders["inbox (4)"], MailboxId::Received(4));
+        assert_eq!(folders["Sent"], MailboxId::Sent);
+        assert_eq!(folders["Sent (5)"], MailboxId::Received(5));
+        assert_eq!(folders["(6)"], MailboxId::Received(6));
+        assert_eq!(folders["&AMk-l&AOg-ves"], MailboxId::Received(7));
+        assert_eq!(folders.len(), SPECIAL_FOLDERS.len() + 6);
+    }
+}
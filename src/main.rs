@@ -1,64 +1,326 @@
 use imap_codec::{
-    decode::{AuthenticateDataDecodeError, CommandDecodeError, Decoder},
+    decode::{AuthenticateDataDecodeError, CommandDecodeError, Decoder, IdleDoneDecodeError},
     encode::Encoder,
     imap_types::{
         self,
-        auth::AuthMechanism,
         bounded_static::IntoBoundedStatic,
-        command::Command,
-        core::Text,
+        command::{Command, CommandBody},
+        core::{LiteralMode, QuotedChar, Tag, Text},
+        fetch::MessageDataItem,
+        flag::{Flag, StoreResponse},
         mailbox::{ListMailbox, Mailbox},
+        search::SearchKey,
         response::{
-            Code, CommandContinuationRequest, Data, Greeting, GreetingKind, Response, Status,
+            Code, CodeOther, CommandContinuationRequest, Data, Greeting, GreetingKind, Response, Status,
         },
-        secret::Secret,
         state::State,
     },
-    AuthenticateDataCodec, CommandCodec, GreetingCodec, ResponseCodec,
+    AuthenticateDataCodec, CommandCodec, GreetingCodec, IdleDoneCodec, ResponseCodec,
 };
 use std::borrow::Cow;
-use std::collections::HashMap;
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroU32;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpListener;
 use std::ops::Range;
+use std::panic::{self, AssertUnwindSafe};
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::path::PathBuf;
+use std::process;
 use std::str;
-use std::thread;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use ecoledirecte_imap::account;
 use ecoledirecte_imap::api;
 use ecoledirecte_imap::auth;
-use ecoledirecte_imap::capabilities;
+use ecoledirecte_imap::cache;
+use ecoledirecte_imap::{capabilities, lock, Reply};
+use ecoledirecte_imap::command::{self, ExtendedCommand};
+use ecoledirecte_imap::condstore;
+use ecoledirecte_imap::config::{self, Config, LogFormat};
+use ecoledirecte_imap::connections;
+use ecoledirecte_imap::enable;
+use ecoledirecte_imap::expunge;
 use ecoledirecte_imap::fetch;
+use ecoledirecte_imap::id;
+use ecoledirecte_imap::idle;
+use ecoledirecte_imap::logging;
 use ecoledirecte_imap::lsub;
 use ecoledirecte_imap::mailbox;
+use ecoledirecte_imap::r#move;
+use ecoledirecte_imap::namespace;
+use ecoledirecte_imap::search;
+use ecoledirecte_imap::sort;
+use ecoledirecte_imap::state;
 use ecoledirecte_imap::status;
 use ecoledirecte_imap::store;
+use ecoledirecte_imap::thread;
+use ecoledirecte_imap::tls::{self, Encryption, Stream};
+use imap_types::sequence::SequenceSet;
+use clap::Parser;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use rustls::ServerConfig;
+use tracing::{debug, error, field, info, info_span, warn, Span};
 use api::MailboxId;
 
+const BUFFER_SIZE: usize = 1024;
+
+// Taille maximale d'un littéral (comme LITERAL- pour les non synchronisants)
+const MAX_LITERAL_SIZE: u32 = 4096;
+
+// Pendant IDLE, intervalle entre deux regards sur les changements faits par
+// les autres connexions du compte (EcoleDirecte n'est interrogé que toutes
+// les `poll-interval`)
+const IDLE_TICK: Duration = Duration::from_secs(1);
+
 struct Connection<'a> {
     state: State<'a>,
-    user: Option<auth::User>,
-    folders: Option<HashMap<String, MailboxId>>,
+    // Session EcoleDirecte, partagée avec les autres connexions du compte
+    session: Option<Arc<account::Session>>,
+    // Ce que le client sait du dossier sélectionné
+    view: idle::View,
+    // Dernières données connues des messages de la vue (ceux qui ont disparu
+    // d'EcoleDirecte restent adressables jusqu'à leur EXPUNGE)
+    messages: HashMap<u32, serde_json::Value>,
+    // UIDs du dernier résultat de recherche sauvegardé (`$`)
+    search_result: Vec<u32>,
+    // Tag de la commande IDLE en cours
+    idling: Option<Tag<'static>>,
+    // Commande AUTHENTICATE qui attend une réponse du client
+    authenticating: Option<(Tag<'static>, Box<dyn auth::Mechanism>)>,
+    encryption: Encryption,
+    // STARTTLS accepté : la négociation commence après la réponse
+    starting_tls: bool,
+    extensions: enable::Extensions,
+    // Inscription dans le registre des connexions, retirée à la fin
+    registration: Option<connections::Registration>,
 }
 
 impl<'a> Default for Connection<'a> {
     fn default() -> Connection<'a> {
         Connection {
             state: State::Greeting,
-            user: None,
-            folders: None,
+            session: None,
+            view: Vec::new(),
+            messages: HashMap::new(),
+            search_result: Vec::new(),
+            idling: None,
+            authenticating: None,
+            encryption: Encryption::Unavailable,
+            starting_tls: false,
+            extensions: enable::Extensions::default(),
+            registration: None,
         }
     }
 }
 
+/// Serveur IMAP pour la messagerie EcoleDirecte.
+///
+/// Les options passent par-dessus celles du fichier de configuration.
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Fichier de configuration TOML (par défaut
+    /// `$XDG_CONFIG_HOME/ecoledirecte-imap/config.toml` s'il existe)
+    #[arg(short, long, value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Adresse d'écoute en clair, plusieurs fois si besoin
+    #[arg(long, value_name = "ADDRESS")]
+    listen: Vec<String>,
+    /// Adresse d'écoute en IMAPS, plusieurs fois si besoin
+    #[arg(long, value_name = "ADDRESS", env = "ECOLEDIRECTE_IMAP_TLS_LISTEN", value_delimiter = ',')]
+    tls_listen: Vec<String>,
+    /// Certificat TLS (PEM)
+    #[arg(long, value_name = "FILE", env = "ECOLEDIRECTE_IMAP_TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// Clé du certificat TLS (PEM)
+    #[arg(long, value_name = "FILE", env = "ECOLEDIRECTE_IMAP_TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// Secondes entre deux interrogations d'EcoleDirecte pendant IDLE
+    #[arg(long, value_name = "SECONDS", env = "ECOLEDIRECTE_IMAP_POLL_INTERVAL")]
+    poll_interval: Option<u64>,
+    /// Taille maximale d'une commande en octets
+    #[arg(long, value_name = "BYTES", env = "ECOLEDIRECTE_IMAP_MAX_COMMAND_SIZE")]
+    max_command_size: Option<usize>,
+    /// Dossier où garder les UIDs attribués
+    #[arg(long, value_name = "DIR")]
+    state_dir: Option<PathBuf>,
+    /// Dossier où garder le contenu des messages téléchargés
+    #[arg(long, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+    /// Adresse de l'API EcoleDirecte
+    #[arg(long, value_name = "URL")]
+    api_url: Option<String>,
+    /// Proxy pour joindre EcoleDirecte
+    #[arg(long, value_name = "URL")]
+    proxy: Option<String>,
+    /// Niveau des journaux, ou filtre ("info", "ecoledirecte_imap=debug"...)
+    #[arg(long, value_name = "FILTER", env = "RUST_LOG")]
+    log_level: Option<String>,
+    #[arg(long, value_name = "FORMAT")]
+    log_format: Option<LogFormat>,
+    /// "stderr", "stdout" ou un fichier
+    #[arg(long, value_name = "OUTPUT")]
+    log_output: Option<String>,
+    /// Trace les commandes et les réponses IMAP (sans les mots de passe)
+    #[arg(long)]
+    protocol_log: bool,
+    /// Vérifie la configuration et s'arrête
+    #[arg(long)]
+    check: bool,
+}
+
+// Configuration du fichier, puis de la ligne de commande
+fn configure(cli: Cli) -> Result<Config, String> {
+    let mut config = match (cli.config, config::default_path()) {
+        (Some(path), _) => Config::load(&path)?,
+        (None, Some(path)) if path.exists() => Config::load(&path)?,
+        _ => Config::default(),
+    };
+    if !cli.listen.is_empty() {
+        config.listen = cli.listen;
+    }
+    if let (Some(cert), Some(key)) = (cli.tls_cert, cli.tls_key) {
+        let listen = config.tls.take().map(|tls| tls.listen).unwrap_or_else(config::default_tls_listen);
+        config.tls = Some(config::Tls { cert, key, listen });
+    }
+    if !cli.tls_listen.is_empty() {
+        match &mut config.tls {
+            Some(tls) => tls.listen = cli.tls_listen,
+            None => return Err(String::from("--tls-listen needs a TLS certificate and key")),
+        }
+    }
+    config.poll_interval = cli.poll_interval.unwrap_or(config.poll_interval);
+    config.max_command_size = cli.max_command_size.unwrap_or(config.max_command_size);
+    config.state_dir = cli.state_dir.unwrap_or(config.state_dir);
+    config.cache_dir = cli.cache_dir.or(config.cache_dir);
+    config.api.url = cli.api_url.unwrap_or(config.api.url);
+    config.api.proxy = cli.proxy.or(config.api.proxy);
+    config.log.level = cli.log_level.unwrap_or(config.log.level);
+    config.log.format = cli.log_format.unwrap_or(config.log.format);
+    config.log.output = cli.log_output.unwrap_or(config.log.output);
+    config.log.protocol |= cli.protocol_log;
+    config.validate(BUFFER_SIZE)?;
+    Ok(config)
+}
+
+// Ce qui est commun à toutes les connexions
+struct Server {
+    config: Config,
+    client: api::Client,
+    tls_config: Option<Arc<ServerConfig>>,
+}
+
+fn start(config: Config) -> Result<(Server, Vec<(TcpListener, bool)>), String> {
+    let client = api::Client::new(&config.api)?;
+    // Sans certificat, pas de TLS (ni IMAPS ni STARTTLS)
+    let tls_config = match &config.tls {
+        Some(tls) => Some(tls::load_config(&tls.cert, &tls.key)?),
+        None => None,
+    };
+
+    let mut listeners = Vec::new();
+    let tls_listen = config.tls.as_ref().map(|tls| tls.listen.as_slice()).unwrap_or_default();
+    for (addresses, implicit_tls) in [(config.listen.as_slice(), false), (tls_listen, true)] {
+        for address in config::resolve_addresses(addresses)? {
+            let listener = TcpListener::bind(address).map_err(|error| format!("cannot listen on {address}: {error}"))?;
+            listeners.push((listener, implicit_tls));
+        }
+    }
+    Ok((Server { config, client, tls_config }, listeners))
+}
+
 fn main() {
-    let listener = TcpListener::bind("localhost:1993").unwrap();
-    let client = reqwest::blocking::Client::new();
+    let cli = Cli::parse();
+    let check = cli.check;
+    let started = configure(cli).and_then(|config| {
+        // Pas de journaux (et pas de fichier créé) pour une simple vérification
+        if !check {
+            logging::init(&config.log)?;
+        }
+        start(config)
+    });
+    let (server, listeners) = match started {
+        Ok(started) => started,
+        Err(error) => {
+            eprintln!("ecoledirecte-imap: {error}");
+            process::exit(1);
+        }
+    };
+    if check {
+        println!("Configuration OK");
+        return;
+    }
+    for (listener, implicit_tls) in &listeners {
+        if let Ok(address) = listener.local_addr() {
+            info!(%address, tls = implicit_tls, "listening");
+        }
+    }
+    let encryption = if server.tls_config.is_some() { Encryption::Available } else { Encryption::Unavailable };
 
-    thread::scope(|s| {
-        for stream in listener.incoming() {
-            let stream = stream.unwrap();
+    // Une panique ne termine que sa connexion (voir `responder`), mais on
+    // veut savoir où elle s'est produite
+    panic::set_hook(Box::new(|info| {
+        let backtrace = Backtrace::capture();
+        if backtrace.status() == BacktraceStatus::Captured {
+            error!(%backtrace, "internal error: {info}");
+        } else {
+            error!("internal error: {info}");
+        }
+    }));
+    let mut signals = match Signals::new([SIGTERM, SIGINT]) {
+        Ok(signals) => signals,
+        Err(error) => {
+            error!(%error, "cannot handle signals");
+            process::exit(1);
+        }
+    };
 
-            s.spawn(|| responder(stream, Connection::default(), &client));
+    let server = &server;
+    std::thread::scope(|s| {
+        s.spawn(move || {
+            if signals.forever().next().is_some() {
+                info!("shutting down");
+                connections::shut_down();
+                let remaining = connections::wait(server.config.shutdown_timeout());
+                if remaining > 0 {
+                    warn!(remaining, "sessions still running, exiting anyway");
+                }
+                state::flush_all();
+                process::exit(0);
+            }
+        });
+        for (listener, implicit_tls) in listeners {
+            s.spawn(move || {
+                for stream in listener.incoming() {
+                    let Ok(stream) = stream else {
+                        continue;
+                    };
+                    let registration = connections::register(&stream);
+                    let peer = stream.peer_addr().map(|address| address.to_string()).unwrap_or_default();
+                    let span = info_span!("connection", %peer, user = field::Empty, mailbox = field::Empty);
+                    let registration = Some(registration);
+                    let (stream, connection) = if implicit_tls {
+                        // unwrap: on n'écoute en IMAPS que si TLS est configuré
+                        // La négociation TLS ne se fait qu'à la première lecture : ici
+                        // seule la création de la connexion rustls peut échouer
+                        let stream = match Stream::accept(stream, server.tls_config.as_ref().unwrap()) {
+                            Ok(stream) => stream,
+                            Err(error) => {
+                                span.in_scope(|| warn!(%error, "could not set up the TLS connection"));
+                                continue;
+                            }
+                        };
+                        (stream, Connection { encryption: Encryption::Active, registration, ..Connection::default() })
+                    } else {
+                        (Stream::Plain(stream), Connection { encryption, registration, ..Connection::default() })
+                    };
+                    s.spawn(move || span.in_scope(|| responder(stream, connection, server)));
+                }
+            });
         }
     });
 }
@@ -81,88 +343,662 @@ impl<T> AsRange for [T] {
     }
 }
 
+// Messages d'un dossier avec leurs UIDs, par UID croissant, demandés à
+// EcoleDirecte (ou la dernière liste du compte si elle a moins de `max_age`)
+fn get_messages(
+    client: &api::Client,
+    session: &account::Session,
+    mailbox_id: &MailboxId,
+    max_age: Option<Duration>,
+) -> Result<Vec<(u32, serde_json::Value)>, api::Error> {
+    session.messages(mailbox_id, max_age, || {
+        let messages = session.call(client, |token| api::get_messages(client, session.id, token, mailbox_id))?;
+        Ok(lock(&session.state).assign(mailbox_id, messages))
+    })
+}
+
+// Messages du dossier sélectionné tels que le client les connaît, dans
+// l'ordre de ses numéros de séquence. Les nouveaux n'y sont qu'une fois
+// annoncés par EXISTS (voir `updates`), pour que les numéros ne bougent pas
+// entre deux commandes.
+fn known_messages(
+    server: &Server,
+    session: &account::Session,
+    mailbox_id: &MailboxId,
+    view: &idle::View,
+    messages: &mut HashMap<u32, serde_json::Value>,
+) -> Result<Vec<(u32, serde_json::Value)>, api::Error> {
+    let known: HashSet<u32> = view.iter().map(|(uid, _)| *uid).collect();
+    let fresh = get_messages(&server.client, session, mailbox_id, Some(server.config.poll_interval()))?;
+    messages.extend(fresh.into_iter().filter(|(uid, _)| known.contains(uid)));
+    messages.retain(|uid, _| known.contains(uid));
+    Ok(view.iter()
+        .filter_map(|(uid, _)| Some((*uid, messages.get(uid)?.clone())))
+        .collect())
+}
+
+// Drapeaux qu'on garde de notre côté pour les messages d'un dossier :
+// \Deleted jusqu'à EXPUNGE, \Flagged et les mots-clés dans l'état du compte
+fn local_flags<'s>(
+    session: &'s account::Session,
+    mailbox_id: &MailboxId,
+    messages: &[(u32, serde_json::Value)],
+) -> impl Fn(u32) -> Vec<Flag<'static>> + 's {
+    let mailbox_id = *mailbox_id;
+    let message_ids: HashMap<u32, u32> = messages
+        .iter()
+        .map(|(uid, message)| (*uid, message["id"].as_u64().unwrap() as u32))
+        .collect();
+    move |uid| {
+        let mut flags = match message_ids.get(&uid) {
+            Some(message_id) => lock(&session.state).keywords(*message_id),
+            None => vec![],
+        };
+        if session.is_deleted(&mailbox_id, uid) {
+            flags.push(Flag::Deleted);
+        }
+        flags
+    }
+}
+
+// Dossiers du compte, demandés à EcoleDirecte la première fois ou si `refresh`
+fn get_folders(session: &account::Session, client: &api::Client, refresh: bool) -> Result<HashMap<String, MailboxId>, api::Error> {
+    session.folders(refresh, || Ok(mailbox::make_folders(session.call(client, |token| api::get_folders(client, session.id, token))?)))
+}
+
+// Remet non lus les messages qu'une recherche a dû ouvrir
+fn restore_unread(client: &api::Client, session: &account::Session, mailbox_id: &MailboxId, opened: &search::Opened) -> Result<(), api::Error> {
+    opened.restore(|message_ids| {
+        session
+            .call(client, |token| api::set_read_status(client, session.id, token, mailbox_id, false, message_ids))
+            .map_err(|mut failures| failures.remove(0).1)
+    })
+}
+
+// Dossier sélectionné, s'il existe encore (un classeur peut être supprimé
+// depuis SELECT)
+fn selected(session: &account::Session, mailbox: &Mailbox) -> Option<MailboxId> {
+    session.mailbox_id(mailbox::name(mailbox))
+}
+
+fn nonexistent(tag: Tag<'_>) -> Response<'_> {
+    Response::Status(
+        Status::no(
+            Some(tag),
+            Some(Code::Other(CodeOther::unvalidated(&b"NONEXISTENT"[..]))),
+            "Selected mailbox no longer exists",
+        )
+        .unwrap(),
+    )
+}
+
+// Réponse à une commande qui n'a pas pu aboutir faute de réponse d'EcoleDirecte
+fn failed(tag: Tag<'_>, error: api::Error) -> Response<'_> {
+    warn!(%error, "EcoleDirecte request failed");
+    Response::Status(Status::no(Some(tag), None, format!("EcoleDirecte request failed: {error}")).unwrap())
+}
+
+// Premier échec des requêtes faites pendant une commande. Les fonctions qui
+// traitent les commandes attendent des contenus : elles reçoivent un contenu
+// vide, et la commande répond NO à la place de ce qu'elles ont renvoyé.
+#[derive(Default)]
+struct Failure(RefCell<Option<api::Error>>);
+
+impl Failure {
+    fn record<T: Default>(&self, result: Result<T, api::Error>) -> T {
+        result.unwrap_or_else(|error| {
+            self.0.borrow_mut().get_or_insert(error);
+            T::default()
+        })
+    }
+
+    fn failed(&self) -> bool {
+        self.0.borrow().is_some()
+    }
+
+    fn check<'a, R: From<Response<'a>>>(self, tag: Tag<'a>, replies: Vec<R>) -> Vec<R> {
+        match self.0.into_inner() {
+            Some(error) => vec![failed(tag, error).into()],
+            None => replies,
+        }
+    }
+}
+
+fn send(stream: &mut Stream, response: &Response) -> io::Result<()> {
+    let data = ResponseCodec::default().encode(response).dump();
+    logging::protocol("S", &data);
+    stream.write_all(&data)?;
+    stream.flush()
+}
+
+fn send_reply(stream: &mut Stream, reply: &Reply) -> io::Result<()> {
+    match reply {
+        Reply::Response(response) => send(stream, response),
+        Reply::Untagged(line) => {
+            let data = format!("* {line}\r\n");
+            logging::protocol("S", data.as_bytes());
+            stream.write_all(data.as_bytes())?;
+            stream.flush()
+        }
+    }
+}
+
 fn responder(
-    mut stream: TcpStream,
+    mut stream: Stream,
     mut connection: Connection<'_>,
-    client: &reqwest::blocking::Client,
+    server: &Server,
 ) {
-    let mut buffer = [0u8; 1024];
-    let mut cursor = 0;
-
-    stream
-        .write(
-            &GreetingCodec::default()
-                .encode(&Greeting {
-                    kind: GreetingKind::Ok,
-                    code: Some(Code::Capability(capabilities())),
-                    text: Text::try_from("ecoledirecte-imap ready").unwrap(),
-                })
-                .dump(),
-        )
-        .unwrap();
+    // Pendant l'arrêt du serveur ou s'il y a trop de connexions, on refuse
+    // les nouvelles (le compte inclut celle-ci)
+    let refused = connections::shutting_down() || connections::count() > server.config.max_connections;
+    let greeting = if connections::shutting_down() {
+        Greeting::bye(None, "Server shutting down").unwrap()
+    } else if refused {
+        warn!("too many connections");
+        Greeting::bye(Some(Code::Other(CodeOther::unvalidated(&b"UNAVAILABLE"[..]))), "Too many connections").unwrap()
+    } else {
+        Greeting {
+            kind: GreetingKind::Ok,
+            code: Some(Code::Capability(capabilities(connection.encryption))),
+            text: Text::try_from("ecoledirecte-imap ready").unwrap(),
+        }
+    };
+    let greeting = GreetingCodec::default().encode(&greeting).dump();
+    // Avec TLS, c'est là que la négociation peut échouer (ou traîner)
+    let _ = stream.set_read_timeout(Some(server.config.login_timeout()));
+    if stream.write_all(&greeting).and_then(|_| stream.flush()).is_err() || refused {
+        stream.close();
+        return;
+    }
 
     connection.state = State::NotAuthenticated;
+    info!("connected");
+
+    // Une erreur interne ou une erreur réseau ne termine que cette session
+    match panic::catch_unwind(AssertUnwindSafe(|| session(&mut stream, &mut connection, server))) {
+        Ok(Ok(())) => {
+            if connections::shutting_down() && !matches!(connection.state, State::Logout) {
+                let _ = send(&mut stream, &Response::Status(Status::bye(None, "Server shutting down").unwrap()));
+            }
+        }
+        Ok(Err(error)) => debug!(%error, "connection lost"),
+        // La panique est déjà journalisée (voir `main`)
+        Err(_) => {
+            let _ = send(&mut stream, &Response::Status(Status::bye(None, "Internal server error").unwrap()));
+        }
+    }
+
+    stream.close();
+    info!("disconnected");
+}
+
+fn session(stream: &mut Stream, connection: &mut Connection<'_>, server: &Server) -> io::Result<()> {
+    let max_command_size = server.config.max_command_size;
+    let login_deadline = Instant::now() + server.config.login_timeout();
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let mut cursor = 0;
+    // Reste d'une commande refusée à jeter : nombre d'octets de littéral puis
+    // jusqu'à la fin de la ligne
+    let mut skipping: Option<usize> = None;
 
     loop {
-        match CommandCodec::default().decode(&buffer[..cursor]) {
-            Ok((remaining, command)) => {
-                print!(
-                    "C: {}",
-                    str::from_utf8(&CommandCodec::default().encode(&command).dump()).unwrap()
-                );
-                for response in process(command, &mut connection, &mut stream, client) {
-                    print!(
-                        "S: {}",
-                        str::from_utf8(&ResponseCodec::default().encode(&response).dump()).unwrap()
-                    );
-                    stream
-                        .write(&ResponseCodec::default().encode(&response).dump())
-                        .unwrap();
+        if let Some(tag) = connection.idling.take() {
+            match IdleDoneCodec::default().decode(&buffer[..cursor]) {
+                Ok((remaining, _)) => {
+                    logging::protocol("C", b"DONE");
+                    send(stream, &Response::Status(Status::ok(Some(tag), None, "IDLE terminated").unwrap()))?;
+                    stream.set_read_timeout(None)?;
+                    let range = remaining.as_range_of(&buffer).unwrap();
+                    cursor = range.len();
+                    buffer.copy_within(range, 0);
+                    continue;
                 }
+                Err(IdleDoneDecodeError::Incomplete) => {
+                    connection.idling = Some(tag);
+                }
+                Err(IdleDoneDecodeError::Failed) => {
+                    send(stream, &Response::Status(Status::bad(Some(tag), None, "Expected DONE").unwrap()))?;
+                    stream.set_read_timeout(None)?;
+                    skipping = Some(0);
+                    continue;
+                }
+            }
 
-                if let State::Logout = connection.state {
-                    break;
+            // On se réveille régulièrement pour aller voir s'il y a du nouveau
+            stream.set_read_timeout(Some(IDLE_TICK))?;
+            if !make_room(&mut buffer, cursor, max_command_size) {
+                let tag = connection.idling.take();
+                send(stream, &Response::Status(Status::bad(tag, Some(Code::TooBig), "Expected DONE").unwrap()))?;
+                stream.set_read_timeout(None)?;
+                skipping = Some(0);
+                cursor = 0;
+                continue;
+            }
+            match stream.read(&mut buffer[cursor..]) {
+                Ok(0) => break,
+                Ok(received) => cursor += received,
+                Err(error) if matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    let journal = journal(connection);
+                    match updates(connection, server) {
+                        Ok(responses) => {
+                            for reply in adapt(responses.into_iter().map(Reply::from).collect(), journal, false) {
+                                send_reply(stream, &reply)?;
+                            }
+                        }
+                        // On réessaiera au prochain réveil
+                        Err(error) => debug!(%error, "updates failed"),
+                    }
                 }
+                Err(_) => break,
+            }
+            continue;
+        }
 
-                let range = remaining.as_range_of(&buffer).unwrap();
-                cursor = range.len();
-                buffer.copy_within(range, 0);
+        let incomplete = if let Some(length) = skipping {
+            let (consumed, rest) = skip(&buffer[..cursor], length);
+            buffer.copy_within(consumed..cursor, 0);
+            cursor -= consumed;
+            skipping = rest;
+            rest.is_some()
+        } else if let Some((tag, mut mechanism)) = connection.authenticating.take() {
+            // Une ligne "*" annule l'échange (AuthenticateDataCodec ne la
+            // reconnaît pas)
+            if buffer[..cursor].starts_with(b"*\r\n") {
+                logging::protocol("C", b"*");
+                send(stream, &Response::Status(Status::bad(Some(tag), None, "Authentication cancelled").unwrap()))?;
+                buffer.copy_within(3..cursor, 0);
+                cursor -= 3;
+                false
+            } else if b"*\r\n".starts_with(&buffer[..cursor]) {
+                connection.authenticating = Some((tag, mechanism));
+                true
+            } else {
+                match AuthenticateDataCodec::default().decode(&buffer[..cursor]) {
+                    Ok((remaining, line)) => {
+                        logging::protocol("C", b"<authentication data>");
+                        let step = mechanism.step(Some(line.0.declassify()));
+                        for response in authentication_step(tag, mechanism, step, connection, server) {
+                            send(stream, &response)?;
+                        }
+                        let range = remaining.as_range_of(&buffer).unwrap();
+                        cursor = range.len();
+                        buffer.copy_within(range, 0);
+                        false
+                    }
+                    Err(AuthenticateDataDecodeError::Incomplete) => {
+                        connection.authenticating = Some((tag, mechanism));
+                        true
+                    }
+                    Err(AuthenticateDataDecodeError::Failed) => {
+                        send(
+                            stream,
+                            &Response::Status(Status::bad(Some(tag), None, "Invalid BASE64 literal").unwrap()),
+                        )?;
+                        skipping = Some(0);
+                        false
+                    }
+                }
             }
-            Err(CommandDecodeError::LiteralFound { tag, length, mode }) => {
-                todo!("LITERAL {:?} {} {:?}", tag, length, mode)
+        } else if let command::Extent::TooBig { line_end, length, mode } =
+            command::extent(&buffer[..cursor], MAX_LITERAL_SIZE)
+        {
+            // Refusé dès l'annonce : ses données peuvent être arrivées avec
+            // elle, et imap-codec ne voit alors qu'une commande incomplète
+            send(
+                stream,
+                &Response::Status(
+                    Status::bad(command::tag(&buffer[..cursor]), Some(Code::TooBig), "Literal too big").unwrap(),
+                ),
+            )?;
+            // Le client n'enverra pas un littéral synchronisant refusé, mais il
+            // envoie quand même un littéral non synchronisant
+            if mode == LiteralMode::NonSync {
+                skipping = Some(length as usize);
             }
-            Err(CommandDecodeError::Incomplete) => {
-                if cursor >= buffer.len() {
-                    todo!("OUT OF MEMORY!");
+            buffer.copy_within(line_end..cursor, 0);
+            cursor -= line_end;
+            false
+        } else {
+            match CommandCodec::default().decode(&buffer[..cursor]) {
+                Ok((remaining, command)) => {
+                    logging::command(&command);
+                    let journal = journal(connection);
+                    let silent_store = matches!(command.body, CommandBody::Store { response: StoreResponse::Silent, .. });
+                    let responses = process(command, connection, server);
+                    for reply in adapt(responses.into_iter().map(Reply::from).collect(), journal, silent_store) {
+                        send_reply(stream, &reply)?;
+                    }
+
+                    if let State::Logout = connection.state {
+                        break;
+                    }
+
+                    let range = remaining.as_range_of(&buffer).unwrap();
+                    cursor = range.len();
+                    buffer.copy_within(range, 0);
+                    false
                 }
-                let received = stream.read(&mut buffer[cursor..]).unwrap();
-                if received == 0 {
-                    break;
+                // imap-codec s'arrête juste après l'annonce {length} ou {length+},
+                // dont la taille a déjà été vérifiée
+                Err(CommandDecodeError::LiteralFound { mode, .. }) => {
+                    if mode == LiteralMode::Sync {
+                        send(
+                            stream,
+                            &Response::CommandContinuationRequest(
+                                CommandContinuationRequest::basic(None, "Ready for literal data").unwrap(),
+                            ),
+                        )?;
+                    }
+                    true
                 }
-                cursor += received;
-            }
-            Err(CommandDecodeError::Failed) => {
-                stream
-                    .write(
-                        &ResponseCodec::default()
-                            .encode(&Response::Status(
-                                Status::bad(None, None, "Parsing failed").unwrap(),
-                            ))
-                            .dump(),
-                    )
-                    .unwrap();
-                cursor = 0;
+                Err(CommandDecodeError::Incomplete) => true,
+                Err(CommandDecodeError::Failed) => {
+                    // Peut-être une commande d'extension qu'imap-codec ne connaît pas
+                    // (il échoue avant même d'avoir la commande entière)
+                    match command::extent(&buffer[..cursor], MAX_LITERAL_SIZE) {
+                        command::Extent::Line(_) => true,
+                        command::Extent::Complete(length) => {
+                            if let Some(command) = command::decode(&buffer[..length], |uid| saved_result(connection, uid)) {
+                                logging::protocol("C", &buffer[..length]);
+                                // UNCHANGEDSINCE active CONDSTORE, déjà pour les
+                                // réponses de ce STORE
+                                if matches!(command, ExtendedCommand::Store { .. }) {
+                                    connection.extensions.condstore = true;
+                                }
+                                let journal = journal(connection);
+                                let silent_store = matches!(command, ExtendedCommand::Store { response: StoreResponse::Silent, .. });
+                                for reply in adapt(process_extended(command, connection, server), journal, silent_store) {
+                                    send_reply(stream, &reply)?;
+                                }
+                                if let State::Logout = connection.state {
+                                    break;
+                                }
+                            } else {
+                                // On ne jette que cette commande, littéraux compris : les
+                                // suivantes sont traitées normalement
+                                send(
+                                    stream,
+                                    &Response::Status(
+                                        Status::bad(command::tag(&buffer[..length]), None, "Parsing failed").unwrap(),
+                                    ),
+                                )?;
+                            }
+                            buffer.copy_within(length..cursor, 0);
+                            cursor -= length;
+                            false
+                        }
+                        // imap-codec a échoué avant ce littéral : inutile de l'attendre.
+                        // Le client ne l'enverra pas s'il est synchronisant.
+                        command::Extent::Literal(missing, mode) => {
+                            send(
+                                stream,
+                                &Response::Status(
+                                    Status::bad(command::tag(&buffer[..cursor]), None, "Parsing failed").unwrap(),
+                                ),
+                            )?;
+                            if mode == LiteralMode::NonSync {
+                                skipping = Some(missing);
+                            }
+                            cursor = 0;
+                            false
+                        }
+                        // Déjà refusé avant le décodage
+                        command::Extent::TooBig { .. } => false,
+                    }
+                }
+            }
+        };
+
+        if connection.starting_tls {
+            connection.starting_tls = false;
+            // Ce que le client a envoyé en clair après STARTTLS est ignoré
+            // (sinon on pourrait injecter des commandes avant la négociation)
+            cursor = 0;
+            // unwrap: STARTTLS n'est accepté que si TLS est configuré
+            stream.start_tls(server.tls_config.as_ref().unwrap())?;
+            connection.encryption = Encryption::Active;
+            debug!("TLS started");
+            continue;
+        }
+
+        if incomplete {
+            if !make_room(&mut buffer, cursor, max_command_size) {
+                let tag = connection.authenticating.take().map(|(tag, _)| tag).or_else(|| command::tag(&buffer[..cursor]));
+                send(stream, &Response::Status(Status::bad(tag, Some(Code::TooBig), "Command too long").unwrap()))?;
+                // On jette la commande, littéraux compris, et on reprend à la
+                // suivante
+                match command::extent(&buffer[..cursor], MAX_LITERAL_SIZE) {
+                    command::Extent::Literal(missing, _) => {
+                        skipping = Some(missing);
+                        cursor = 0;
+                    }
+                    // La fin de la ligne en cours peut encore annoncer un littéral
+                    command::Extent::Line(start) => {
+                        skipping = Some(0);
+                        buffer.copy_within(start..cursor, 0);
+                        cursor -= start;
+                    }
+                    _ => {
+                        skipping = Some(0);
+                        cursor = 0;
+                    }
+                }
+                continue;
+            }
+            // Avant l'authentification, le client a `login-timeout` en tout ;
+            // ensuite, autologout après `autologout` d'inactivité (RFC 3501)
+            let (timeout, reason) = match connection.state {
+                State::NotAuthenticated => {
+                    (login_deadline.saturating_duration_since(Instant::now()), "Authentication timeout")
+                }
+                _ => (server.config.autologout(), "Autologout; idle for too long"),
+            };
+            let timed_out = if timeout.is_zero() {
+                true
+            } else {
+                stream.set_read_timeout(Some(timeout))?;
+                match stream.read(&mut buffer[cursor..]) {
+                    Ok(0) => break,
+                    Ok(received) => {
+                        cursor += received;
+                        false
+                    }
+                    Err(error) if matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => true,
+                    Err(_) => break,
+                }
+            };
+            if timed_out {
+                debug!(reason, "closing inactive session");
+                send(stream, &Response::Status(Status::bye(None, reason).unwrap()))?;
+                connection.state = State::Logout;
+                break;
             }
         }
     }
+
+    Ok(())
+}
+
+// Fait de la place pour lire la suite : le buffer grandit jusqu'à
+// `max_command_size` et reprend sa taille normale une fois la grosse commande
+// traitée. Renvoie false si la commande en cours est trop longue.
+fn make_room(buffer: &mut Vec<u8>, cursor: usize, max_command_size: usize) -> bool {
+    if cursor < BUFFER_SIZE && buffer.len() > BUFFER_SIZE {
+        buffer.truncate(BUFFER_SIZE);
+        buffer.shrink_to_fit();
+    }
+    if cursor < buffer.len() {
+        return true;
+    }
+    if buffer.len() >= max_command_size {
+        return false;
+    }
+    buffer.resize((buffer.len() * 2).min(max_command_size), 0);
+    true
+}
+
+// Saute `length` octets de littéral puis la fin de la ligne, et ainsi de suite
+// si elle annonce un littéral non synchronisant (le client n'envoie pas les
+// synchronisants d'une commande refusée). Renvoie le nombre d'octets consommés
+// et ce qu'il reste à sauter si la commande n'est pas finie.
+fn skip(data: &[u8], mut length: usize) -> (usize, Option<usize>) {
+    let mut start = 0;
+    loop {
+        let line = start + length;
+        if data.len() < line {
+            return (data.len(), Some(line - data.len()));
+        }
+        match data[line..].windows(2).position(|window| window == b"\r\n") {
+            Some(end) => {
+                let line_end = line + end + 2;
+                match command::literal(&data[line..line_end]) {
+                    Some((literal, LiteralMode::NonSync)) => {
+                        start = line_end;
+                        length = literal as usize;
+                    }
+                    _ => return (line_end, None),
+                }
+            }
+            None => {
+                // On garde la fin de la ligne, qui peut être le début de
+                // l'annonce d'un littéral ("{4294967295+}\r" au plus)
+                let kept = (data.len() - line).min(16);
+                return (data.len() - kept, Some(0));
+            }
+        }
+    }
+}
+
+// Suite de AUTHENTICATE : un nouveau défi pour le client, ou l'authentification
+// une fois qu'il a donné ses identifiants
+fn authentication_step(
+    tag: Tag<'static>,
+    mechanism: Box<dyn auth::Mechanism>,
+    step: auth::Step,
+    connection: &mut Connection<'_>,
+    server: &Server,
+) -> Vec<Response<'static>> {
+    match step {
+        auth::Step::Challenge(challenge) => {
+            // La suite est lue par `session`
+            connection.authenticating = Some((tag, mechanism));
+            vec![Response::CommandContinuationRequest(CommandContinuationRequest::Base64(Cow::Owned(challenge)))]
+        }
+        auth::Step::Done { username, password } => log_in(connection, tag, &username, password.declassify(), server),
+        auth::Step::Failed(message) => vec![Response::Status(Status::no(Some(tag), None, message).unwrap())],
+    }
+}
+
+// Le compte a déjà trop de connexions : on ferme celle-ci sans aller
+// jusqu'à EcoleDirecte. Sinon, sa place est réservée.
+fn account_full(connection: &mut Connection<'_>, username: &str, server: &Server) -> Option<Vec<Response<'static>>> {
+    let registration = connection.registration.as_ref()?;
+    if registration.reserve(username, server.config.max_connections_per_account) {
+        return None;
+    }
+    warn!("too many connections for this account");
+    connection.state = State::Logout;
+    Some(vec![Response::Status(
+        Status::bye(
+            Some(Code::Other(CodeOther::unvalidated(&b"UNAVAILABLE"[..]))),
+            "Too many connections for this account",
+        )
+        .unwrap(),
+    )])
+}
+
+// Authentification, avec la session des autres connexions du compte s'il y
+// en a une (voir `account`)
+fn log_in<'a>(
+    connection: &mut Connection<'_>,
+    tag: Tag<'a>,
+    username: &str,
+    password: &str,
+    server: &Server,
+) -> Vec<Response<'a>> {
+    if let Some(response) = account_full(connection, username, server) {
+        return response;
+    }
+    let session = account::log_in(&server.config.state_dir, username, password, || {
+        api::login(&server.client, username, password)
+    });
+    let (state, response) = auth::translate(
+        session.as_ref().map(|_| ()).map_err(|error| error.clone().into()),
+        tag,
+        connection.encryption,
+    );
+    match &session {
+        Ok(session) => {
+            Span::current().record("user", field::debug(session.id));
+            info!("logged in");
+        }
+        Err(error) => {
+            warn!(%error, "login failed");
+            if let Some(registration) = &connection.registration {
+                registration.release();
+            }
+        }
+    }
+    connection.state = state;
+    connection.session = session.ok();
+    response
+}
+
+// Ce qu'il faut pour adapter les réponses à CONDSTORE : le journal du dossier
+// sélectionné et ce que le client en sait avant la commande
+type Journal = (Arc<Mutex<state::AccountState>>, MailboxId, idle::View, bool);
+
+fn journal(connection: &Connection<'_>) -> Option<Journal> {
+    let State::Selected(mailbox) = &connection.state else {
+        return None;
+    };
+    if !connection.extensions.condstore {
+        return None;
+    }
+    let session = connection.session.as_ref()?;
+    let mailbox_id = session.mailbox_id(mailbox::name(mailbox))?;
+    Some((session.state.clone(), mailbox_id, connection.view.clone(), connection.extensions.qresync))
+}
+
+// `silent_store` : les réponses sont celles de STORE .SILENT
+fn adapt<'a>(replies: Vec<Reply<'a>>, journal: Option<Journal>, silent_store: bool) -> Vec<Reply<'a>> {
+    match journal {
+        Some((account, mailbox_id, view, qresync)) => {
+            condstore::adapt(replies, &view, |uid| lock(&account).modseq(&mailbox_id, uid), qresync, silent_store)
+        }
+        None => replies,
+    }
+}
+
+// Changements dans le dossier sélectionné depuis la dernière fois, faits
+// sur EcoleDirecte ou par les autres connexions du compte (pour NOOP et IDLE)
+fn updates(
+    connection: &mut Connection<'_>,
+    server: &Server,
+) -> Result<Vec<Response<'static>>, api::Error> {
+    let client = &server.client;
+    let State::Selected(mailbox) = &connection.state else {
+        return Ok(vec![]);
+    };
+    let session = connection.session.clone().unwrap();
+    // Les commandes suivantes répondront NO
+    let Some(mailbox_id) = &selected(&session, mailbox) else {
+        return Ok(vec![]);
+    };
+    let messages = get_messages(client, &session, mailbox_id, Some(server.config.poll_interval()))?;
+    let view = idle::view(&messages, local_flags(&session, mailbox_id, &messages));
+    let responses = idle::updates(&connection.view, &view);
+    connection.view = view;
+    connection.messages = messages.into_iter().collect();
+    Ok(responses)
 }
 
 fn process<'a>(
     command: Command<'a>,
     connection: &'a mut Connection<'_>,
-    stream: &mut TcpStream,
-    client: &reqwest::blocking::Client,
+    server: &Server,
 ) -> Vec<Response<'a>> {
+    let client = &server.client;
     use imap_types::{
         command::CommandBody::*,
         command::CommandBody::{Logout, Status as StatusCommand},
@@ -174,16 +1010,23 @@ fn process<'a>(
     match command.body {
         Capability => {
             return vec![
-                Response::Data(Data::Capability(capabilities())),
+                Response::Data(Data::Capability(capabilities(connection.encryption))),
                 Response::Status(
                     Status::ok(Some(command.tag), None, "CAPABILITY completed").unwrap(),
                 ),
             ]
         }
         Noop => {
-            return vec![Response::Status(
+            // NOOP réussit toujours : s'il n'y a pas de nouvelles, ce sera
+            // pour la prochaine fois
+            let mut responses: Vec<Response> = updates(connection, server).unwrap_or_else(|error| {
+                debug!(%error, "updates failed");
+                vec![]
+            });
+            responses.push(Response::Status(
                 Status::ok(Some(command.tag), None, "NOOP completed").unwrap(),
-            )]
+            ));
+            return responses;
         }
         Logout => {
             connection.state = LogoutState;
@@ -197,123 +1040,53 @@ fn process<'a>(
 
     if connection.state == NotAuthenticated {
         match command.body {
+            StartTLS => {
+                if connection.encryption != Encryption::Available {
+                    return vec![Response::Status(
+                        Status::bad(Some(command.tag), None, "STARTTLS not available").unwrap(),
+                    )];
+                }
+                connection.starting_tls = true;
+                return vec![Response::Status(
+                    Status::ok(Some(command.tag), None, "Begin TLS negotiation now").unwrap(),
+                )];
+            }
+            Authenticate { .. } | Login { .. } if connection.encryption == Encryption::Available => {
+                return vec![Response::Status(
+                    Status::no(
+                        Some(command.tag),
+                        Some(Code::Other(CodeOther::unvalidated(&b"PRIVACYREQUIRED"[..]))),
+                        "Use STARTTLS first",
+                    )
+                    .unwrap(),
+                )];
+            }
             Authenticate {
                 mechanism,
                 initial_response,
             } => {
-                if mechanism != AuthMechanism::Plain {
+                let Some(mut mechanism) = auth::mechanism(&mechanism) else {
                     return vec![Response::Status(
                         Status::no(Some(command.tag), None, "Unsupported mechanism").unwrap(),
                     )];
-                }
-                if initial_response != None {
-                    return vec![Response::Status(
-                        Status::no(Some(command.tag), None, "Unexpected initial response").unwrap(),
-                    )];
-                }
-
-                stream
-                    .write(
-                        &ResponseCodec::default()
-                            .encode(&Response::CommandContinuationRequest(
-                                CommandContinuationRequest::Base64(Cow::Borrowed(&[])),
-                            ))
-                            .dump(),
-                    )
-                    .unwrap();
-
-                // TODO: Malgré cette tentative de faire marcher les choses (voir commentaire suivant),
-                // ça va pas :
-                // Si le client envoie un paquet AAABBB avec AAA une commande AUTHENTICATE PLAIN
-                // normalement on devrait lire BBB pour avoir les données d'authentification
-                // mais ici on n'y a pas accès (elles sont dans le buffer de la boucle principale)
-                // donc on se retrouvera à lire CCC d'un autre paquet
-                // Donc la gestion totale pour AAABBB, CCC... serait AAA, CCC, BBB, ...
-                // ce qui ne va clairement pas (même si en pratique si le client est bien discipliné
-                // il devrait attendre de recevoir la confirmation du serveur pour envoyer les données
-                // d'authentification. il y a quand même de quoi améliorer les choses + aussi les
-                // littéraux non-synchronisants poseraient problème (mais je sais pas s'ils peuvent
-                // être utilisés pendant l'authentification))
-                let mut buffer = [0u8; 1024];
-                let mut consumed = 0;
-                let mut peeked;
-
-                peeked = stream.peek(&mut buffer).unwrap();
-
-                // Le problème est de consommer juste la bonne quantité de données
-                // pour que le reste soit géré par la boucle principale
-                // On pourrait utiliser juste peek puis consommer ce qui a été con-
-                // sommé par le codec mais le problème est que peek ne bloque pas
-                // et donc on attendrait en boucle quand il manque des données.
-                // La solution: peek pour obtenir des données (puisque quand il n'y
-                // a pas de données disponibles peek bloque) puis si c'est pas suf-
-                // fisant, on read() les données pour les consommer (puisqu'on sait
-                // qu'on les utilise de toute manière) et on peek à nouveau.
-                let line = loop {
-                    match AuthenticateDataCodec::default().decode(&buffer[..peeked]) {
-                        Ok((remaining, line)) => {
-                            // unwrap: ok puisque remaining est une slice de buffer
-                            let range = remaining.as_range_of(&buffer).unwrap();
-                            // unwrap: ok puisque déjà peeked
-                            stream.read(&mut buffer[consumed..range.start]).unwrap();
-                            break line;
-                        }
-                        Err(AuthenticateDataDecodeError::Incomplete) => {
-                            if peeked >= buffer.len() {
-                                todo!("OUT OF MEMORY");
-                            }
-                            // unwrap: ok puisque déjà peeked
-                            stream.read(&mut buffer[consumed..peeked]).unwrap();
-                            consumed = peeked;
-                            let received = stream.peek(&mut buffer[consumed..]).unwrap();
-                            if received == 0 {
-                                return vec![];
-                            }
-                            peeked += received;
-                        }
-                        Err(AuthenticateDataDecodeError::Failed) => {
-                            stream.read(&mut buffer[consumed..peeked]).unwrap();
-                            return vec![Response::Status(
-                                Status::bad(Some(command.tag), None, "Invalid BASE64 literal")
-                                    .unwrap(),
-                            )];
-                        }
-                    }
                 };
-
-                /* AuthenticateDataCodec ne gère par "*" mais l'erreur failed le gère
-                 * (pour la mauvaise raison :p)
-                 */
-                let (username, password) = match auth::parse_plain_message(
-                    Secret::new(&line.0.declassify()),
-                    command.tag.clone(),
-                ) {
-                    Ok(tup) => tup,
-                    Err(response) => {
-                        return response;
-                    }
-                };
-
-                let (state, user, response) =
-                    auth::translate(api::login(client, username, password), command.tag);
-
-                connection.state = state;
-                connection.user = user;
-                return response;
+                // Avec SASL-IR, la première réponse vient avec la commande
+                let step = mechanism.step(initial_response.as_ref().map(|response| response.declassify().as_ref()));
+                return authentication_step(command.tag.into_static(), mechanism, step, connection, server);
             }
             Login { username, password } => {
-                let (state, user, response) = auth::translate(
-                    api::login(
-                        client,
-                        str::from_utf8(username.as_ref()).unwrap(),
-                        str::from_utf8(password.declassify().as_ref()).unwrap(),
-                    ),
-                    command.tag,
-                );
-
-                connection.state = state;
-                connection.user = user;
-                return response;
+                // Un littéral peut contenir n'importe quels octets
+                let (Ok(username), Ok(password)) = (str::from_utf8(username.as_ref()), str::from_utf8(password.declassify().as_ref())) else {
+                    return vec![Response::Status(
+                        Status::no(
+                            Some(command.tag),
+                            Some(Code::Other(CodeOther::unvalidated(&b"AUTHENTICATIONFAILED"[..]))),
+                            "Credentials must be valid UTF-8",
+                        )
+                        .unwrap(),
+                    )];
+                };
+                return log_in(connection, command.tag, username, password, server);
             }
             _ => (),
         }
@@ -323,24 +1096,40 @@ fn process<'a>(
         match command.body {
             Select { mailbox } => {
                 // unwrap: on est en authenticated ou selected
-                let user = connection.user.as_ref().unwrap();
-                if let None = connection.folders {
-                    let folders =
-                        mailbox::make_folders(api::get_folders(client, user.id, &user.token));
-                    connection.folders = Some(folders);
-                }
-                let folders = connection.folders.as_ref().unwrap();
-
-                let name = match mailbox {
-                    Mailbox::Inbox => "INBOX",
-                    Mailbox::Other(ref mailbox) => str::from_utf8(mailbox.as_ref()).unwrap(),
+                let session = connection.session.clone().unwrap();
+                let folders = match get_folders(&session, client, false) {
+                    Ok(folders) => folders,
+                    Err(error) => return vec![failed(command.tag, error)],
                 };
+
+                let name = mailbox::name(&mailbox);
                 match folders.get(name) {
-                    Some(&ref mailbox_id) => {
-                        let mut response = mailbox::mailbox_info(
-                            mailbox_id,
-                            api::get_folder_info(client, mailbox_id, user.id, &user.token),
-                        );
+                    Some(mailbox_id) => {
+                        let messages = match session.call(client, |token| api::get_messages(client, session.id, token, mailbox_id)) {
+                            Ok(messages) => messages,
+                            Err(error) => return vec![failed(command.tag, error)],
+                        };
+                        let mut account = lock(&session.state);
+                        let messages = account.assign(mailbox_id, messages);
+                        let mut response = Vec::new();
+                        // Avec QRESYNC, le client doit savoir quand les réponses
+                        // de l'ancien dossier s'arrêtent
+                        if connection.extensions.qresync && matches!(connection.state, Selected(_)) {
+                            response.push(Response::Status(
+                                Status::ok(None, Some(Code::Other(CodeOther::unvalidated(&b"CLOSED"[..]))), "Previous mailbox closed").unwrap(),
+                            ));
+                        }
+                        let keywords = account.keyword_names();
+                        response.extend(mailbox::mailbox_info(
+                            &messages,
+                            keywords,
+                            account.uid_validity(mailbox_id),
+                            account.uid_next(mailbox_id),
+                        ));
+                        response.push(condstore::highest_modseq(account.highest_modseq(mailbox_id)));
+                        drop(account);
+                        // Les autres connexions partent de la même liste
+                        session.update(mailbox_id, messages.clone());
                         response.push(Response::Status(
                             Status::ok(
                                 Some(command.tag),
@@ -350,14 +1139,19 @@ fn process<'a>(
                             .unwrap(),
                         ));
 
+                        Span::current().record("mailbox", name);
                         connection.state = State::Selected(mailbox.into_static());
+                        connection.search_result.clear();
+                        connection.view = idle::view(&messages, local_flags(&session, mailbox_id, &messages));
+                        connection.messages = messages.into_iter().collect();
                         return response;
                     }
                     None => {
-                        let folders =
-                            mailbox::make_folders(api::get_folders(client, user.id, &user.token));
-                        connection.folders = Some(folders);
-                        let folders = connection.folders.as_ref().unwrap();
+                        // Peut-être un classeur créé depuis
+                        let folders = match get_folders(&session, client, true) {
+                            Ok(folders) => folders,
+                            Err(error) => return vec![failed(command.tag, error)],
+                        };
                         if folders.contains_key(name) {
                             return process(
                                 Command {
@@ -365,8 +1159,7 @@ fn process<'a>(
                                     body: Select { mailbox },
                                 },
                                 connection,
-                                stream,
-                                client,
+                                server,
                             );
                         } else {
                             return vec![Response::Status(
@@ -376,6 +1169,10 @@ fn process<'a>(
                     }
                 }
             }
+            // ENABLE n'est valide qu'avant de sélectionner un dossier
+            Enable { capabilities } if connection.state == Authenticated => {
+                return enable::handle(command.tag, capabilities, &mut connection.extensions);
+            }
             Examine { mailbox } => todo!("EXAMINE {:?}", mailbox),
             Create { mailbox } => todo!("CREATE {:?}", mailbox),
             Delete { mailbox } => todo!("DELETE {:?}", mailbox),
@@ -384,8 +1181,60 @@ fn process<'a>(
                 reference,
                 mailbox_wildcard
             } => {
-                return lsub::handle(command.tag, reference, mailbox_wildcard);
+                // unwrap: on est en authenticated ou selected
+                let session = connection.session.clone().unwrap();
+                let folders = match get_folders(&session, client, true) {
+                    Ok(folders) => folders,
+                    Err(error) => return vec![failed(command.tag, error)],
+                };
+                let account = lock(&session.state);
+                return lsub::handle(
+                    command.tag,
+                    &folders,
+                    reference,
+                    mailbox_wildcard,
+                    |mailbox_id| account.is_subscribed(mailbox_id))
+                    .into_static();
             },
+            Idle => {
+                connection.idling = Some(command.tag.into_static());
+                return vec![Response::CommandContinuationRequest(
+                    CommandContinuationRequest::basic(None, "idling").unwrap(),
+                )];
+            }
+            Subscribe { mailbox } => {
+                let session = connection.session.clone().unwrap();
+                let folders = match get_folders(&session, client, false) {
+                    Ok(folders) => folders,
+                    Err(error) => return vec![failed(command.tag, error)],
+                };
+                let mut account = lock(&session.state);
+                return match folders.get(mailbox::name(&mailbox)) {
+                    Some(mailbox_id) => {
+                        account.set_subscribed(mailbox_id, true);
+                        vec![Response::Status(
+                            Status::ok(Some(command.tag), None, "SUBSCRIBE completed").unwrap(),
+                        )]
+                    }
+                    None => vec![Response::Status(
+                        Status::no(Some(command.tag), None, "SUBSCRIBE No such mailbox!").unwrap(),
+                    )],
+                };
+            }
+            Unsubscribe { mailbox } => {
+                let session = connection.session.clone().unwrap();
+                let folders = match get_folders(&session, client, false) {
+                    Ok(folders) => folders,
+                    Err(error) => return vec![failed(command.tag, error)],
+                };
+                let mut account = lock(&session.state);
+                if let Some(mailbox_id) = folders.get(mailbox::name(&mailbox)) {
+                    account.set_subscribed(mailbox_id, false);
+                }
+                return vec![Response::Status(
+                    Status::ok(Some(command.tag), None, "UNSUBSCRIBE completed").unwrap(),
+                )];
+            }
             List {
                 reference,
                 mailbox_wildcard,
@@ -396,11 +1245,11 @@ fn process<'a>(
                     ListMailbox::Token(ref name) => name.as_ref(),
                 };
 
-                if name.len() == 0 {
+                if name.is_empty() {
                     return vec![
                         Response::Data(Data::List {
                             items: vec![Noselect],
-                            delimiter: None,
+                            delimiter: Some(QuotedChar::try_from(mailbox::DELIMITER).unwrap()),
                             mailbox: Mailbox::try_from("").unwrap(),
                         }),
                         Response::Status(
@@ -410,15 +1259,13 @@ fn process<'a>(
                 }
 
                 // unwrap: on est en authenticated ou selected
-                let user = connection.user.as_ref().unwrap();
-                connection.folders = Some(mailbox::make_folders(api::get_folders(
-                    client,
-                    user.id,
-                    &user.token,
-                )));
-                let folders = connection.folders.as_ref().unwrap();
+                let session = connection.session.clone().unwrap();
+                let folders = match get_folders(&session, client, true) {
+                    Ok(folders) => folders,
+                    Err(error) => return vec![failed(command.tag, error)],
+                };
 
-                let mut response = mailbox::filter(folders, reference, name);
+                let mut response = mailbox::filter(&folders, reference, name).into_static();
 
                 response.push(Response::Status(
                     Status::ok(Some(command.tag), None, "LIST completed").unwrap(),
@@ -427,51 +1274,87 @@ fn process<'a>(
             }
             StatusCommand {
                 mailbox,
-                item_names: _,
+                item_names,
             } => {
-                let user = connection.user.as_ref().unwrap();
-                let mailbox_id = connection.folders.as_ref().unwrap().get(match mailbox {
-                    Mailbox::Inbox => "INBOX",
-                    Mailbox::Other(ref mailbox) => str::from_utf8(mailbox.as_ref()).unwrap(),
-                });
-                return match mailbox_id {
-                    Some(mailbox_id) => status::handle(
-                        command.tag,
-                        mailbox,
-                        mailbox_id,
-                        api::get_folder_info(client, mailbox_id, user.id, &user.token)),
+                let session = connection.session.clone().unwrap();
+                let folders = match get_folders(&session, client, false) {
+                    Ok(folders) => folders,
+                    Err(error) => return vec![failed(command.tag, error)],
+                };
+                return match folders.get(mailbox::name(&mailbox)) {
+                    Some(mailbox_id) => {
+                        let folder = session.call(client, |token| api::get_folder_info(client, mailbox_id, session.id, token))
+                            .and_then(|folder| Ok((api::folder_messages(&folder, mailbox_id)?, folder)));
+                        let (messages, folder) = match folder {
+                            Ok(folder) => folder,
+                            Err(error) => return vec![failed(command.tag, error)],
+                        };
+                        let mut account = lock(&session.state);
+                        account.assign(mailbox_id, messages);
+                        status::handle(
+                            command.tag,
+                            mailbox,
+                            &item_names,
+                            mailbox_id,
+                            folder,
+                            account.uid_validity(mailbox_id),
+                            account.uid_next(mailbox_id))
+                    },
                     None => vec![
                         Response::Status(
                             Status::no(Some(command.tag), None, "STATUS No such mailbox!").unwrap())
                     ],
                 }
             },
-            Store {
-                sequence_set,
-                kind,
-                response,
-                flags,
-                uid,
-            } => {
-                let user = connection.user.as_ref().unwrap();
-                return store::handle(
-                    command.tag,
-                    sequence_set,
-                    kind,
-                    response,
-                    flags,
-                    uid,
-                    |message_ids, read_status| api::set_read_status(client, user.id, &user.token, read_status, message_ids));
-            },
             _ => (),
         }
     }
 
     if let Selected(mailbox) = &connection.state {
         match command.body {
-            Check => todo!("CHECK ({:?})", mailbox),
+            // Rien à écrire : CHECK est l'occasion d'annoncer les changements
+            Check => {
+                let mut responses: Vec<Response> = match updates(connection, server) {
+                    Ok(responses) => responses,
+                    Err(error) => return vec![failed(command.tag, error)],
+                };
+                responses.push(Response::Status(
+                    Status::ok(Some(command.tag), None, "CHECK completed").unwrap(),
+                ));
+                return responses;
+            }
             Close => {
+                // CLOSE supprime les messages \Deleted sans envoyer les EXPUNGE
+                let session = connection.session.clone().unwrap();
+                let Some(mailbox_id) = &selected(&session, mailbox) else {
+                    return vec![nonexistent(command.tag)];
+                };
+                let messages = match get_messages(client, &session, mailbox_id, None) {
+                    Ok(messages) => messages,
+                    Err(error) => return vec![failed(command.tag, error)],
+                };
+                let expunged = session.expunge(mailbox_id, |deleted| expunge::expunge(
+                    None,
+                    &messages,
+                    deleted,
+                    |message_ids| session.call(client, |token| api::delete_messages(client, session.id, token, mailbox_id, message_ids)).map_err(Option::from)));
+                // Le dossier reste sélectionné : le client peut réessayer
+                if let Err(message) = expunged {
+                    return vec![Response::Status(
+                        Status::no(
+                            Some(command.tag),
+                            None,
+                            match message {
+                                Some(message) => format!("CLOSE failed: {}", message),
+                                None => String::from("CLOSE failed"),
+                            },
+                        )
+                        .unwrap(),
+                    )];
+                }
+                connection.search_result.clear();
                 connection.state = State::Authenticated;
+                Span::current().record("mailbox", "");
                 return vec![Response::Status(
                     Status::ok(Some(command.tag), None, "Mailbox closed").unwrap(),
                 )];
@@ -480,32 +1363,191 @@ fn process<'a>(
                 charset,
                 criteria,
                 uid,
-            } => todo!(
-                "SEARCH {:?} {:?} {:?} ({:?})",
-                charset,
-                criteria,
-                uid,
-                mailbox
-            ),
+            } => {
+                let session = connection.session.clone().unwrap();
+                let Some(mailbox_id) = &selected(&session, mailbox) else {
+                    return vec![nonexistent(command.tag)];
+                };
+                let messages = match known_messages(server, &session, mailbox_id, &connection.view, &mut connection.messages) {
+                    Ok(messages) => messages,
+                    Err(error) => return vec![failed(command.tag, error)],
+                };
+                let local_flags = local_flags(&session, mailbox_id, &messages);
+                let contents = cache::contents(server.config.cache_dir.as_deref(), session.id);
+                let opened = search::Opened::new(&messages);
+                let failure = Failure::default();
+                let responses = search::handle(
+                    command.tag.clone(),
+                    charset,
+                    criteria,
+                    uid,
+                    messages,
+                    |message_id| failure.record(contents.get(message_id, || session.call(client, |token| api::get_message(client, session.id, token, mailbox_id, message_id)).inspect(|_| opened.add(message_id)))),
+                    local_flags);
+                failure.record(restore_unread(client, &session, mailbox_id, &opened));
+                return failure.check(command.tag, responses);
+            }
             Fetch {
                 sequence_set,
                 macro_or_item_names,
                 uid,
             } => {
-                let user = connection.user.as_ref().unwrap();
-                let mailbox_id = connection.folders.as_ref().unwrap().get(match mailbox {
-                    Mailbox::Inbox => "INBOX",
-                    Mailbox::Other(ref mailbox) => str::from_utf8(mailbox.as_ref()).unwrap(),
-                }).unwrap();
-                let messages = api::get_messages(client, user.id, &user.token, mailbox_id);
-                return fetch::handle(
-                    command.tag,
+                let session = connection.session.clone().unwrap();
+                let Some(mailbox_id) = &selected(&session, mailbox) else {
+                    return vec![nonexistent(command.tag)];
+                };
+                let messages = match known_messages(server, &session, mailbox_id, &connection.view, &mut connection.messages) {
+                    Ok(messages) => messages,
+                    Err(error) => return vec![failed(command.tag, error)],
+                };
+                let local_flags = local_flags(&session, mailbox_id, &messages);
+                let contents = cache::contents(server.config.cache_dir.as_deref(), session.id);
+                // Avec CONDSTORE, l'UID permet de retrouver le MODSEQ de chaque message
+                let macro_or_item_names = if connection.extensions.condstore {
+                    condstore::with_uid(macro_or_item_names)
+                } else {
+                    macro_or_item_names
+                };
+                let failure = Failure::default();
+                let responses = fetch::handle(
+                    command.tag.clone(),
                     sequence_set,
                     macro_or_item_names,
                     uid,
                     messages,
-                    |message_id| api::get_message(client, user.id, &user.token, &mailbox_id, message_id),
-                    |attachment_id| api::get_attachment(client, &user.token, attachment_id));
+                    |message_id| failure.record(contents.get(message_id, || session.call(client, |token| api::get_message(client, session.id, token, mailbox_id, message_id)))),
+                    |attachment_id| failure.record(session.call(client, |token| api::get_attachment(client, token, attachment_id))),
+                    local_flags);
+                return failure.check(command.tag, responses);
+            }
+            Store {
+                sequence_set,
+                kind,
+                response,
+                flags,
+                uid,
+            } => {
+                let session = connection.session.clone().unwrap();
+                let Some(mailbox_id) = &selected(&session, mailbox) else {
+                    return vec![nonexistent(command.tag)];
+                };
+                let messages = match known_messages(server, &session, mailbox_id, &connection.view, &mut connection.messages) {
+                    Ok(messages) => messages,
+                    Err(error) => return vec![failed(command.tag, error)],
+                };
+                let message_ids: HashMap<u32, u32> = messages
+                    .iter()
+                    .map(|(uid, message)| (*uid, message["id"].as_u64().unwrap() as u32))
+                    .collect();
+                let local_flags = local_flags(&session, mailbox_id, &messages);
+                // Les nouveaux drapeaux vont dans la vue même avec .SILENT (le
+                // client sait ce qu'il a demandé), pour ne pas les renvoyer
+                // avec NOOP ou IDLE
+                let mut responses = store::handle(
+                    command.tag,
+                    sequence_set,
+                    kind,
+                    StoreResponse::Answer,
+                    flags,
+                    uid,
+                    messages,
+                    |uids, read_status| {
+                        let ids: Vec<u32> = uids.iter().map(|uid| message_ids[uid]).collect();
+                        session.call(client, |token| api::set_read_status(client, session.id, token, mailbox_id, read_status, &ids)).map_err(|failures| {
+                            let uids: HashMap<u32, u32> = uids.iter().map(|uid| (message_ids[uid], *uid)).collect();
+                            failures.into_iter().map(|(message_id, error)| (uids[&message_id], error.into())).collect()
+                        })
+                    },
+                    |uids, deleted| session.set_deleted(mailbox_id, uids, deleted),
+                    |changes| {
+                        let changes: Vec<(u32, u32, Vec<Flag>)> = changes
+                            .into_iter()
+                            .map(|(uid, keywords)| (uid, message_ids[&uid], keywords))
+                            .collect();
+                        lock(&session.state).set_keywords(mailbox_id, &changes);
+                    },
+                    local_flags);
+                // Les autres connexions verront les nouveaux drapeaux
+                session.invalidate(mailbox_id);
+                idle::apply(&mut connection.view, &responses);
+                if response == StoreResponse::Silent {
+                    if connection.extensions.condstore {
+                        // Le client doit quand même apprendre le nouveau MODSEQ
+                        // de chaque message (RFC 7162), que `adapt` ajoute à
+                        // ces réponses sans FLAGS
+                        responses = responses
+                            .into_iter()
+                            .filter_map(|response| match response {
+                                Response::Data(Data::Fetch { seq, .. }) => {
+                                    let (uid, _) = connection.view.get(seq.get() as usize - 1)?;
+                                    Some(Response::Data(Data::fetch(seq, vec![MessageDataItem::Uid(NonZeroU32::new(*uid)?)]).unwrap()))
+                                }
+                                response => Some(response),
+                            })
+                            .collect();
+                    } else {
+                        responses.retain(|response| !matches!(response, Response::Data(_)));
+                    }
+                }
+                return responses;
+            },
+            Expunge => {
+                let session = connection.session.clone().unwrap();
+                let Some(mailbox_id) = &selected(&session, mailbox) else {
+                    return vec![nonexistent(command.tag)];
+                };
+                let messages = match known_messages(server, &session, mailbox_id, &connection.view, &mut connection.messages) {
+                    Ok(messages) => messages,
+                    Err(error) => return vec![failed(command.tag, error)],
+                };
+                let responses = session.expunge(mailbox_id, |deleted| expunge::handle(
+                    command.tag,
+                    None,
+                    messages,
+                    deleted,
+                    |message_ids| session.call(client, |token| api::delete_messages(client, session.id, token, mailbox_id, message_ids)).map_err(Option::from)));
+                idle::apply(&mut connection.view, &responses);
+                return responses;
+            }
+            Move {
+                sequence_set,
+                mailbox: destination,
+                uid,
+            } => {
+                let session = connection.session.clone().unwrap();
+                let folders = match get_folders(&session, client, false) {
+                    Ok(folders) => folders,
+                    Err(error) => return vec![failed(command.tag, error)],
+                };
+                let Some(mailbox_id) = folders.get(mailbox::name(mailbox)) else {
+                    return vec![nonexistent(command.tag)];
+                };
+                let destination_id = match folders.get(mailbox::name(&destination)) {
+                    Some(destination_id) => destination_id,
+                    None => return vec![Response::Status(
+                        Status::no(Some(command.tag), Some(Code::TryCreate), "MOVE No such mailbox!").unwrap(),
+                    )],
+                };
+                let messages = match known_messages(server, &session, mailbox_id, &connection.view, &mut connection.messages) {
+                    Ok(messages) => messages,
+                    Err(error) => return vec![failed(command.tag, error)],
+                };
+                let account = &session.state;
+                let responses = r#move::handle(
+                    command.tag,
+                    sequence_set,
+                    uid,
+                    messages,
+                    |message_ids| session.call(client, |token| api::move_messages(client, session.id, token, mailbox_id, destination_id, message_ids)).map_err(Option::from),
+                    |message_ids| {
+                        let mut account = lock(account);
+                        let destination_uids = account.reserve(destination_id, message_ids);
+                        (account.uid_validity(destination_id), destination_uids)
+                    });
+                session.invalidate(mailbox_id);
+                session.invalidate(destination_id);
+                idle::apply(&mut connection.view, &responses);
+                return responses;
             }
             _ => (),
         }
@@ -515,3 +1557,536 @@ fn process<'a>(
         Status::no(Some(command.tag), None, "Not supported!").unwrap(),
     )]
 }
+
+// Ce qu'il faut mettre à la place de `$` : le résultat sauvegardé, en UIDs ou
+// en numéros de séquence
+fn saved_result(connection: &Connection<'_>, uid: bool) -> Option<String> {
+    let ids: Vec<u32> = if uid {
+        connection.search_result.clone()
+    } else {
+        let saved: HashSet<&u32> = connection.search_result.iter().collect();
+        connection.view
+            .iter()
+            .enumerate()
+            .filter(|(_, (uid, _))| saved.contains(uid))
+            .map(|(pos, _)| pos as u32 + 1)
+            .collect()
+    };
+    (!ids.is_empty()).then(|| fetch::sequence_set(&ids))
+}
+
+fn process_extended<'a>(
+    command: ExtendedCommand<'a>,
+    connection: &'a mut Connection<'_>,
+    server: &Server,
+) -> Vec<Reply<'a>> {
+    let client = &server.client;
+    match command {
+        ExtendedCommand::Standard(command) => {
+            process(command, connection, server).into_iter().map(Reply::from).collect()
+        }
+        ExtendedCommand::Search { tag, charset, criteria, uid, options, modseq } => {
+            // MODSEQ active CONDSTORE
+            if modseq.is_some() {
+                connection.extensions.condstore = true;
+            }
+            match &connection.state {
+                State::Selected(mailbox) => {
+                    if let Some(response) = search::check_charset(&tag, charset.as_ref()) {
+                        return vec![response.into()];
+                    }
+                    let session = connection.session.clone().unwrap();
+                    let Some(mailbox_id) = &selected(&session, mailbox) else {
+                        return vec![nonexistent(tag).into()];
+                    };
+                    let messages = match known_messages(server, &session, mailbox_id, &connection.view, &mut connection.messages) {
+                        Ok(messages) => messages,
+                        Err(error) => return vec![failed(tag, error).into()],
+                    };
+                    let contents = cache::contents(server.config.cache_dir.as_deref(), session.id);
+                    let local_flags = local_flags(&session, mailbox_id, &messages);
+                    let opened = search::Opened::new(&messages);
+                    let failure = Failure::default();
+                    let get_message = |message_id| failure.record(contents.get(message_id, || session.call(client, |token| api::get_message(client, session.id, token, mailbox_id, message_id)).inspect(|_| opened.add(message_id))));
+
+                    // Avec MODSEQ, on cherche d'abord les messages qui ont changé
+                    // depuis, pour connaître le plus grand MODSEQ des trouvés ;
+                    // la recherche ne porte ensuite que sur leurs UIDs
+                    let (criteria, highest_modseq) = match modseq {
+                        Some(modseq) => {
+                            let found = search::search(&criteria, &messages, get_message, &local_flags);
+                            let mut account = lock(&session.state);
+                            let found: Vec<(u32, u64)> = found
+                                .into_iter()
+                                .map(|pos| (messages[pos].0, account.modseq(mailbox_id, messages[pos].0)))
+                                .filter(|(_, message_modseq)| *message_modseq >= modseq)
+                                .collect();
+                            drop(account);
+                            let uids: Vec<NonZeroU32> = found.iter().map(|(uid, _)| NonZeroU32::new(*uid).unwrap()).collect();
+                            let criteria = match SequenceSet::try_from(uids) {
+                                Ok(uids) => SearchKey::Uid(uids),
+                                Err(_) => SearchKey::Not(Box::new(SearchKey::All)),
+                            };
+                            (criteria, found.iter().map(|(_, modseq)| *modseq).max())
+                        }
+                        None => (criteria, None),
+                    };
+
+                    let mut saved = None;
+                    let mut replies = match options {
+                        Some(options) => search::esearch(
+                            tag.clone(),
+                            charset,
+                            criteria,
+                            uid,
+                            &options,
+                            messages,
+                            get_message,
+                            local_flags,
+                            |uids| saved = Some(uids)),
+                        None => search::handle(tag.clone(), charset, criteria, uid, messages, get_message, local_flags)
+                            .into_iter()
+                            .map(Reply::from)
+                            .collect(),
+                    };
+                    failure.record(restore_unread(client, &session, mailbox_id, &opened));
+                    // Une recherche qui échoue vide le résultat sauvegardé (RFC 5182)
+                    if let Some(uids) = saved {
+                        connection.search_result = if failure.failed() { Vec::new() } else { uids };
+                    }
+                    if let Some(highest_modseq) = highest_modseq {
+                        replies = condstore::search_modseq(replies, highest_modseq);
+                    }
+                    failure.check(tag, replies)
+                }
+                _ => vec![Response::Status(
+                    Status::no(Some(tag), None, "Not supported!").unwrap(),
+                ).into()],
+            }
+        }
+        ExtendedCommand::Sort { tag, sort_criteria, charset, criteria, uid } => match &connection.state {
+            State::Selected(mailbox) => {
+                let session = connection.session.clone().unwrap();
+                let Some(mailbox_id) = &selected(&session, mailbox) else {
+                    return vec![nonexistent(tag).into()];
+                };
+                let messages = match known_messages(server, &session, mailbox_id, &connection.view, &mut connection.messages) {
+                    Ok(messages) => messages,
+                    Err(error) => return vec![failed(tag, error).into()],
+                };
+                let local_flags = local_flags(&session, mailbox_id, &messages);
+                let contents = cache::contents(server.config.cache_dir.as_deref(), session.id);
+                let opened = search::Opened::new(&messages);
+                let failure = Failure::default();
+                let replies = sort::handle(
+                    tag.clone(),
+                    &sort_criteria,
+                    charset,
+                    criteria,
+                    uid,
+                    messages,
+                    |message_id| failure.record(contents.get(message_id, || session.call(client, |token| api::get_message(client, session.id, token, mailbox_id, message_id)).inspect(|_| opened.add(message_id)))),
+                    local_flags);
+                failure.record(restore_unread(client, &session, mailbox_id, &opened));
+                failure.check(tag, replies)
+            }
+            _ => vec![Response::Status(
+                Status::no(Some(tag), None, "Not supported!").unwrap(),
+            ).into()],
+        },
+        ExtendedCommand::Thread { tag, algorithm, charset, criteria, uid } => match &connection.state {
+            State::Selected(mailbox) => {
+                let session = connection.session.clone().unwrap();
+                let Some(mailbox_id) = &selected(&session, mailbox) else {
+                    return vec![nonexistent(tag).into()];
+                };
+                let messages = match known_messages(server, &session, mailbox_id, &connection.view, &mut connection.messages) {
+                    Ok(messages) => messages,
+                    Err(error) => return vec![failed(tag, error).into()],
+                };
+                let local_flags = local_flags(&session, mailbox_id, &messages);
+                let contents = cache::contents(server.config.cache_dir.as_deref(), session.id);
+                let opened = search::Opened::new(&messages);
+                let failure = Failure::default();
+                let replies = thread::handle(
+                    tag.clone(),
+                    algorithm,
+                    charset,
+                    criteria,
+                    uid,
+                    messages,
+                    |message_id| failure.record(contents.get(message_id, || session.call(client, |token| api::get_message(client, session.id, token, mailbox_id, message_id)).inspect(|_| opened.add(message_id)))),
+                    local_flags);
+                failure.record(restore_unread(client, &session, mailbox_id, &opened));
+                failure.check(tag, replies)
+            }
+            _ => vec![Response::Status(
+                Status::no(Some(tag), None, "Not supported!").unwrap(),
+            ).into()],
+        },
+        ExtendedCommand::Namespace { tag } => match connection.state {
+            State::Authenticated | State::Selected(_) => namespace::handle(tag),
+            _ => vec![Response::Status(
+                Status::no(Some(tag), None, "Not supported!").unwrap(),
+            ).into()],
+        },
+        // ID est valide dans tous les états
+        ExtendedCommand::Id { tag } => id::handle(tag),
+        ExtendedCommand::NoMessages { tag } => match connection.state {
+            State::Selected(_) => vec![Response::Status(
+                Status::ok(Some(tag), None, "No messages in the saved result").unwrap(),
+            ).into()],
+            _ => vec![Response::Status(
+                Status::no(Some(tag), None, "Not supported!").unwrap(),
+            ).into()],
+        },
+        ExtendedCommand::Select { tag, mailbox, condstore, qresync } => {
+            if qresync.is_some() && !connection.extensions.qresync {
+                return vec![Response::Status(
+                    Status::bad(Some(tag), None, "QRESYNC not enabled").unwrap(),
+                ).into()];
+            }
+            if condstore {
+                connection.extensions.condstore = true;
+            }
+            let requested = mailbox.clone();
+            let mut replies: Vec<Reply> = process(Command { tag, body: CommandBody::Select { mailbox } }, connection, server)
+                .into_iter()
+                .map(|response| Reply::from(response.into_static()))
+                .collect();
+            // Si SELECT a échoué, on est encore (ou plus) dans un autre dossier
+            let (State::Selected(mailbox), Some(qresync)) = (&connection.state, qresync) else {
+                return replies;
+            };
+            if *mailbox != requested {
+                return replies;
+            }
+            let session = connection.session.clone().unwrap();
+            let Some(mailbox_id) = &selected(&session, mailbox) else {
+                return replies;
+            };
+            let mut account = lock(&session.state);
+            // Nouvel UIDVALIDITY : le client doit tout resynchroniser
+            if account.uid_validity(mailbox_id).get() != qresync.uid_validity {
+                return replies;
+            }
+            let last_uid = connection.view.last().map(|(uid, _)| *uid).unwrap_or_default();
+            let vanished: Vec<u32> = account
+                .vanished(mailbox_id, qresync.modseq)
+                .into_iter()
+                .filter(|uid| qresync.known_uids.as_ref().is_none_or(|known_uids| fetch::matches(known_uids, *uid, last_uid)))
+                .collect();
+            let modseqs: HashMap<u32, u64> = connection.view.iter().map(|(uid, _)| (*uid, account.modseq(mailbox_id, *uid))).collect();
+            drop(account);
+            // Avant la réponse étiquetée
+            let tagged = replies.pop().unwrap();
+            if !vanished.is_empty() {
+                replies.push(condstore::vanished(&vanished, true));
+            }
+            replies.extend(condstore::changes(&connection.view, qresync.modseq, |uid| modseqs[&uid]));
+            replies.push(tagged);
+            replies
+        }
+        ExtendedCommand::Fetch { tag, sequence_set, macro_or_item_names, uid, modseq: _, changed_since, vanished } => {
+            // MODSEQ et CHANGEDSINCE activent CONDSTORE
+            connection.extensions.condstore = true;
+            match &connection.state {
+                State::Selected(mailbox) => {
+                    if vanished && !(uid && changed_since.is_some() && connection.extensions.qresync) {
+                        return vec![Response::Status(
+                            Status::bad(Some(tag), None, "VANISHED needs UID FETCH, CHANGEDSINCE and QRESYNC").unwrap(),
+                        ).into()];
+                    }
+                    let session = connection.session.clone().unwrap();
+                    let Some(mailbox_id) = &selected(&session, mailbox) else {
+                        return vec![nonexistent(tag).into()];
+                    };
+                    let messages = match known_messages(server, &session, mailbox_id, &connection.view, &mut connection.messages) {
+                        Ok(messages) => messages,
+                        Err(error) => return vec![failed(tag, error).into()],
+                    };
+                    let local_flags = local_flags(&session, mailbox_id, &messages);
+                    let mut account = lock(&session.state);
+                    let modseqs: HashMap<u32, u64> = messages.iter().map(|(uid, _)| (*uid, account.modseq(mailbox_id, *uid))).collect();
+
+                    let mut replies = Vec::new();
+                    if let (true, Some(since)) = (vanished, changed_since) {
+                        let vanished: Vec<u32> = account
+                            .vanished(mailbox_id, since)
+                            .into_iter()
+                            .filter(|uid| fetch::matches(&sequence_set, *uid, fetch::largest(&messages, true)))
+                            .collect();
+                        if !vanished.is_empty() {
+                            replies.push(condstore::vanished(&vanished, true));
+                        }
+                    }
+                    drop(account);
+
+                    // Seulement les messages qui ont changé depuis CHANGEDSINCE
+                    let sequence_set = match changed_since {
+                        Some(since) => {
+                            let ids: Vec<NonZeroU32> = fetch::positions(&sequence_set, uid, &messages)
+                                .into_iter()
+                                .filter(|pos| modseqs[&messages[*pos].0] > since)
+                                .map(|pos| NonZeroU32::new(if uid { messages[pos].0 } else { pos as u32 + 1 }).unwrap())
+                                .collect();
+                            match SequenceSet::try_from(ids) {
+                                Ok(sequence_set) => sequence_set,
+                                Err(_) => {
+                                    replies.push(Response::Status(Status::ok(Some(tag), None, "FETCH completed").unwrap()).into());
+                                    return replies;
+                                }
+                            }
+                        }
+                        None => sequence_set,
+                    };
+
+                    let contents = cache::contents(server.config.cache_dir.as_deref(), session.id);
+                    let failure = Failure::default();
+                    let responses = fetch::handle(
+                        tag.clone(),
+                        sequence_set,
+                        condstore::with_uid(macro_or_item_names),
+                        uid,
+                        messages,
+                        |message_id| failure.record(contents.get(message_id, || session.call(client, |token| api::get_message(client, session.id, token, mailbox_id, message_id)))),
+                        |attachment_id| failure.record(session.call(client, |token| api::get_attachment(client, token, attachment_id))),
+                        local_flags);
+                    let responses = failure.check(tag, responses);
+                    let modseq = |uid: u32| modseqs.get(&uid).copied().unwrap_or_default();
+                    replies.extend(responses.into_iter().map(|response| condstore::with_modseq(response, &connection.view, &modseq, true)));
+                    replies
+                }
+                _ => vec![Response::Status(
+                    Status::no(Some(tag), None, "Not supported!").unwrap(),
+                ).into()],
+            }
+        }
+        ExtendedCommand::Store { tag, sequence_set, kind, response, flags, uid, unchanged_since } => {
+            let State::Selected(mailbox) = &connection.state else {
+                return process(Command { tag, body: CommandBody::Store { sequence_set, kind, response, flags, uid } }, connection, server)
+                    .into_iter()
+                    .map(Reply::from)
+                    .collect();
+            };
+            let session = connection.session.clone().unwrap();
+            let Some(mailbox_id) = &selected(&session, mailbox) else {
+                return vec![nonexistent(tag).into()];
+            };
+            let messages = match known_messages(server, &session, mailbox_id, &connection.view, &mut connection.messages) {
+                Ok(messages) => messages,
+                Err(error) => return vec![failed(tag, error).into()],
+            };
+            let mut account = lock(&session.state);
+            let (kept, modified): (Vec<usize>, Vec<usize>) = fetch::positions(&sequence_set, uid, &messages)
+                .into_iter()
+                .partition(|pos| account.modseq(mailbox_id, messages[*pos].0) <= unchanged_since);
+            drop(account);
+            let id = |pos: usize| if uid { messages[pos].0 } else { pos as u32 + 1 };
+            let kept: Vec<NonZeroU32> = kept.into_iter().map(|pos| NonZeroU32::new(id(pos)).unwrap()).collect();
+            let modified: Vec<u32> = modified.into_iter().map(id).collect();
+
+            let mut replies: Vec<Reply> = match SequenceSet::try_from(kept) {
+                Ok(sequence_set) => {
+                    process(Command { tag: tag.clone(), body: CommandBody::Store { sequence_set, kind, response, flags, uid } }, connection, server)
+                        .into_iter()
+                        .map(IntoBoundedStatic::into_static)
+                        .map(Reply::from)
+                        .collect()
+                }
+                Err(_) => vec![Response::Status(Status::ok(Some(tag), None, "STORE completed").unwrap()).into()],
+            };
+            // Ceux qui ont changé entre-temps ne sont pas touchés
+            if !modified.is_empty() {
+                let code = Code::Other(CodeOther::unvalidated(format!("MODIFIED {}", fetch::sequence_set(&modified)).into_bytes()));
+                if let Some(Reply::Response(Response::Status(status))) = replies.last_mut() {
+                    let with_code = match status {
+                        Status::Ok { tag, .. } => Status::ok(tag.clone(), Some(code), "Conditional STORE failed").ok(),
+                        Status::No { tag, text, .. } => Status::no(tag.clone(), Some(code), text.inner().to_string()).ok(),
+                        _ => None,
+                    };
+                    if let Some(with_code) = with_code {
+                        *status = with_code;
+                    }
+                }
+            }
+            replies
+        }
+        ExtendedCommand::Status { tag, mailbox, item_names } => {
+            // HIGHESTMODSEQ active CONDSTORE
+            connection.extensions.condstore = true;
+            let name = mailbox::name(&mailbox).to_string();
+            let responses: Vec<Response> = process(Command { tag, body: CommandBody::Status { mailbox, item_names: item_names.into() } }, connection, server)
+                .into_iter()
+                .map(IntoBoundedStatic::into_static)
+                .collect();
+            // STATUS a chargé les dossiers s'il le fallait
+            let journal = connection
+                .session
+                .as_ref()
+                .and_then(|session| Some((session.mailbox_id(&name)?, session.state.clone())));
+            let Some((mailbox_id, account)) = journal else {
+                return responses.into_iter().map(Reply::from).collect();
+            };
+            let highest_modseq = lock(&account).highest_modseq(&mailbox_id);
+            responses
+                .into_iter()
+                .map(|response| match response {
+                    Response::Data(Data::Status { .. }) => Reply::extend(&response, &format!("HIGHESTMODSEQ {highest_modseq}")),
+                    response => response.into(),
+                })
+                .collect()
+        }
+        ExtendedCommand::UidExpunge { tag, sequence_set } => match &connection.state {
+            State::Selected(mailbox) => {
+                let session = connection.session.clone().unwrap();
+                let Some(mailbox_id) = &selected(&session, mailbox) else {
+                    return vec![nonexistent(tag).into()];
+                };
+                let messages = match known_messages(server, &session, mailbox_id, &connection.view, &mut connection.messages) {
+                    Ok(messages) => messages,
+                    Err(error) => return vec![failed(tag, error).into()],
+                };
+                let responses = session.expunge(mailbox_id, |deleted| expunge::handle(
+                    tag,
+                    Some(&sequence_set),
+                    messages,
+                    deleted,
+                    |message_ids| session.call(client, |token| api::delete_messages(client, session.id, token, mailbox_id, message_ids)).map_err(Option::from)));
+                idle::apply(&mut connection.view, &responses);
+                responses.into_iter().map(Reply::from).collect()
+            }
+            _ => vec![Response::Status(
+                Status::no(Some(tag), None, "Not supported!").unwrap(),
+            ).into()],
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Shutdown, TcpStream};
+    use std::thread;
+
+    // Fait tourner une session sur une connexion locale avec ce que le client
+    // envoie d'un coup, et renvoie les réponses du serveur
+    fn exchange(input: &[u8]) -> String {
+        exchange_with(input, Encryption::Unavailable)
+    }
+
+    fn exchange_with(input: &[u8], encryption: Encryption) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let mut writer = client.try_clone().unwrap();
+        let input = input.to_vec();
+        let writing = thread::spawn(move || {
+            writer.write_all(&input).unwrap();
+            writer.shutdown(Shutdown::Write).unwrap();
+        });
+
+        let config = Config::default();
+        let server = Server { client: api::Client::new(&config.api).unwrap(), config, tls_config: None };
+        let mut connection = Connection { state: State::NotAuthenticated, encryption, ..Connection::default() };
+        session(&mut Stream::Plain(stream), &mut connection, &server).unwrap();
+
+        writing.join().unwrap();
+        let mut output = String::new();
+        client.read_to_string(&mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn skipped_literals() {
+        // Le littéral puis la fin de la ligne
+        assert_eq!(skip(b"abc def\r\nb NOOP\r\n", 3), (9, None));
+        assert_eq!(skip(b"ab", 5), (2, Some(3)));
+        // Un littéral non synchronisant annoncé à la fin de la ligne suit
+        assert_eq!(skip(b"x {2+}\r\nyz\r\nb NOOP\r\n", 0), (12, None));
+        assert_eq!(skip(b"x {4+}\r\nyz", 0), (10, Some(2)));
+        // Le client n'envoie pas un littéral synchronisant
+        assert_eq!(skip(b"x {2}\r\nb NOOP\r\n", 0), (7, None));
+        // Sans la fin de la ligne, on garde de quoi reconnaître l'annonce d'un
+        // littéral
+        assert_eq!(skip(&[b'x'; 40], 0), (24, Some(0)));
+        assert_eq!(skip(b"x {2", 0), (0, Some(0)));
+    }
+
+    #[test]
+    fn literal_too_big_with_its_data() {
+        let mut input = b"a LOGIN {5000+}\r\n".to_vec();
+        input.extend([b'x'; 5000]);
+        input.extend(b" password\r\nb NOOP\r\n");
+        let output = exchange(&input);
+        assert!(output.starts_with("a BAD [TOOBIG] "), "{}", output);
+        assert!(output.contains("\r\nb OK "), "{}", output);
+        assert_eq!(output.matches("\r\n").count(), 2, "{}", output);
+    }
+
+    #[test]
+    fn command_too_long_skips_its_literals() {
+        // Des commandes dans les littéraux, qui ne doivent pas être exécutées
+        let literal = b"z NOOP\r\n".repeat(500);
+        let mut input = b"a SEARCH".to_vec();
+        for _ in 0..20 {
+            input.extend(format!(" TEXT {{{}+}}\r\n", literal.len()).as_bytes());
+            input.extend(&literal);
+        }
+        input.extend(b"\r\nb NOOP\r\n");
+        let output = exchange(&input);
+        assert!(output.starts_with("a BAD [TOOBIG] "), "{}", output);
+        assert!(output.contains("\r\nb OK "), "{}", output);
+        assert!(!output.contains("z "), "{}", output);
+    }
+
+    #[test]
+    fn failed_command_ends_after_its_literals() {
+        let output = exchange(b"a SELECT {3}\r\nabc bad\r\nb NOOP\r\n");
+        assert!(output.starts_with("a BAD "), "{}", output);
+        assert!(output.contains("\r\nb OK "), "{}", output);
+        assert_eq!(output.matches("\r\n").count(), 2, "{}", output);
+
+        let output = exchange(b"a FOO {8+}\r\nz NOOP\r\n\r\nb NOOP\r\n");
+        assert!(output.starts_with("a BAD "), "{}", output);
+        assert!(output.contains("\r\nb OK "), "{}", output);
+        assert!(!output.contains("z "), "{}", output);
+    }
+
+    #[test]
+    fn credentials_must_be_utf8() {
+        let output = exchange(b"a LOGIN jean {5+}\r\nd\xe9j\xe0!\r\nb NOOP\r\n");
+        assert!(output.starts_with("a NO [AUTHENTICATIONFAILED] "), "{}", output);
+        assert!(output.contains("\r\nb OK "), "{}", output);
+    }
+
+    #[test]
+    fn authentication_needs_starttls() {
+        let output = exchange_with(b"a CAPABILITY\r\nb LOGIN jean secret\r\nc AUTHENTICATE PLAIN\r\n", Encryption::Available);
+        let capability = output.lines().next().unwrap();
+        assert!(capability.contains(" STARTTLS LOGINDISABLED "), "{}", output);
+        assert!(!capability.contains("AUTH="), "{}", output);
+        assert!(output.contains("\r\nb NO [PRIVACYREQUIRED] "), "{}", output);
+        assert!(output.contains("\r\nc NO [PRIVACYREQUIRED] "), "{}", output);
+
+        // Sans certificat, pas de STARTTLS mais on peut s'authentifier
+        let output = exchange(b"a CAPABILITY\r\nb STARTTLS\r\n");
+        let capability = output.lines().next().unwrap();
+        assert!(capability.contains(" AUTH=PLAIN "), "{}", output);
+        assert!(!capability.contains("STARTTLS") && !capability.contains("LOGINDISABLED"), "{}", output);
+        assert!(output.contains("\r\nb BAD "), "{}", output);
+    }
+
+    #[test]
+    fn authentication_exchange() {
+        // Le client abandonne en réponse au défi
+        let output = exchange(b"a AUTHENTICATE PLAIN\r\n*\r\nb NOOP\r\n");
+        assert!(output.starts_with("+ \r\na BAD "), "{}", output);
+        assert!(output.contains("\r\nb OK "), "{}", output);
+
+        // Une réponse initiale vide est une réponse, pas son absence
+        let output = exchange(b"a AUTHENTICATE PLAIN =\r\nb NOOP\r\n");
+        assert!(output.starts_with("a NO "), "{}", output);
+        assert!(output.contains("\r\nb OK "), "{}", output);
+    }
+}
@@ -0,0 +1,115 @@
+use imap_codec::imap_types::{
+    core::Tag,
+    response::{Code, CodeOther, Data, Response, Status},
+    sequence::SequenceSet,
+};
+use std::num::NonZeroU32;
+use crate::fetch;
+
+pub fn handle<'a, F: Fn(&[u32]) -> Result<(), Option<String>>, G: FnOnce(&[u32]) -> (NonZeroU32, Vec<u32>)>(tag: Tag<'a>, sequence_set: SequenceSet, uid: bool, messages: Vec<(u32, serde_json::Value)>, move_messages: F, reserve_uids: G) -> Vec<Response<'a>> {
+    // messages est trié par UID
+    let selected: Vec<(usize, u32, u32)> = fetch::positions(&sequence_set, uid, &messages)
+        .into_iter()
+        .map(|pos| (pos, messages[pos].0, messages[pos].1["id"].as_u64().unwrap() as u32))
+        .collect();
+
+    if selected.is_empty() {
+        return vec![Response::Status(Status::ok(Some(tag), None, "MOVE completed (no messages)").unwrap())];
+    }
+
+    let message_ids: Vec<u32> = selected.iter().map(|&(_, _, message_id)| message_id).collect();
+    if let Err(message) = move_messages(&message_ids) {
+        return vec![Response::Status(
+            Status::no(
+                Some(tag),
+                None,
+                match message {
+                    Some(message) => format!("MOVE failed: {}", message),
+                    None => String::from("MOVE failed"),
+                },
+            )
+            .unwrap(),
+        )];
+    }
+
+    let source_uids: Vec<u32> = selected.iter().map(|&(_, uid, _)| uid).collect();
+    let (uid_validity, destination_uids) = reserve_uids(&message_ids);
+    let copy_uid = format!("COPYUID {uid_validity} {} {}", fetch::sequence_set(&source_uids), fetch::sequence_set(&destination_uids));
+    let mut responses = vec![Response::Status(
+        Status::ok(None, Some(Code::Other(CodeOther::unvalidated(copy_uid.into_bytes()))), "Messages moved").unwrap(),
+    )];
+
+    // Du plus grand au plus petit pour que les numéros restent valides
+    responses.extend(selected.iter().rev().map(|&(pos, _, _)| {
+        Response::Data(Data::Expunge(NonZeroU32::new((pos + 1) as u32).unwrap()))
+    }));
+
+    responses.push(Response::Status(Status::ok(Some(tag), None, "MOVE completed").unwrap()));
+    responses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use imap_codec::{encode::Encoder, ResponseCodec};
+    use serde_json::{json, Value};
+    use std::cell::RefCell;
+
+    fn lines(responses: Vec<Response>) -> Vec<String> {
+        responses
+            .iter()
+            .map(|response| String::from_utf8(ResponseCodec::default().encode(response).dump()).unwrap())
+            .collect()
+    }
+
+    // UIDs 3, 5 et 9 pour les messages 30, 50 et 90
+    fn messages() -> Vec<(u32, Value)> {
+        [3, 5, 9].map(|uid| (uid, json!({ "id": uid * 10 }))).to_vec()
+    }
+
+    #[test]
+    fn moved_messages() {
+        let tag = Tag::try_from("a").unwrap();
+        let moved = RefCell::new(Vec::new());
+        let responses = handle(
+            tag,
+            SequenceSet::try_from("5:*").unwrap(),
+            true,
+            messages(),
+            |message_ids| {
+                moved.borrow_mut().extend_from_slice(message_ids);
+                Ok(())
+            },
+            |message_ids| (NonZeroU32::new(7).unwrap(), (1..=message_ids.len() as u32).map(|uid| uid + 20).collect()),
+        );
+        assert_eq!(*moved.borrow(), [50, 90]);
+        // Les EXPUNGE vont du dernier au premier
+        assert_eq!(
+            lines(responses),
+            [
+                "* OK [COPYUID 7 5,9 21:22] Messages moved\r\n",
+                "* 3 EXPUNGE\r\n",
+                "* 2 EXPUNGE\r\n",
+                "a OK MOVE completed\r\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_or_empty_move() {
+        let tag = Tag::try_from("a").unwrap();
+        let no_uids = |_: &[u32]| -> (NonZeroU32, Vec<u32>) { unreachable!() };
+        let responses = handle(
+            tag.clone(),
+            SequenceSet::try_from("1").unwrap(),
+            false,
+            messages(),
+            |_| Err(Some(String::from("Dossier inconnu"))),
+            no_uids,
+        );
+        assert_eq!(lines(responses), ["a NO MOVE failed: Dossier inconnu\r\n"]);
+        // Des UIDs qui n'existent pas : rien à demander à EcoleDirecte
+        let responses = handle(tag, SequenceSet::try_from("4").unwrap(), true, messages(), |_| unreachable!(), no_uids);
+        assert_eq!(lines(responses), ["a OK MOVE completed (no messages)\r\n"]);
+    }
+}
@@ -0,0 +1,30 @@
+use imap_codec::imap_types::{
+    core::Tag,
+    response::{Response, Status},
+};
+use crate::mailbox::DELIMITER;
+use crate::Reply;
+
+/// Tous les dossiers d'un compte (dossiers spéciaux et classeurs) sont au
+/// même niveau, sans préfixe, et il n'y a pas de dossiers partagés (RFC 2342).
+pub fn handle(tag: Tag) -> Vec<Reply> {
+    vec![
+        Reply::Untagged(format!("NAMESPACE ((\"\" \"{DELIMITER}\")) NIL NIL")),
+        Response::Status(Status::ok(Some(tag), None, "NAMESPACE completed").unwrap()).into(),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use imap_codec::{encode::Encoder, ResponseCodec};
+
+    #[test]
+    fn personal_namespace_only() {
+        let replies = handle(Tag::try_from("a").unwrap());
+        let Reply::Untagged(namespace) = &replies[0] else { panic!("réponse NAMESPACE attendue") };
+        assert_eq!(namespace, "NAMESPACE ((\"\" \"/\")) NIL NIL");
+        let Reply::Response(response) = &replies[1] else { panic!("réponse OK attendue") };
+        assert_eq!(ResponseCodec::default().encode(response).dump(), b"a OK NAMESPACE completed\r\n");
+    }
+}
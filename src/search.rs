@@ -0,0 +1,416 @@
+use imap_codec::imap_types::{
+    self,
+    core::{Charset, Tag},
+    flag::Flag,
+    response::{Code, Data, Response, Status},
+    search::SearchKey,
+};
+use base64::Engine;
+use chrono::{NaiveDate, NaiveDateTime};
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::num::NonZeroU32;
+use crate::command::SearchReturn;
+use crate::fetch;
+use crate::Reply;
+
+const CHARSETS: [&str; 2] = ["UTF-8", "US-ASCII"];
+
+// Un message tel que le voit le client
+struct Candidate<'m> {
+    seq: u32,
+    uid: u32,
+    message: &'m Value,
+    flags: Vec<Flag<'static>>,
+    // Ce que vaut `*` en numéro de séquence et en UID
+    largest: (u32, u32),
+}
+
+// Comparaison insensible à la casse, comme le veut la RFC
+fn contains(haystack: &str, needle: &[u8]) -> bool {
+    haystack.to_lowercase().contains(&String::from_utf8_lossy(needle).to_lowercase())
+}
+
+// Noms des destinataires d'un type donné ("to", "cc" ou "cci")
+fn recipients(message: &Value, kind: &str) -> String {
+    message["to"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|person| person["to_cc_cci"].as_str() == Some(kind))
+        .filter_map(|person| person["name"].as_str())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn date(message: &Value) -> Option<NaiveDate> {
+    NaiveDateTime::parse_from_str(message["date"].as_str()?, "%Y-%m-%d %H:%M:%S")
+        .ok()
+        .map(|date| date.date())
+}
+
+fn header(message: &Value, name: &[u8]) -> Option<String> {
+    let header = fetch::make_header(message).replace("\r\n ", " ");
+    header
+        .split("\r\n")
+        .filter_map(|line| line.split_once(": "))
+        .find(|(field, _)| field.as_bytes().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.to_string())
+}
+
+// Le contenu EcoleDirecte est du HTML encodé en base64
+fn html(full_message: &Value) -> String {
+    let content = full_message["content"].as_str().unwrap_or_default();
+    let html = base64::engine::general_purpose::STANDARD.decode(content).unwrap_or_default();
+    String::from_utf8_lossy(&html).into_owned()
+}
+
+/// Texte d'un message, sans les balises HTML.
+pub fn text(full_message: &Value) -> String {
+    let mut text = String::new();
+    let mut in_tag = false;
+    for c in html(full_message).chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => {
+                in_tag = false;
+                text.push(' ');
+            }
+            _ if !in_tag => text.push(c),
+            _ => (),
+        }
+    }
+    decode_entities(&text)
+}
+
+// Remplace les entités HTML (&eacute;, &#233;, &#xE9;...) par leur caractère.
+// Celles qu'on ne connaît pas restent telles quelles.
+fn decode_entities(html: &str) -> String {
+    let mut text = String::new();
+    let mut rest = html;
+    while let Some(start) = rest.find('&') {
+        text.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        let decoded = rest
+            .find(';')
+            .filter(|end| *end <= 8)
+            .and_then(|end| Some((entity(&rest[..end])?, end + 1)));
+        match decoded {
+            Some((c, length)) => {
+                text.push(c);
+                rest = &rest[length..];
+            }
+            None => text.push('&'),
+        }
+    }
+    text.push_str(rest);
+    text
+}
+
+fn entity(name: &str) -> Option<char> {
+    if let Some(number) = name.strip_prefix('#') {
+        let code = match number.strip_prefix(['x', 'X']) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => number.parse().ok()?,
+        };
+        return char::from_u32(code);
+    }
+    // Celles qu'on trouve dans les messages EcoleDirecte (l'éditeur encode les
+    // accents)
+    Some(match name {
+        "nbsp" => ' ',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "amp" => '&',
+        "agrave" => 'à',
+        "acirc" => 'â',
+        "auml" => 'ä',
+        "ccedil" => 'ç',
+        "eacute" => 'é',
+        "egrave" => 'è',
+        "ecirc" => 'ê',
+        "euml" => 'ë',
+        "icirc" => 'î',
+        "iuml" => 'ï',
+        "ocirc" => 'ô',
+        "ouml" => 'ö',
+        "ugrave" => 'ù',
+        "ucirc" => 'û',
+        "uuml" => 'ü',
+        "yuml" => 'ÿ',
+        "aelig" => 'æ',
+        "oelig" => 'œ',
+        "Agrave" => 'À',
+        "Acirc" => 'Â',
+        "Ccedil" => 'Ç',
+        "Eacute" => 'É',
+        "Egrave" => 'È',
+        "Ecirc" => 'Ê',
+        "Euml" => 'Ë',
+        "Icirc" => 'Î',
+        "Iuml" => 'Ï',
+        "Ocirc" => 'Ô',
+        "Ugrave" => 'Ù',
+        "Ucirc" => 'Û',
+        "AElig" => 'Æ',
+        "OElig" => 'Œ',
+        "laquo" => '«',
+        "raquo" => '»',
+        "lsquo" => '‘',
+        "rsquo" => '’',
+        "ldquo" => '“',
+        "rdquo" => '”',
+        "ndash" => '–',
+        "mdash" => '—',
+        "hellip" => '…',
+        "euro" => '€',
+        "deg" => '°',
+        _ => return None,
+    })
+}
+
+/// Taille approximative du message tel qu'on l'envoie au client.
+pub fn size(message: &Value, full_message: &Value) -> u32 {
+    (fetch::make_header(message).len() + html(full_message).len()) as u32
+}
+
+fn has_flag(candidate: &Candidate, flag: &Flag) -> bool {
+    candidate.flags.iter().any(|candidate_flag| fetch::same_flag(candidate_flag, flag))
+}
+
+fn matches<F: Fn(u32) -> Value>(key: &SearchKey, candidate: &Candidate, get_message: &F) -> bool {
+    use SearchKey::*;
+    let message = candidate.message;
+    let full_message = || get_message(message["id"].as_u64().unwrap() as u32);
+    let in_date = |compare: fn(NaiveDate, &NaiveDate) -> bool, day: &imap_types::datetime::NaiveDate| {
+        date(message).is_some_and(|date| compare(date, day.as_ref()))
+    };
+    match key {
+        And(keys) => keys.as_ref().iter().all(|key| matches(key, candidate, get_message)),
+        Not(key) => !matches(key, candidate, get_message),
+        Or(left, right) => matches(left, candidate, get_message) || matches(right, candidate, get_message),
+        SequenceSet(sequence_set) => fetch::matches(sequence_set, candidate.seq, candidate.largest.0),
+        Uid(sequence_set) => fetch::matches(sequence_set, candidate.uid, candidate.largest.1),
+        All => true,
+        Answered => has_flag(candidate, &Flag::Answered),
+        Unanswered => !has_flag(candidate, &Flag::Answered),
+        Deleted => has_flag(candidate, &Flag::Deleted),
+        Undeleted => !has_flag(candidate, &Flag::Deleted),
+        Draft => has_flag(candidate, &Flag::Draft),
+        Undraft => !has_flag(candidate, &Flag::Draft),
+        Flagged => has_flag(candidate, &Flag::Flagged),
+        Unflagged => !has_flag(candidate, &Flag::Flagged),
+        Seen => has_flag(candidate, &Flag::Seen),
+        Unseen => !has_flag(candidate, &Flag::Seen),
+        Keyword(keyword) => has_flag(candidate, &Flag::Keyword(keyword.clone())),
+        Unkeyword(keyword) => !has_flag(candidate, &Flag::Keyword(keyword.clone())),
+        // Aucun message n'est \Recent
+        New | Recent => false,
+        Old => true,
+        From(value) => contains(message["from"]["name"].as_str().unwrap_or_default(), value.as_ref()),
+        To(value) => contains(&recipients(message, "to"), value.as_ref()),
+        Cc(value) => contains(&recipients(message, "cc"), value.as_ref()),
+        Bcc(value) => contains(&recipients(message, "cci"), value.as_ref()),
+        Subject(value) => contains(message["subject"].as_str().unwrap_or_default(), value.as_ref()),
+        Header(name, value) => header(message, name.as_ref()).is_some_and(|header| contains(&header, value.as_ref())),
+        // La date d'envoi et la date de réception sont la même chez EcoleDirecte
+        Since(day) | SentSince(day) => in_date(|date, day| date >= *day, day),
+        Before(day) | SentBefore(day) => in_date(|date, day| date < *day, day),
+        On(day) | SentOn(day) => in_date(|date, day| date == *day, day),
+        Body(value) => contains(&text(&full_message()), value.as_ref()),
+        Text(value) => {
+            contains(&fetch::make_header(message), value.as_ref()) || contains(&text(&full_message()), value.as_ref())
+        }
+        Larger(n) => size(message, &full_message()) > *n,
+        Smaller(n) => size(message, &full_message()) < *n,
+    }
+}
+
+/// Messages téléchargés pendant une recherche. EcoleDirecte marque lu un
+/// message qu'on ouvre, alors que SEARCH (et SORT, THREAD) ne doit pas
+/// changer \Seen : ceux qui n'étaient pas lus sont remis non lus à la fin.
+pub struct Opened {
+    unread: HashSet<u32>,
+    message_ids: RefCell<Vec<u32>>,
+}
+
+impl Opened {
+    pub fn new(messages: &[(u32, Value)]) -> Opened {
+        let unread = messages
+            .iter()
+            .filter(|(_, message)| message["read"].as_bool() == Some(false))
+            .filter_map(|(_, message)| Some(message["id"].as_u64()? as u32))
+            .collect();
+        Opened { unread, message_ids: RefCell::default() }
+    }
+
+    /// `message_id` vient d'être téléchargé.
+    pub fn add(&self, message_id: u32) {
+        if self.unread.contains(&message_id) {
+            self.message_ids.borrow_mut().push(message_id);
+        }
+    }
+
+    /// Remet non lus, avec `set_unread`, les messages ouverts depuis `new`.
+    pub fn restore<E, F: FnOnce(&[u32]) -> Result<(), E>>(&self, set_unread: F) -> Result<(), E> {
+        let message_ids = self.message_ids.take();
+        if message_ids.is_empty() {
+            return Ok(());
+        }
+        set_unread(&message_ids)
+    }
+}
+
+/// Positions (à partir de 0) des messages qui correspondent aux critères.
+pub fn search<F: Fn(u32) -> Value, H: Fn(u32) -> Vec<Flag<'static>>>(criteria: &SearchKey, messages: &[(u32, Value)], get_message: F, local_flags: H) -> Vec<usize> {
+    let largest = (fetch::largest(messages, false), fetch::largest(messages, true));
+    messages
+        .iter()
+        .enumerate()
+        .filter(|(pos, (uid, message))| {
+            let candidate = Candidate {
+                seq: (pos + 1) as u32,
+                uid: *uid,
+                message,
+                flags: fetch::flags(message, local_flags(*uid)),
+                largest,
+            };
+            matches(criteria, &candidate, &get_message)
+        })
+        .map(|(pos, _)| pos)
+        .collect()
+}
+
+/// Réponse NO [BADCHARSET] si le jeu de caractères n'est pas géré.
+pub fn check_charset<'a>(tag: &Tag<'a>, charset: Option<&Charset>) -> Option<Response<'a>> {
+    let charset = charset?;
+    if CHARSETS.iter().any(|supported| supported.eq_ignore_ascii_case(charset.as_ref())) {
+        return None;
+    }
+    Some(Response::Status(
+        Status::no(
+            Some(tag.clone()),
+            Some(Code::BadCharset {
+                allowed: CHARSETS.iter().map(|charset| Charset::try_from(*charset).unwrap()).collect(),
+            }),
+            "Unsupported charset",
+        )
+        .unwrap(),
+    ))
+}
+
+pub fn handle<'a, F: Fn(u32) -> Value, H: Fn(u32) -> Vec<Flag<'static>>>(tag: Tag<'a>, charset: Option<Charset>, criteria: SearchKey, uid: bool, messages: Vec<(u32, Value)>, get_message: F, local_flags: H) -> Vec<Response<'a>> {
+    if let Some(response) = check_charset(&tag, charset.as_ref()) {
+        return vec![response];
+    }
+
+    let found = search(&criteria, &messages, get_message, local_flags)
+        .into_iter()
+        .map(|pos| NonZeroU32::new(if uid { messages[pos].0 } else { (pos + 1) as u32 }).unwrap())
+        .collect();
+
+    vec![
+        Response::Data(Data::Search(found)),
+        Response::Status(Status::ok(Some(tag), None, "SEARCH completed").unwrap()),
+    ]
+}
+
+/// SEARCH RETURN (...) : résultat compact (ESEARCH) et/ou sauvegardé pour `$`.
+#[allow(clippy::too_many_arguments)]
+pub fn esearch<'a, F: Fn(u32) -> Value, H: Fn(u32) -> Vec<Flag<'static>>, S: FnOnce(Vec<u32>)>(tag: Tag<'a>, charset: Option<Charset>, criteria: SearchKey, uid: bool, options: &[SearchReturn], messages: Vec<(u32, Value)>, get_message: F, local_flags: H, save: S) -> Vec<Reply<'a>> {
+    if let Some(response) = check_charset(&tag, charset.as_ref()) {
+        return vec![response.into()];
+    }
+
+    // RETURN () veut dire RETURN (ALL)
+    let options = if options.is_empty() { &[SearchReturn::All][..] } else { options };
+    let found = search(&criteria, &messages, get_message, local_flags);
+    let ids: Vec<u32> = found.iter().map(|&pos| if uid { messages[pos].0 } else { (pos + 1) as u32 }).collect();
+
+    if options.contains(&SearchReturn::Save) {
+        // Avec seulement MIN et/ou MAX, on ne sauvegarde que ceux-là
+        let only_min_max = options.iter().any(|option| matches!(option, SearchReturn::Min | SearchReturn::Max))
+            && !options.iter().any(|option| matches!(option, SearchReturn::All | SearchReturn::Count));
+        let saved = if !only_min_max {
+            found.clone()
+        } else {
+            [(SearchReturn::Min, found.first()), (SearchReturn::Max, found.last())]
+                .into_iter()
+                .filter(|(option, _)| options.contains(option))
+                .filter_map(|(_, pos)| pos.copied())
+                .collect()
+        };
+        let mut saved: Vec<u32> = saved.into_iter().map(|pos| messages[pos].0).collect();
+        saved.dedup();
+        save(saved);
+    }
+
+    let mut replies = Vec::new();
+    // Pas de réponse ESEARCH quand on ne demande qu'à sauvegarder
+    if options.iter().any(|option| *option != SearchReturn::Save) {
+        let mut esearch = format!("ESEARCH (TAG \"{}\")", tag.inner());
+        if uid {
+            esearch += " UID";
+        }
+        for option in options {
+            match (option, ids.first(), ids.last()) {
+                (SearchReturn::Min, Some(min), _) => esearch += &format!(" MIN {min}"),
+                (SearchReturn::Max, _, Some(max)) => esearch += &format!(" MAX {max}"),
+                (SearchReturn::Count, _, _) => esearch += &format!(" COUNT {}", ids.len()),
+                (SearchReturn::All, Some(_), _) => esearch += &format!(" ALL {}", fetch::sequence_set(&ids)),
+                _ => (),
+            }
+        }
+        replies.push(Reply::Untagged(esearch));
+    }
+
+    replies.push(Response::Status(Status::ok(Some(tag), None, "SEARCH completed").unwrap()).into());
+    replies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    #[test]
+    fn entities() {
+        assert_eq!(decode_entities("R&eacute;union &amp; sortie"), "Réunion & sortie");
+        assert_eq!(decode_entities("&#233;t&#xE9; &lt;3"), "été <3");
+        assert_eq!(decode_entities("&amp;lt; &inconnu; a & b &;"), "&lt; &inconnu; a & b &;");
+    }
+    #[test]
+    fn searched_messages_stay_unread() {
+        // EcoleDirecte : le contenu des messages et s'ils sont lus
+        let read = RefCell::new(HashMap::from([(10, false), (20, true), (30, false)]));
+        let messages: Vec<(u32, Value)> = [10, 20, 30]
+            .into_iter()
+            .enumerate()
+            .map(|(pos, id)| (pos as u32 + 1, json!({ "id": id, "read": read.borrow()[&id], "answered": false, "brouillon": false })))
+            .collect();
+        let opened = Opened::new(&messages);
+        let get_message = |message_id: u32| {
+            read.borrow_mut().insert(message_id, true);
+            opened.add(message_id);
+            json!({ "content": base64::engine::general_purpose::STANDARD.encode(format!("<p>message {message_id}</p>")) })
+        };
+
+        let criteria = SearchKey::Body("message 3".try_into().unwrap());
+        assert_eq!(search(&criteria, &messages, get_message, |_| vec![]), [2]);
+        opened
+            .restore(|message_ids| {
+                for message_id in message_ids {
+                    read.borrow_mut().insert(*message_id, false);
+                }
+                Ok::<_, ()>(())
+            })
+            .unwrap();
+        assert_eq!(*read.borrow(), HashMap::from([(10, false), (20, true), (30, false)]));
+        // Rien à remettre une deuxième fois
+        opened.restore(|_| -> Result<(), ()> { unreachable!() }).unwrap();
+    }
+}
@@ -0,0 +1,177 @@
+use imap_codec::imap_types::{
+    core::{Charset, Tag},
+    flag::Flag,
+    response::{Response, Status},
+    search::SearchKey,
+};
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use crate::command::SortCriterion;
+use crate::search;
+use crate::Reply;
+
+// Enlève un "Re:", "Fwd:", "TR:" (transfert en français) ou "[blob]" au début
+fn strip_prefix(subject: &str) -> Option<&str> {
+    if let Some((_, rest)) = subject.strip_prefix('[').and_then(|rest| rest.split_once(']')) {
+        // Un sujet qui n'est qu'un [blob] le garde
+        if !rest.trim().is_empty() {
+            return Some(rest);
+        }
+    }
+    ["re", "fwd", "fw", "tr"].iter().find_map(|prefix| {
+        let rest = subject.get(..prefix.len()).filter(|start| start.eq_ignore_ascii_case(prefix))?;
+        let mut rest = subject[rest.len()..].trim_start();
+        if let Some((_, after)) = rest.strip_prefix('[').and_then(|rest| rest.split_once(']')) {
+            rest = after.trim_start();
+        }
+        rest.strip_prefix(':')
+    })
+}
+
+/// Sujet de base (RFC 5256) pour trier et regrouper les messages : sans les
+/// "Re:", "Fwd:" et autres, en minuscules.
+pub fn base_subject(subject: &str) -> String {
+    let mut subject = subject.split_whitespace().collect::<Vec<_>>().join(" ");
+    loop {
+        let mut stripped = subject.trim().to_string();
+        while stripped.len() >= 5 && stripped.get(stripped.len() - 5..).is_some_and(|end| end.eq_ignore_ascii_case("(fwd)")) {
+            stripped = stripped[..stripped.len() - 5].trim_end().to_string();
+        }
+        if let Some(rest) = strip_prefix(&stripped) {
+            stripped = rest.trim_start().to_string();
+        }
+        if stripped == subject {
+            return subject.to_lowercase();
+        }
+        subject = stripped;
+    }
+}
+
+// Premier nom d'une liste de personnes EcoleDirecte, pour trier par FROM, TO ou CC
+fn first_name(message: &Value, criterion: SortCriterion) -> String {
+    let name = match criterion {
+        SortCriterion::From => message["from"]["name"].as_str(),
+        SortCriterion::To | SortCriterion::Cc => {
+            let kind = if criterion == SortCriterion::To { "to" } else { "cc" };
+            message["to"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .find(|person| person["to_cc_cci"].as_str() == Some(kind))
+                .and_then(|person| person["name"].as_str())
+        }
+        _ => None,
+    };
+    name.unwrap_or_default().to_lowercase()
+}
+
+fn compare(criterion: SortCriterion, left: &Value, right: &Value, sizes: &HashMap<u32, u32>) -> Ordering {
+    match criterion {
+        // Chez EcoleDirecte la date d'envoi est aussi la date d'arrivée, et
+        // "AAAA-MM-JJ HH:MM:SS" se trie comme une chaîne
+        SortCriterion::Arrival | SortCriterion::Date => left["date"].as_str().cmp(&right["date"].as_str()),
+        SortCriterion::From | SortCriterion::To | SortCriterion::Cc => {
+            first_name(left, criterion).cmp(&first_name(right, criterion))
+        }
+        SortCriterion::Subject => base_subject(left["subject"].as_str().unwrap_or_default())
+            .cmp(&base_subject(right["subject"].as_str().unwrap_or_default())),
+        SortCriterion::Size => {
+            let size = |message: &Value| sizes.get(&(message["id"].as_u64().unwrap() as u32)).copied();
+            size(left).cmp(&size(right))
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn handle<'a, F: Fn(u32) -> Value, H: Fn(u32) -> Vec<Flag<'static>>>(tag: Tag<'a>, sort_criteria: &[(bool, SortCriterion)], charset: Charset, criteria: SearchKey, uid: bool, messages: Vec<(u32, Value)>, get_message: F, local_flags: H) -> Vec<Reply<'a>> {
+    if let Some(response) = search::check_charset(&tag, Some(&charset)) {
+        return vec![response.into()];
+    }
+
+    let mut found = search::search(&criteria, &messages, &get_message, local_flags);
+
+    // La taille demande le contenu : on ne le récupère qu'une fois par message
+    let sizes: HashMap<u32, u32> = if sort_criteria.iter().any(|(_, criterion)| *criterion == SortCriterion::Size) {
+        found
+            .iter()
+            .map(|&pos| {
+                let message = &messages[pos].1;
+                let message_id = message["id"].as_u64().unwrap() as u32;
+                (message_id, search::size(message, &get_message(message_id)))
+            })
+            .collect()
+    } else {
+        HashMap::new()
+    };
+
+    // En cas d'égalité, on garde l'ordre des numéros de séquence
+    found.sort_by(|&left, &right| {
+        sort_criteria
+            .iter()
+            .map(|&(reverse, criterion)| {
+                let ordering = compare(criterion, &messages[left].1, &messages[right].1, &sizes);
+                if reverse { ordering.reverse() } else { ordering }
+            })
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or(left.cmp(&right))
+    });
+
+    let mut sort = String::from("SORT");
+    for pos in found {
+        sort += &format!(" {}", if uid { messages[pos].0 } else { (pos + 1) as u32 });
+    }
+
+    vec![
+        Reply::Untagged(sort),
+        Response::Status(Status::ok(Some(tag), None, "SORT completed").unwrap()).into(),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn base_subjects() {
+        assert_eq!(base_subject("Sortie au musée"), "sortie au musée");
+        assert_eq!(base_subject("RE: Re:  Sortie   au musée"), "sortie au musée");
+        assert_eq!(base_subject("Fwd: [3e B] TR : Sortie (fwd)"), "sortie");
+        assert_eq!(base_subject("Re[2]: Sortie (FWD) (fwd)"), "sortie");
+        assert_eq!(base_subject("[3e B] Re: Sortie"), "sortie");
+        // Un sujet qui n'est qu'un [blob] le garde
+        assert_eq!(base_subject("[3e B]"), "[3e b]");
+        assert_eq!(base_subject("Retour de sortie"), "retour de sortie");
+        assert_eq!(base_subject("Re:"), "");
+    }
+
+    #[test]
+    fn comparisons() {
+        let first = json!({
+            "id": 1,
+            "date": "2024-01-02 10:00:00",
+            "subject": "Re: Sortie",
+            "from": { "name": "Martin" },
+            "to": [{ "name": "Bernard", "to_cc_cci": "cc" }, { "name": "Dupont", "to_cc_cci": "to" }],
+        });
+        let second = json!({
+            "id": 2,
+            "date": "2024-01-01 10:00:00",
+            "subject": "sortie",
+            "from": { "name": "durand" },
+            "to": [{ "name": "Caron", "to_cc_cci": "to" }],
+        });
+        let sizes = HashMap::from([(1, 100), (2, 50)]);
+        let compare = |criterion| compare(criterion, &first, &second, &sizes);
+        assert_eq!(compare(SortCriterion::Date), Ordering::Greater);
+        assert_eq!(compare(SortCriterion::Arrival), Ordering::Greater);
+        // Sans tenir compte de la casse
+        assert_eq!(compare(SortCriterion::From), Ordering::Greater);
+        assert_eq!(compare(SortCriterion::To), Ordering::Greater);
+        // Un message sans copie passe avant
+        assert_eq!(compare(SortCriterion::Cc), Ordering::Greater);
+        assert_eq!(compare(SortCriterion::Subject), Ordering::Equal);
+        assert_eq!(compare(SortCriterion::Size), Ordering::Greater);
+    }
+}
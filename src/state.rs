@@ -0,0 +1,639 @@
+use imap_codec::imap_types::{bounded_static::IntoBoundedStatic, flag::Flag};
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::num::NonZeroU32;
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::api::MailboxId;
+use crate::auth::UserId;
+use crate::fetch;
+use crate::lock;
+
+// Les ids EcoleDirecte sont partagés entre les dossiers et ne bougent pas
+// quand un message change de dossier, donc on ne peut pas s'en servir comme
+// UIDs : on attribue nos propres UIDs, dossier par dossier, et on s'en
+// souvient d'une session à l'autre.
+struct MailboxUids {
+    uid_validity: u32,
+    uid_next: u32,
+    // id EcoleDirecte -> UID
+    uids: HashMap<u32, u32>,
+    // Journal des changements vus entre deux interrogations (CONDSTORE) :
+    // dernier MODSEQ, MODSEQ et drapeaux de chaque UID, UIDs disparus avec le
+    // MODSEQ de leur disparition
+    highest_modseq: u64,
+    modseqs: HashMap<u32, u64>,
+    flags: HashMap<u32, String>,
+    vanished: Vec<(u32, u64)>,
+    // Le journal des UIDs disparus ne remonte pas avant ce MODSEQ
+    vanished_since: u64,
+}
+
+// Au-delà, on oublie les plus anciens UIDs disparus
+const MAX_VANISHED: usize = 10000;
+
+impl MailboxUids {
+    fn new(previous_validity: Option<u32>) -> MailboxUids {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs() as u32)
+            .unwrap_or(1)
+            .max(1);
+        MailboxUids {
+            // Ne jamais redonner la même UIDVALIDITY à un dossier
+            uid_validity: match previous_validity {
+                Some(previous) if previous >= now => previous.wrapping_add(1).max(1),
+                _ => now,
+            },
+            uid_next: 1,
+            uids: HashMap::new(),
+            highest_modseq: 1,
+            modseqs: HashMap::new(),
+            flags: HashMap::new(),
+            vanished: Vec::new(),
+            vanished_since: 1,
+        }
+    }
+
+    fn from_json(value: &Value) -> Option<MailboxUids> {
+        let uid_validity = value["uidValidity"].as_u64()?.try_into().ok().filter(|v| *v > 0)?;
+        let uid_next: u32 = value["uidNext"].as_u64()?.try_into().ok().filter(|v| *v > 0)?;
+        let uids = value["uids"]
+            .as_object()?
+            .iter()
+            .map(|(id, uid)| Some((id.parse().ok()?, uid.as_u64()?.try_into().ok()?)))
+            .collect::<Option<HashMap<u32, u32>>>()?;
+        // Un UID au-delà de UIDNEXT voudrait dire que l'état est corrompu
+        if uids.values().any(|uid| *uid == 0 || *uid >= uid_next) {
+            return None;
+        }
+        // Le journal peut manquer (état d'une version précédente) : dans ce
+        // cas tous les messages seront vus comme changés
+        let highest_modseq = value["highestModseq"].as_u64().unwrap_or(1).max(1);
+        let modseqs = value["modseqs"]
+            .as_object()
+            .map(|modseqs| {
+                modseqs
+                    .iter()
+                    .filter_map(|(uid, modseq)| Some((uid.parse().ok()?, modseq.as_u64()?.min(highest_modseq))))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let flags = value["flags"]
+            .as_object()
+            .map(|flags| {
+                flags
+                    .iter()
+                    .filter_map(|(uid, flags)| Some((uid.parse().ok()?, flags.as_str()?.to_string())))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let vanished = value["vanished"]
+            .as_array()
+            .map(|vanished| {
+                vanished
+                    .iter()
+                    .filter_map(|entry| Some((entry[0].as_u64()?.try_into().ok()?, entry[1].as_u64()?)))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let vanished_since = value["vanishedSince"].as_u64().unwrap_or(highest_modseq);
+        Some(MailboxUids { uid_validity, uid_next, uids, highest_modseq, modseqs, flags, vanished, vanished_since })
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "uidValidity": self.uid_validity,
+            "uidNext": self.uid_next,
+            "uids": self.uids
+                .iter()
+                .map(|(id, uid)| (id.to_string(), json!(uid)))
+                .collect::<Map<_, _>>(),
+            "highestModseq": self.highest_modseq,
+            "modseqs": self.modseqs
+                .iter()
+                .map(|(uid, modseq)| (uid.to_string(), json!(modseq)))
+                .collect::<Map<_, _>>(),
+            "flags": self.flags
+                .iter()
+                .map(|(uid, flags)| (uid.to_string(), json!(flags)))
+                .collect::<Map<_, _>>(),
+            "vanished": self.vanished,
+            "vanishedSince": self.vanished_since,
+        })
+    }
+
+    // Enregistre les drapeaux actuels et les UIDs disparus, avec un nouveau
+    // MODSEQ s'il y a eu du changement
+    fn journal(&mut self, flags: HashMap<u32, String>) -> bool {
+        let vanished: Vec<u32> = self.flags.keys().filter(|uid| !flags.contains_key(uid)).copied().collect();
+        let changed: Vec<u32> = flags
+            .iter()
+            .filter(|(uid, flags)| self.flags.get(uid) != Some(flags))
+            .map(|(uid, _)| *uid)
+            .collect();
+        if vanished.is_empty() && changed.is_empty() {
+            return false;
+        }
+
+        self.highest_modseq += 1;
+        for uid in changed {
+            self.modseqs.insert(uid, self.highest_modseq);
+        }
+        for uid in vanished {
+            self.modseqs.remove(&uid);
+            self.vanished.push((uid, self.highest_modseq));
+        }
+        if self.vanished.len() > MAX_VANISHED {
+            let forgotten = self.vanished.len() - MAX_VANISHED;
+            self.vanished_since = self.vanished[forgotten - 1].1;
+            self.vanished.drain(..forgotten);
+        }
+        self.flags = flags;
+        true
+    }
+
+    fn next(&mut self) -> Option<u32> {
+        let uid = self.uid_next;
+        self.uid_next = self.uid_next.checked_add(1)?;
+        Some(uid)
+    }
+}
+
+/// État local d'un compte (UIDs attribués, abonnements, drapeaux),
+/// enregistré dans un fichier.
+pub struct AccountState {
+    mailboxes: HashMap<String, MailboxUids>,
+    // Tous les dossiers sont abonnés sauf ceux-là, comme ça les nouveaux
+    // classeurs sont abonnés automatiquement.
+    unsubscribed: HashSet<String>,
+    // Drapeaux qu'EcoleDirecte ne connaît pas (\Flagged et mots-clés), par id
+    // EcoleDirecte : ils suivent le message d'un dossier à l'autre
+    keywords: HashMap<u32, Vec<String>>,
+    writer: Sender<Write>,
+}
+
+// Ce qu'on demande au thread qui écrit le fichier d'un compte (voir `writer`)
+enum Write {
+    Save(String),
+    // Prévenir quand ce qui précède est écrit
+    Flush(Sender<()>),
+}
+
+// Le fichier est écrit sans tenir le verrou de l'état, pour ne pas bloquer
+// les connexions du compte. Un état déjà remplacé par un plus récent n'est
+// pas écrit.
+fn writer(path: PathBuf, requests: Receiver<Write>) {
+    while let Ok(request) = requests.recv() {
+        let mut latest = None;
+        let mut flushed = Vec::new();
+        for request in iter::once(request).chain(requests.try_iter()) {
+            match request {
+                Write::Save(state) => latest = Some(state),
+                Write::Flush(done) => flushed.push(done),
+            }
+        }
+        if let Some(state) = latest {
+            // On écrit à côté puis on renomme pour ne jamais laisser un fichier à moitié écrit
+            let temporary = path.with_extension("tmp");
+            let result = path
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| fs::write(&temporary, state))
+                .and_then(|_| fs::rename(&temporary, &path));
+            if let Err(error) = result {
+                tracing::error!(path = %path.display(), %error, "could not save account state");
+            }
+        }
+        for done in flushed {
+            let _ = done.send(());
+        }
+    }
+}
+
+// Attend que les états déjà envoyés à `writer` soient écrits
+fn flush(writer: &Sender<Write>) {
+    let (done, written) = mpsc::channel();
+    if writer.send(Write::Flush(done)).is_ok() {
+        let _ = written.recv();
+    }
+}
+
+// Drapeaux qui viennent d'EcoleDirecte (voir `fetch::flags`)
+const ECOLEDIRECTE_FLAGS: [&str; 3] = ["\\Seen", "\\Answered", "\\Draft"];
+
+fn flag_names(flags: &[Flag]) -> Vec<String> {
+    flags.iter().map(ToString::to_string).collect()
+}
+
+fn key(mailbox_id: &MailboxId) -> String {
+    match mailbox_id {
+        MailboxId::Received(0) => "received".to_string(),
+        MailboxId::Received(id) => format!("classeur/{id}"),
+        MailboxId::Sent => "sent".to_string(),
+        MailboxId::Draft => "draft".to_string(),
+        MailboxId::Archived => "archived".to_string(),
+        MailboxId::Deleted => "deleted".to_string(),
+    }
+}
+
+impl AccountState {
+    fn load(path: PathBuf) -> AccountState {
+        let state = fs::read(&path)
+            .ok()
+            .and_then(|data| serde_json::from_slice::<Value>(&data).ok())
+            .unwrap_or_default();
+        let mailboxes = state["mailboxes"]
+            .as_object()
+            .map(|mailboxes| {
+                mailboxes
+                    .iter()
+                    .filter_map(|(key, value)| Some((key.clone(), MailboxUids::from_json(value)?)))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let unsubscribed = state["unsubscribed"]
+            .as_array()
+            .map(|keys| keys.iter().filter_map(|key| Some(key.as_str()?.to_string())).collect())
+            .unwrap_or_default();
+        let keywords = state["keywords"]
+            .as_object()
+            .map(|keywords| {
+                keywords
+                    .iter()
+                    .filter_map(|(id, flags)| {
+                        let flags = flags.as_array()?.iter().filter_map(|flag| Some(flag.as_str()?.to_string())).collect();
+                        Some((id.parse().ok()?, flags))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let (writer, requests) = mpsc::channel();
+        thread::spawn(move || self::writer(path, requests));
+        AccountState { mailboxes, unsubscribed, keywords, writer }
+    }
+
+    fn save(&self) {
+        let state = json!({
+            "mailboxes": self.mailboxes
+                .iter()
+                .map(|(key, mailbox)| (key.clone(), mailbox.to_json()))
+                .collect::<Map<_, _>>(),
+            "unsubscribed": self.unsubscribed,
+            "keywords": self.keywords
+                .iter()
+                .map(|(id, flags)| (id.to_string(), json!(flags)))
+                .collect::<Map<_, _>>(),
+        });
+        // Le thread qui écrit ne s'arrête qu'avec l'état
+        let _ = self.writer.send(Write::Save(state.to_string()));
+    }
+
+    fn mailbox(&mut self, mailbox_id: &MailboxId) -> &mut MailboxUids {
+        self.mailboxes.entry(key(mailbox_id)).or_insert_with(|| MailboxUids::new(None))
+    }
+
+    pub fn uid_validity(&mut self, mailbox_id: &MailboxId) -> NonZeroU32 {
+        // unwrap: jamais 0 (voir MailboxUids::new et from_json)
+        NonZeroU32::new(self.mailbox(mailbox_id).uid_validity).unwrap()
+    }
+
+    pub fn uid_next(&mut self, mailbox_id: &MailboxId) -> NonZeroU32 {
+        NonZeroU32::new(self.mailbox(mailbox_id).uid_next).unwrap()
+    }
+
+    // Quand il n'y a plus d'UIDs disponibles, le dossier repart de zéro
+    // avec une nouvelle UIDVALIDITY.
+    fn reset(&mut self, mailbox_id: &MailboxId) {
+        let previous = self.mailbox(mailbox_id).uid_validity;
+        self.mailboxes.insert(key(mailbox_id), MailboxUids::new(Some(previous)));
+    }
+
+    /// Remplace les ids EcoleDirecte des messages d'un dossier par leurs UIDs
+    /// et les trie par UID croissant, en attribuant des UIDs aux nouveaux.
+    pub fn assign(&mut self, mailbox_id: &MailboxId, mut messages: Vec<(u32, Value)>) -> Vec<(u32, Value)> {
+        // Les nouveaux messages reçoivent leurs UIDs dans l'ordre des ids
+        messages.sort_by_key(|(id, _)| *id);
+
+        let present: HashSet<u32> = messages.iter().map(|(id, _)| *id).collect();
+        // Drapeaux pour le journal, avec ceux qu'on garde localement
+        let flags: HashMap<u32, String> = messages
+            .iter()
+            .map(|(id, message)| (*id, flag_names(&fetch::flags(message, self.keywords(*id))).join(" ")))
+            .collect();
+        let mailbox = self.mailbox(mailbox_id);
+        let before = (mailbox.uid_next, mailbox.uids.len());
+        mailbox.uids.retain(|id, _| present.contains(id));
+        for (id, _) in &messages {
+            if !mailbox.uids.contains_key(id) {
+                match mailbox.next() {
+                    Some(uid) => {
+                        mailbox.uids.insert(*id, uid);
+                    }
+                    None => {
+                        self.reset(mailbox_id);
+                        return self.assign(mailbox_id, messages);
+                    }
+                }
+            }
+        }
+        let mut changed = before != (mailbox.uid_next, mailbox.uids.len());
+
+        let mut messages: Vec<(u32, Value)> = messages
+            .into_iter()
+            .map(|(id, message)| (mailbox.uids[&id], message))
+            .collect();
+        messages.sort_by_key(|(uid, _)| *uid);
+        let flags = flags.into_iter().map(|(id, flags)| (mailbox.uids[&id], flags)).collect();
+        changed |= mailbox.journal(flags);
+        if changed {
+            self.save();
+        }
+        messages
+    }
+
+    /// Attribue de nouveaux UIDs à des messages qu'on vient de mettre dans
+    /// un dossier (pour COPYUID), dans l'ordre donné.
+    pub fn reserve(&mut self, mailbox_id: &MailboxId, message_ids: &[u32]) -> Vec<u32> {
+        let mailbox = self.mailbox(mailbox_id);
+        if mailbox.uid_next.checked_add(message_ids.len() as u32).is_none() {
+            self.reset(mailbox_id);
+        }
+        let mailbox = self.mailbox(mailbox_id);
+        let uids = message_ids
+            .iter()
+            .map(|id| {
+                // unwrap: on a vérifié qu'il restait assez d'UIDs
+                let uid = mailbox.next().unwrap();
+                mailbox.uids.insert(*id, uid);
+                uid
+            })
+            .collect();
+        self.save();
+        uids
+    }
+}
+
+impl AccountState {
+    pub fn highest_modseq(&mut self, mailbox_id: &MailboxId) -> u64 {
+        self.mailbox(mailbox_id).highest_modseq
+    }
+
+    pub fn modseq(&mut self, mailbox_id: &MailboxId, uid: u32) -> u64 {
+        let mailbox = self.mailbox(mailbox_id);
+        mailbox.modseqs.get(&uid).copied().unwrap_or(mailbox.highest_modseq)
+    }
+
+    /// UIDs qui ont disparu depuis `modseq` (pour VANISHED). Si le journal ne
+    /// remonte pas assez loin, tous les UIDs qui ne sont plus là.
+    pub fn vanished(&mut self, mailbox_id: &MailboxId, modseq: u64) -> Vec<u32> {
+        let mailbox = self.mailbox(mailbox_id);
+        let mut vanished: Vec<u32> = if modseq.saturating_add(1) < mailbox.vanished_since {
+            let present: HashSet<&u32> = mailbox.uids.values().collect();
+            (1..mailbox.uid_next).filter(|uid| !present.contains(uid)).collect()
+        } else {
+            mailbox.vanished.iter().filter(|(_, vanished)| *vanished > modseq).map(|(uid, _)| *uid).collect()
+        };
+        vanished.sort();
+        vanished.dedup();
+        vanished
+    }
+
+    /// \Flagged et mots-clés d'un message.
+    pub fn keywords(&self, message_id: u32) -> Vec<Flag<'static>> {
+        self.keywords
+            .get(&message_id)
+            .map(|flags| flags.iter().filter_map(|flag| Some(Flag::try_from(flag.as_str()).ok()?.into_static())).collect())
+            .unwrap_or_default()
+    }
+
+    /// Mots-clés utilisés dans le compte, pour FLAGS.
+    pub fn keyword_names(&self) -> Vec<Flag<'static>> {
+        let mut names: Vec<&String> = self.keywords.values().flatten().filter(|flag| !flag.starts_with('\\')).collect();
+        names.sort();
+        names.dedup();
+        names.into_iter().filter_map(|flag| Some(Flag::try_from(flag.as_str()).ok()?.into_static())).collect()
+    }
+
+    /// Remplace les drapeaux locaux de messages du dossier (UID, id
+    /// EcoleDirecte, drapeaux). Ceux qui changent ont un nouveau MODSEQ.
+    pub fn set_keywords(&mut self, mailbox_id: &MailboxId, changes: &[(u32, u32, Vec<Flag>)]) {
+        let mut changed = Vec::new();
+        for (uid, message_id, flags) in changes {
+            let flags = flag_names(flags);
+            let previous = if flags.is_empty() {
+                self.keywords.remove(message_id)
+            } else {
+                self.keywords.insert(*message_id, flags.clone())
+            };
+            if previous.unwrap_or_default() != flags {
+                changed.push((*uid, flags));
+            }
+        }
+        if changed.is_empty() {
+            return;
+        }
+
+        let mailbox = self.mailbox(mailbox_id);
+        mailbox.highest_modseq += 1;
+        for (uid, flags) in changed {
+            mailbox.modseqs.insert(uid, mailbox.highest_modseq);
+            // Le journal garde les drapeaux d'EcoleDirecte, suivis des nouveaux
+            if let Some(journal) = mailbox.flags.get_mut(&uid) {
+                let mut names: Vec<String> = journal
+                    .split(' ')
+                    .filter(|flag| ECOLEDIRECTE_FLAGS.contains(flag))
+                    .map(str::to_string)
+                    .collect();
+                names.extend(flags);
+                *journal = names.join(" ");
+            }
+        }
+        self.save();
+    }
+
+    /// Nouveau MODSEQ pour des messages dont un drapeau qui n'est pas dans le
+    /// journal a changé (\Deleted, gardé dans la session).
+    pub fn touch(&mut self, mailbox_id: &MailboxId, uids: &[u32]) {
+        if uids.is_empty() {
+            return;
+        }
+        let mailbox = self.mailbox(mailbox_id);
+        mailbox.highest_modseq += 1;
+        for uid in uids {
+            mailbox.modseqs.insert(*uid, mailbox.highest_modseq);
+        }
+        self.save();
+    }
+
+    pub fn is_subscribed(&self, mailbox_id: &MailboxId) -> bool {
+        !self.unsubscribed.contains(&key(mailbox_id))
+    }
+
+    pub fn set_subscribed(&mut self, mailbox_id: &MailboxId, subscribed: bool) {
+        let changed = if subscribed {
+            self.unsubscribed.remove(&key(mailbox_id))
+        } else {
+            self.unsubscribed.insert(key(mailbox_id))
+        };
+        if changed {
+            self.save();
+        }
+    }
+}
+
+pub fn default_dir() -> PathBuf {
+    match (env::var_os("XDG_STATE_HOME"), env::var_os("HOME")) {
+        (Some(state), _) => PathBuf::from(state).join("ecoledirecte-imap"),
+        (None, Some(home)) => PathBuf::from(home).join(".local/state/ecoledirecte-imap"),
+        (None, None) => PathBuf::from("state"),
+    }
+}
+
+static STATES: OnceLock<Mutex<HashMap<PathBuf, Arc<Mutex<AccountState>>>>> = OnceLock::new();
+
+/// Toutes les connexions d'un même compte partagent le même état.
+pub fn open(dir: &Path, user_id: UserId) -> Arc<Mutex<AccountState>> {
+    let path = dir.join(match user_id {
+        UserId::Eleve(id) => format!("eleve-{id}.json"),
+        UserId::Famille(id) => format!("famille-{id}.json"),
+    });
+    lock(STATES.get_or_init(Default::default))
+        .entry(path.clone())
+        .or_insert_with(|| Arc::new(Mutex::new(AccountState::load(path))))
+        .clone()
+}
+
+/// Attend que l'état de tous les comptes soit écrit, avant de s'arrêter.
+pub fn flush_all() {
+    let writers: Vec<Sender<Write>> = lock(STATES.get_or_init(Default::default))
+        .values()
+        .map(|state| lock(state).writer.clone())
+        .collect();
+    for writer in &writers {
+        flush(writer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn uids_follow_ecoledirecte_ids() {
+        let path = env::temp_dir().join(format!("ecoledirecte-imap-test-{}.json", process::id()));
+        let mut state = AccountState::load(path.clone());
+        let inbox = MailboxId::Received(0);
+        let message = |id: u32| (id, json!({ "id": id, "read": false, "answered": false, "brouillon": false }));
+        let assigned = |messages: Vec<(u32, Value)>| -> Vec<(u32, u64)> {
+            messages.iter().map(|(uid, message)| (*uid, message["id"].as_u64().unwrap())).collect()
+        };
+
+        // Les nouveaux messages reçoivent leurs UIDs dans l'ordre des ids
+        assert_eq!(assigned(state.assign(&inbox, vec![message(30), message(10)])), [(1, 10), (2, 30)]);
+        assert_eq!(assigned(state.assign(&inbox, vec![message(40), message(10)])), [(1, 10), (3, 40)]);
+        // Un message qui revient a un nouvel UID
+        assert_eq!(assigned(state.assign(&inbox, vec![message(30), message(10)])), [(1, 10), (4, 30)]);
+        assert_eq!(state.uid_next(&inbox).get(), 5);
+        // Chaque dossier a ses UIDs
+        assert_eq!(assigned(state.assign(&MailboxId::Sent, vec![message(30)])), [(1, 30)]);
+
+        flush(&state.writer);
+        let _ = fs::remove_file(path);
+    }
+    #[test]
+    fn vanished_uids() {
+        let path = env::temp_dir().join(format!("ecoledirecte-imap-test-vanished-{}.json", process::id()));
+        let mut state = AccountState::load(path.clone());
+        let inbox = MailboxId::Received(0);
+        let message = |id: u32| (id, json!({ "id": id, "read": false, "answered": false, "brouillon": false }));
+
+        state.assign(&inbox, vec![message(10), message(20)]);
+        let modseq = state.highest_modseq(&inbox);
+        state.assign(&inbox, vec![message(10)]);
+        assert_eq!(state.vanished(&inbox, modseq), [2]);
+        let highest_modseq = state.highest_modseq(&inbox);
+        assert_eq!(state.vanished(&inbox, highest_modseq), [] as [u32; 0]);
+        // Un MODSEQ du client plus grand que tous les nôtres
+        assert_eq!(state.vanished(&inbox, u64::MAX), [] as [u32; 0]);
+
+        flush(&state.writer);
+        let _ = fs::remove_file(path);
+    }
+    #[test]
+    fn saved_state_is_reloaded() {
+        let path = env::temp_dir().join(format!("ecoledirecte-imap-test-saved-{}.json", process::id()));
+        let inbox = MailboxId::Received(0);
+        let message = |id: u32| (id, json!({ "id": id, "read": false, "answered": false, "brouillon": false }));
+
+        let mut state = AccountState::load(path.clone());
+        state.assign(&inbox, vec![message(10), message(20)]);
+        state.set_subscribed(&MailboxId::Sent, false);
+        flush(&state.writer);
+
+        // Le fichier est écrit par un autre thread, mais tout y est
+        let mut reloaded = AccountState::load(path.clone());
+        assert_eq!(reloaded.uid_next(&inbox).get(), 3);
+        assert!(!reloaded.is_subscribed(&MailboxId::Sent));
+        assert!(!path.with_extension("tmp").exists());
+
+        flush(&reloaded.writer);
+        let _ = fs::remove_file(path);
+    }
+    #[test]
+    fn subscriptions() {
+        let path = env::temp_dir().join(format!("ecoledirecte-imap-test-subscriptions-{}.json", process::id()));
+        let mut state = AccountState::load(path.clone());
+
+        // Tous les dossiers sont abonnés au départ, même ceux qu'on n'a jamais vus
+        assert!(state.is_subscribed(&MailboxId::Received(12)));
+        state.set_subscribed(&MailboxId::Received(12), false);
+        assert!(!state.is_subscribed(&MailboxId::Received(12)));
+        assert!(state.is_subscribed(&MailboxId::Received(0)));
+        state.set_subscribed(&MailboxId::Received(12), true);
+        assert!(state.is_subscribed(&MailboxId::Received(12)));
+
+        flush(&state.writer);
+        let _ = fs::remove_file(path);
+    }
+    #[test]
+    fn keywords_and_modseqs() {
+        let path = env::temp_dir().join(format!("ecoledirecte-imap-test-keywords-{}.json", process::id()));
+        let mut state = AccountState::load(path.clone());
+        let inbox = MailboxId::Received(0);
+        let message = |id: u32| (id, json!({ "id": id, "read": false, "answered": false, "brouillon": false }));
+        let flags = |flags: &[&'static str]| -> Vec<Flag<'static>> { flags.iter().map(|flag| Flag::try_from(*flag).unwrap()).collect() };
+
+        state.assign(&inbox, vec![message(10), message(20)]);
+        let modseq = state.highest_modseq(&inbox);
+        state.set_keywords(&inbox, &[(1, 10, flags(&["\\Flagged", "$Projet"])), (2, 20, flags(&["$Important"]))]);
+        assert_eq!(state.keywords(10), flags(&["\\Flagged", "$Projet"]));
+        // \Flagged n'est pas un mot-clé
+        assert_eq!(state.keyword_names(), flags(&["$Important", "$Projet"]));
+        let changed = state.highest_modseq(&inbox);
+        assert!(changed > modseq);
+        assert_eq!((state.modseq(&inbox, 1), state.modseq(&inbox, 2)), (changed, changed));
+
+        // Rien ne change : pas de nouveau MODSEQ
+        state.set_keywords(&inbox, &[(2, 20, flags(&["$Important"]))]);
+        assert_eq!(state.highest_modseq(&inbox), changed);
+        state.set_keywords(&inbox, &[(2, 20, vec![])]);
+        assert_eq!(state.modseq(&inbox, 2), changed + 1);
+        assert_eq!(state.modseq(&inbox, 1), changed);
+        assert_eq!(state.keyword_names(), flags(&["$Projet"]));
+
+        // Les drapeaux suivent le message dans un autre dossier
+        state.assign(&MailboxId::Archived, vec![message(10)]);
+        assert_eq!(state.keywords(10), flags(&["\\Flagged", "$Projet"]));
+
+        flush(&state.writer);
+        let _ = fs::remove_file(path);
+    }
+}
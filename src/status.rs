@@ -2,25 +2,36 @@ use imap_codec::imap_types::{
     core::Tag,
     mailbox::Mailbox,
     response::{Data, Response, Status},
-    status::StatusDataItem,
+    status::{StatusDataItem, StatusDataItemName},
 };
 use crate::api::MailboxId;
 use serde_json::Value;
+use std::num::NonZeroU32;
 
-pub fn handle<'a>(tag: Tag<'a>, mailbox: Mailbox<'a>, mailbox_id: &MailboxId, folder: Value) -> Vec<Response<'a>> {
+pub fn handle<'a>(tag: Tag<'a>, mailbox: Mailbox<'a>, item_names: &[StatusDataItemName], mailbox_id: &MailboxId, folder: Value, uid_validity: NonZeroU32, uid_next: NonZeroU32) -> Vec<Response<'a>> {
     let (existing_messages_count, unseen_messages_count) = match mailbox_id {
         MailboxId::Received(_) => (&folder["pagination"]["messagesRecusCount"], folder["pagination"]["messagesRecusNotReadCount"].as_u64()),
         MailboxId::Sent => (&folder["pagination"]["messagesEnvoyesCount"], None),
         MailboxId::Draft => (&folder["pagination"]["messagesDraftCount"], None),
         MailboxId::Archived => (&folder["pagination"]["messagesArchivesCount"], None),
+        MailboxId::Deleted => (&folder["pagination"]["messagesSupprimesCount"], None),
     };
     let existing_messages_count = existing_messages_count.as_u64().unwrap() as u32;
 
+    let items: Vec<StatusDataItem> = item_names
+        .iter()
+        .filter_map(|item_name| match item_name {
+            StatusDataItemName::Messages => Some(StatusDataItem::Messages(existing_messages_count)),
+            StatusDataItemName::Recent => Some(StatusDataItem::Recent(0)),
+            StatusDataItemName::UidNext => Some(StatusDataItem::UidNext(uid_next)),
+            StatusDataItemName::UidValidity => Some(StatusDataItem::UidValidity(uid_validity)),
+            StatusDataItemName::Unseen => Some(StatusDataItem::Unseen(unseen_messages_count.unwrap_or(0) as u32)),
+            _ => None,
+        })
+        .collect();
+
     vec![
-        Response::Data(Data::Status { mailbox: mailbox, items: vec![
-            StatusDataItem::Messages(existing_messages_count),
-            StatusDataItem::Unseen(unseen_messages_count.unwrap_or(0) as u32),
-        ].into() }),
+        Response::Data(Data::Status { mailbox, items: items.into() }),
         Response::Status(Status::ok(Some(tag), None, "STATUS completed").unwrap()),
     ]
 }
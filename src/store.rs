@@ -1,46 +1,282 @@
 use imap_codec::imap_types::{
-    core::Tag,
-    flag::{Flag, StoreResponse, StoreType},
-    response::{Response, Status},
-    sequence::{Sequence, SequenceSet, SeqOrUid},
+    bounded_static::IntoBoundedStatic,
+    core::{NonEmptyVec, Tag},
+    fetch::MessageDataItem,
+    flag::{Flag, FlagFetch, StoreResponse, StoreType},
+    response::{Code, CodeOther, Data, Response, Status},
+    sequence::SequenceSet,
 };
+use serde_json::Value;
+use std::collections::HashSet;
+use std::num::NonZeroU32;
+use crate::fetch;
 
-pub fn handle<'a, F: Fn(&Vec<u32>, bool) -> ()>(tag: Tag<'a>, sequence_set: SequenceSet, kind: StoreType, response: StoreResponse, flags: Vec<Flag<'a>>, uid: bool, set_read_status: F) -> Vec<Response<'a>> {
-    if !uid {
-        vec![
-            Response::Status(
-                Status::no(Some(tag), None, "STORE Not supported (no UID)!").unwrap())
-        ]
-    } else if kind != StoreType::Add && kind != StoreType::Remove {
-        vec![
-            Response::Status(
-                Status::no(Some(tag), None, "STORE Not supported (bad store type)!").unwrap())
-        ]
-    } else if flags != vec![Flag::Seen] {
+// Drapeaux qu'EcoleDirecte gère lui-même : on les montre mais on ne peut ni
+// les mettre ni les enlever
+const READ_ONLY_FLAGS: [Flag<'static>; 2] = [Flag::Answered, Flag::Draft];
+
+fn cannot<'a>(tag: Tag<'a>, text: String) -> Vec<Response<'a>> {
+    vec![Response::Status(
+        Status::no(Some(tag), Some(Code::Other(CodeOther::unvalidated(&b"CANNOT"[..]))), text).unwrap(),
+    )]
+}
+
+fn flag_list(flags: &[&Flag]) -> String {
+    flags.iter().map(ToString::to_string).collect::<Vec<_>>().join(" ")
+}
+
+// Drapeaux gardés localement (voir `state`)
+fn is_keyword(flag: &Flag) -> bool {
+    matches!(flag, Flag::Flagged | Flag::Keyword(_))
+}
+
+// Drapeaux locaux d'un message après STORE
+fn new_keywords(kind: &StoreType, current: Vec<Flag<'static>>, requested: &[&Flag]) -> Vec<Flag<'static>> {
+    let mut keywords = match kind {
+        StoreType::Add => current,
+        StoreType::Remove => {
+            return current
+                .into_iter()
+                .filter(|keyword| !requested.iter().any(|flag| fetch::same_flag(keyword, flag)))
+                .collect();
+        }
+        StoreType::Replace => Vec::new(),
+    };
+    for flag in requested {
+        if !keywords.iter().any(|keyword| fetch::same_flag(keyword, flag)) {
+            keywords.push((*flag).clone().into_static());
+        }
+    }
+    keywords
+}
+
+/// STORE et UID STORE : \Seen est changé sur EcoleDirecte, \Deleted dans la
+/// session (jusqu'à EXPUNGE), \Flagged et les mots-clés dans l'état du
+/// compte. `messages` est la liste que connaît le client.
+#[allow(clippy::too_many_arguments)]
+pub fn handle<'a, F, G, K, H>(tag: Tag<'a>, sequence_set: SequenceSet, kind: StoreType, response: StoreResponse, flags: Vec<Flag<'a>>, uid: bool, messages: Vec<(u32, Value)>, set_read_status: F, mut set_deleted: G, mut set_keywords: K, local_flags: H) -> Vec<Response<'a>>
+where
+    F: Fn(&[u32], bool) -> Result<(), Vec<(u32, Option<String>)>>,
+    G: FnMut(&[u32], bool),
+    K: FnMut(Vec<(u32, Vec<Flag<'static>>)>),
+    H: Fn(u32) -> Vec<Flag<'static>>,
+{
+    // Rien n'est changé si un des drapeaux ne peut pas l'être
+    let unsupported: Vec<&Flag> = flags
+        .iter()
+        .filter(|flag| !matches!(flag, Flag::Seen | Flag::Deleted) && !is_keyword(flag) && !READ_ONLY_FLAGS.contains(flag))
+        .collect();
+    if !unsupported.is_empty() {
+        return cannot(tag, format!("Cannot store {}", flag_list(&unsupported)));
+    }
+    // Avec FLAGS, ceux d'EcoleDirecte restent ce qu'ils sont
+    let read_only: Vec<&Flag> = flags.iter().filter(|flag| READ_ONLY_FLAGS.contains(flag)).collect();
+    if kind != StoreType::Replace && !read_only.is_empty() {
+        return cannot(tag, format!("Only EcoleDirecte can change {}", flag_list(&read_only)));
+    }
+
+    // Valeur voulue pour \Seen et \Deleted (None : inchangé)
+    let wanted = |flag: &Flag| match kind {
+        StoreType::Add => flags.contains(flag).then_some(true),
+        StoreType::Remove => flags.contains(flag).then_some(false),
+        StoreType::Replace => Some(flags.contains(flag)),
+    };
+    let seen = wanted(&Flag::Seen);
+    let deleted = wanted(&Flag::Deleted);
+    let keywords: Vec<&Flag> = flags.iter().filter(|flag| is_keyword(flag)).collect();
+
+    let positions = fetch::positions(&sequence_set, uid, &messages);
+    let is_read = |pos: &usize| messages[*pos].1["read"].as_bool().unwrap();
+
+    // Seulement les messages qui changent, pour ne pas solliciter EcoleDirecte
+    // pour rien. On garde ceux qui n'ont pas changé, avec la raison.
+    let mut failures: Vec<(u32, Option<String>)> = Vec::new();
+    if let Some(seen) = seen {
+        let uids: Vec<u32> = positions.iter().filter(|pos| is_read(pos) != seen).map(|&pos| messages[pos].0).collect();
+        if !uids.is_empty() {
+            if let Err(failed) = set_read_status(&uids, seen) {
+                failures = failed;
+            }
+        }
+    }
+    // Les messages dont \Seen n'a pas pu changer gardent tous leurs drapeaux
+    let failed: HashSet<u32> = failures.iter().map(|(uid, _)| *uid).collect();
+    let changed: Vec<usize> = positions.iter().copied().filter(|&pos| !failed.contains(&messages[pos].0)).collect();
+    if let Some(deleted) = deleted {
+        let uids: Vec<u32> = changed.iter().map(|&pos| messages[pos].0).collect();
+        set_deleted(&uids, deleted);
+    }
+    if !keywords.is_empty() || kind == StoreType::Replace {
+        let changes = changed
+            .iter()
+            .map(|&pos| {
+                let message_uid = messages[pos].0;
+                let current = local_flags(message_uid).into_iter().filter(is_keyword).collect();
+                (message_uid, new_keywords(&kind, current, &keywords))
+            })
+            .collect();
+        set_keywords(changes);
+    }
+
+    let mut responses = match response {
+        StoreResponse::Silent => vec![],
+        StoreResponse::Answer => positions
+            .iter()
+            .map(|&pos| {
+                let (message_uid, message) = &messages[pos];
+                let mut message = message.clone();
+                if let (Some(seen), false) = (seen, failed.contains(message_uid)) {
+                    message["read"] = Value::Bool(seen);
+                }
+                let mut items = vec![MessageDataItem::Flags(
+                    fetch::flags(&message, local_flags(*message_uid)).into_iter().map(FlagFetch::Flag).collect(),
+                )];
+                if uid {
+                    items.push(MessageDataItem::Uid(NonZeroU32::new(*message_uid).unwrap()));
+                }
+                Response::Data(Data::Fetch {
+                    seq: NonZeroU32::new((pos + 1) as u32).unwrap(),
+                    items: NonEmptyVec::try_from(items).unwrap(),
+                })
+            })
+            .collect(),
+    };
+    if failures.is_empty() {
+        responses.push(Response::Status(Status::ok(Some(tag), None, "STORE completed").unwrap()));
+    } else {
+        let mut uids: Vec<u32> = failed.into_iter().collect();
+        uids.sort();
+        let mut reasons: Vec<String> = failures.into_iter().filter_map(|(_, reason)| reason).collect();
+        reasons.sort();
+        reasons.dedup();
+        let mut text = format!("STORE failed: flags unchanged for UID {}", fetch::sequence_set(&uids));
+        if !reasons.is_empty() {
+            text += &format!(" ({})", reasons.join(", "));
+        }
+        responses.push(Response::Status(Status::no(Some(tag), None, text).unwrap()));
+    }
+    responses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use imap_codec::{encode::Encoder, ResponseCodec};
+    use serde_json::json;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    // UIDs 3 (non lu) et 5 (lu, répondu)
+    fn messages() -> Vec<(u32, Value)> {
         vec![
-            Response::Status(
-                Status::no(Some(tag), None, "STORE Not supported (bad flags)!").unwrap())
+            (3, json!({ "id": 30, "read": false, "answered": false, "brouillon": false })),
+            (5, json!({ "id": 50, "read": true, "answered": true, "brouillon": false })),
         ]
-    } else {
-        let message_ids = sequence_set.0
-            .into_iter()
-            .map(|sequence| match sequence {
-                Sequence::Single(a) => match a {
-                    SeqOrUid::Value(value) => value.into(),
-                    SeqOrUid::Asterisk => panic!("STORE: invalid sequence range"),
+    }
+
+    fn flags(flags: &[&'static str]) -> Vec<Flag<'static>> {
+        flags.iter().map(|flag| Flag::try_from(*flag).unwrap()).collect()
+    }
+
+    // Drapeaux locaux (\Deleted et mots-clés) et \Seen changés sur
+    // EcoleDirecte, avec les UIDs qu'EcoleDirecte refuse
+    #[derive(Default)]
+    struct Account {
+        deleted: RefCell<HashSet<u32>>,
+        keywords: RefCell<HashMap<u32, Vec<Flag<'static>>>>,
+        read_status: RefCell<Vec<(Vec<u32>, bool)>>,
+        refused: Vec<u32>,
+    }
+
+    impl Account {
+        fn store(&self, sequence_set: &str, kind: StoreType, names: &[&'static str]) -> Vec<String> {
+            let responses = handle(
+                Tag::try_from("a").unwrap(),
+                SequenceSet::try_from(sequence_set).unwrap(),
+                kind,
+                StoreResponse::Answer,
+                flags(names),
+                true,
+                messages(),
+                |uids, seen| {
+                    self.read_status.borrow_mut().push((uids.to_vec(), seen));
+                    let refused: Vec<(u32, Option<String>)> = uids
+                        .iter()
+                        .filter(|uid| self.refused.contains(uid))
+                        .map(|uid| (*uid, Some(String::from("Refus"))))
+                        .collect();
+                    if refused.is_empty() { Ok(()) } else { Err(refused) }
                 },
-                Sequence::Range(_, _) => todo!(),
-            })
-            .collect::<Vec<_>>();
-        set_read_status(&message_ids, kind == StoreType::Add);
-        let mut responses = match response {
-            StoreResponse::Silent => vec![],
-            StoreResponse::Answer => message_ids
+                |uids, deleted| {
+                    let mut current = self.deleted.borrow_mut();
+                    for uid in uids {
+                        if deleted { current.insert(*uid) } else { current.remove(uid) };
+                    }
+                },
+                |changes| self.keywords.borrow_mut().extend(changes),
+                |uid| {
+                    let mut local = if self.deleted.borrow().contains(&uid) { vec![Flag::Deleted] } else { vec![] };
+                    local.extend(self.keywords.borrow().get(&uid).cloned().unwrap_or_default());
+                    local
+                },
+            );
+            responses
                 .iter()
-                .map(|_message_id| todo!())
-                .collect(),
-        };
-        responses.push(Response::Status(Status::ok(Some(tag), None, "STORE completed").unwrap()));
-        responses
+                .map(|response| String::from_utf8(ResponseCodec::default().encode(response).dump()).unwrap())
+                .collect()
+        }
+    }
+
+    #[test]
+    fn added_and_replaced_flags() {
+        let account = Account::default();
+        assert_eq!(
+            account.store("1:*", StoreType::Add, &["\\Seen", "\\Flagged", "$Important"]),
+            [
+                "* 1 FETCH (FLAGS (\\Seen \\Flagged $Important) UID 3)\r\n",
+                "* 2 FETCH (FLAGS (\\Seen \\Answered \\Flagged $Important) UID 5)\r\n",
+                "a OK STORE completed\r\n",
+            ]
+        );
+        // Seul le message qui n'était pas lu change sur EcoleDirecte
+        assert_eq!(*account.read_status.borrow(), [(vec![3], true)]);
+
+        // FLAGS garde \Answered (EcoleDirecte) et remplace le reste
+        assert_eq!(
+            account.store("5", StoreType::Replace, &["\\Answered", "\\Deleted", "$important"]),
+            ["* 2 FETCH (FLAGS (\\Answered \\Deleted $important) UID 5)\r\n", "a OK STORE completed\r\n"]
+        );
+        assert_eq!(account.read_status.borrow()[1], (vec![5], false));
+        assert_eq!(*account.deleted.borrow(), HashSet::from([5]));
+
+        assert_eq!(
+            account.store("3", StoreType::Remove, &["\\FLAGGED", "$IMPORTANT"]),
+            ["* 1 FETCH (FLAGS () UID 3)\r\n", "a OK STORE completed\r\n"]
+        );
+    }
+
+    #[test]
+    fn flags_that_cannot_change() {
+        let account = Account::default();
+        assert_eq!(account.store("1:*", StoreType::Add, &["\\Seen", "\\Foo"]), ["a NO [CANNOT] Cannot store \\Foo\r\n"]);
+        assert_eq!(
+            account.store("1:*", StoreType::Remove, &["\\Draft", "\\Answered"]),
+            ["a NO [CANNOT] Only EcoleDirecte can change \\Draft \\Answered\r\n"]
+        );
+        assert!(account.read_status.borrow().is_empty());
+    }
+
+    #[test]
+    fn refused_messages_keep_their_flags() {
+        let account = Account { refused: vec![3], ..Account::default() };
+        assert_eq!(
+            account.store("1:*", StoreType::Replace, &["\\Seen", "\\Flagged"]),
+            [
+                "* 1 FETCH (FLAGS () UID 3)\r\n",
+                "* 2 FETCH (FLAGS (\\Seen \\Answered \\Flagged) UID 5)\r\n",
+                "a NO STORE failed: flags unchanged for UID 3 (Refus)\r\n",
+            ]
+        );
+        assert_eq!(*account.keywords.borrow(), HashMap::from([(5, flags(&["\\Flagged"]))]));
     }
 }
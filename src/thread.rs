@@ -0,0 +1,271 @@
+use imap_codec::imap_types::{
+    core::{Charset, Tag},
+    flag::Flag,
+    response::{Response, Status},
+    search::SearchKey,
+};
+use serde_json::Value;
+use std::collections::HashMap;
+use crate::command::ThreadAlgorithm;
+use crate::search;
+use crate::sort::base_subject;
+use crate::Reply;
+
+// Un message de la recherche (sa position dans le dossier), ou un message
+// qui n'y est pas mais auquel plusieurs messages répondent
+struct Node {
+    pos: Option<usize>,
+    children: Vec<usize>,
+}
+
+struct Tree<'m> {
+    messages: &'m [(u32, Value)],
+    nodes: Vec<Node>,
+}
+
+impl Tree<'_> {
+    fn add(&mut self, pos: Option<usize>) -> usize {
+        self.nodes.push(Node { pos, children: Vec::new() });
+        self.nodes.len() - 1
+    }
+
+    // Un message absent a la date de sa première réponse
+    fn date(&self, node: usize) -> &str {
+        match self.nodes[node].pos {
+            Some(pos) => self.messages[pos].1["date"].as_str().unwrap_or_default(),
+            None => self.nodes[node].children.iter().map(|&child| self.date(child)).min().unwrap_or_default(),
+        }
+    }
+
+    fn subject(&self, node: usize) -> String {
+        match self.nodes[node].pos {
+            Some(pos) => base_subject(self.messages[pos].1["subject"].as_str().unwrap_or_default()),
+            None => self.nodes[node].children.first().map(|&child| self.subject(child)).unwrap_or_default(),
+        }
+    }
+
+    // Par date puis par numéro de séquence, comme le veut la RFC
+    fn sort(&self, nodes: &mut [usize]) {
+        nodes.sort_by(|&left, &right| {
+            self.date(left)
+                .cmp(self.date(right))
+                .then(self.nodes[left].pos.cmp(&self.nodes[right].pos))
+        });
+    }
+
+    fn format<F: Fn(usize) -> u32>(&self, node: usize, id: &F) -> String {
+        let mut members = Vec::new();
+        if let Some(pos) = self.nodes[node].pos {
+            members.push(id(pos).to_string());
+        }
+        match self.nodes[node].children.as_slice() {
+            [] => (),
+            [child] if self.nodes[node].pos.is_some() => members.push(self.format(*child, id)),
+            children => members.push(children.iter().map(|&child| format!("({})", self.format(child, id))).collect()),
+        }
+        members.join(" ")
+    }
+}
+
+// Le premier message de chaque sujet est le parent de tous les autres
+fn ordered_subject(tree: &mut Tree, mut found: Vec<usize>) -> Vec<usize> {
+    found.sort_by(|&left, &right| {
+        tree.messages[left].1["date"].as_str().cmp(&tree.messages[right].1["date"].as_str()).then(left.cmp(&right))
+    });
+    let mut roots: Vec<usize> = Vec::new();
+    let mut by_subject: HashMap<String, usize> = HashMap::new();
+    for pos in found {
+        let node = tree.add(Some(pos));
+        match by_subject.get(&tree.subject(node)) {
+            Some(&root) => tree.nodes[root].children.push(node),
+            None => {
+                by_subject.insert(tree.subject(node), node);
+                roots.push(node);
+            }
+        }
+    }
+    roots
+}
+
+// EcoleDirecte donne directement le message auquel on répond (responseId) ou
+// qu'on transfère (forwardId) : pas besoin de lire les en-têtes References
+fn references(tree: &mut Tree, found: Vec<usize>) -> Vec<usize> {
+    let nodes: HashMap<u64, usize> = found
+        .iter()
+        .map(|&pos| (tree.messages[pos].1["id"].as_u64().unwrap(), tree.add(Some(pos))))
+        .collect();
+    let mut missing: HashMap<u64, usize> = HashMap::new();
+    let mut roots = Vec::new();
+
+    for &node in nodes.values() {
+        let message = &tree.messages[tree.nodes[node].pos.unwrap()].1;
+        let parent_id = [&message["responseId"], &message["forwardId"]]
+            .iter()
+            .filter_map(|id| id.as_u64())
+            .find(|id| *id > 0 && Some(*id) != message["id"].as_u64());
+        let parent = match parent_id {
+            Some(parent_id) => match nodes.get(&parent_id) {
+                Some(&parent) => Some(parent),
+                None => Some(*missing.entry(parent_id).or_insert_with(|| {
+                    tree.nodes.push(Node { pos: None, children: Vec::new() });
+                    roots.push(tree.nodes.len() - 1);
+                    tree.nodes.len() - 1
+                })),
+            },
+            None => None,
+        };
+        match parent {
+            Some(parent) => tree.nodes[parent].children.push(node),
+            None => roots.push(node),
+        }
+    }
+
+    // Les boucles (qui ne devraient pas exister) laissent des messages sans
+    // racine : on les détache de leur parent
+    let mut reachable = vec![false; tree.nodes.len()];
+    let mut stack = roots.clone();
+    while let Some(node) = stack.pop() {
+        if !reachable[node] {
+            reachable[node] = true;
+            stack.extend(&tree.nodes[node].children);
+        }
+    }
+    let detached: Vec<usize> = (0..tree.nodes.len())
+        .filter(|&node| !reachable[node] && tree.nodes[node].pos.is_some())
+        .collect();
+    for node in detached {
+        for parent in &mut tree.nodes {
+            parent.children.retain(|child| *child != node);
+        }
+        roots.push(node);
+    }
+
+    // Un message absent avec une seule réponse ne sert à rien
+    let roots: Vec<usize> = roots
+        .into_iter()
+        .map(|root| match tree.nodes[root].children.as_slice() {
+            [child] if tree.nodes[root].pos.is_none() => *child,
+            _ => root,
+        })
+        .collect();
+
+    // Les fils qui ont le même sujet sont regroupés sous le plus ancien
+    let mut roots_by_date = roots;
+    tree.sort(&mut roots_by_date);
+    let mut roots = Vec::new();
+    let mut by_subject: HashMap<String, usize> = HashMap::new();
+    for root in roots_by_date {
+        let subject = tree.subject(root);
+        match by_subject.get(&subject) {
+            Some(&first) if !subject.is_empty() => tree.nodes[first].children.push(root),
+            _ => {
+                by_subject.insert(subject, root);
+                roots.push(root);
+            }
+        }
+    }
+
+    for node in 0..tree.nodes.len() {
+        let mut children = std::mem::take(&mut tree.nodes[node].children);
+        tree.sort(&mut children);
+        tree.nodes[node].children = children;
+    }
+    roots
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn handle<'a, F: Fn(u32) -> Value, H: Fn(u32) -> Vec<Flag<'static>>>(tag: Tag<'a>, algorithm: ThreadAlgorithm, charset: Charset, criteria: SearchKey, uid: bool, messages: Vec<(u32, Value)>, get_message: F, local_flags: H) -> Vec<Reply<'a>> {
+    if let Some(response) = search::check_charset(&tag, Some(&charset)) {
+        return vec![response.into()];
+    }
+
+    let found = search::search(&criteria, &messages, get_message, local_flags);
+    let mut tree = Tree { messages: &messages, nodes: Vec::new() };
+    let roots = match algorithm {
+        ThreadAlgorithm::OrderedSubject => ordered_subject(&mut tree, found),
+        ThreadAlgorithm::References => references(&mut tree, found),
+    };
+
+    let id = |pos: usize| if uid { messages[pos].0 } else { (pos + 1) as u32 };
+    let mut thread = String::from("THREAD");
+    if !roots.is_empty() {
+        thread.push(' ');
+    }
+    for root in roots {
+        thread += &format!("({})", tree.format(root, &id));
+    }
+
+    vec![
+        Reply::Untagged(thread),
+        Response::Status(Status::ok(Some(tag), None, "THREAD completed").unwrap()).into(),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // Messages (id, réponse à, sujet, jour de janvier), avec les UIDs 1, 2...
+    fn threads(algorithm: ThreadAlgorithm, messages: &[(u64, u64, &str, u32)]) -> String {
+        let messages = messages
+            .iter()
+            .enumerate()
+            .map(|(pos, (id, response_id, subject, day))| {
+                let message = json!({
+                    "id": id,
+                    "responseId": response_id,
+                    "forwardId": 0,
+                    "subject": subject,
+                    "date": format!("2024-01-{day:02} 10:00:00"),
+                    "read": true,
+                    "answered": false,
+                    "brouillon": false,
+                });
+                (pos as u32 + 1, message)
+            })
+            .collect();
+        let replies = handle(
+            Tag::try_from("a").unwrap(),
+            algorithm,
+            Charset::try_from("UTF-8").unwrap(),
+            SearchKey::All,
+            true,
+            messages,
+            |_| Value::Null,
+            |_| vec![],
+        );
+        match &replies[0] {
+            Reply::Untagged(thread) => thread.clone(),
+            _ => panic!("THREAD attendu"),
+        }
+    }
+
+    #[test]
+    fn ordered_subject() {
+        let messages = [
+            (10, 0, "Re: Sortie", 3),
+            (11, 0, "Devoirs", 2),
+            (12, 0, "Sortie", 1),
+            (13, 0, "Fwd: sortie", 4),
+        ];
+        assert_eq!(threads(ThreadAlgorithm::OrderedSubject, &messages), "THREAD (3 (1)(4))(2)");
+        assert_eq!(threads(ThreadAlgorithm::OrderedSubject, &[]), "THREAD");
+    }
+
+    #[test]
+    fn references() {
+        let messages = [
+            (10, 0, "Sortie", 1),
+            (11, 10, "Re: Sortie", 2),
+            (12, 11, "Re: Sortie", 3),
+            (13, 10, "Re: Sortie", 4),
+            // Réponses au même message absent
+            (14, 99, "Re: Devoirs", 5),
+            (15, 99, "Re: Devoirs", 6),
+            // Une seule réponse à un message absent, et un sujet déjà vu
+            (16, 98, "Re: Sortie", 7),
+        ];
+        assert_eq!(threads(ThreadAlgorithm::References, &messages), "THREAD (1 (2 3)(4)(7))((5)(6))");
+    }
+}
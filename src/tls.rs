@@ -0,0 +1,109 @@
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Où en est le chiffrement d'une connexion.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Encryption {
+    // Pas de certificat configuré : tout passe en clair
+    Unavailable,
+    // STARTTLS possible mais pas encore fait : pas d'authentification
+    Available,
+    Active,
+}
+
+/// Charge le certificat (avec sa chaîne) et la clé privée, au format PEM.
+pub fn load_config(cert_path: &Path, key_path: &Path) -> Result<Arc<ServerConfig>, String> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        File::open(cert_path).map_err(|error| format!("{}: {}", cert_path.display(), error))?,
+    ))
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(|error| format!("{}: {}", cert_path.display(), error))?;
+    if certs.is_empty() {
+        return Err(format!("{}: no certificate found", cert_path.display()));
+    }
+
+    let key = rustls_pemfile::private_key(&mut BufReader::new(
+        File::open(key_path).map_err(|error| format!("{}: {}", key_path.display(), error))?,
+    ))
+    .map_err(|error| format!("{}: {}", key_path.display(), error))?
+    .ok_or_else(|| format!("{}: no private key found", key_path.display()))?;
+
+    ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map(Arc::new)
+        .map_err(|error| error.to_string())
+}
+
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ServerConnection, TcpStream>>),
+}
+
+impl Stream {
+    /// Connexion chiffrée dès le départ (IMAPS). La négociation se fait à la
+    /// première lecture ou écriture.
+    pub fn accept(stream: TcpStream, config: &Arc<ServerConfig>) -> io::Result<Stream> {
+        let connection = ServerConnection::new(config.clone()).map_err(io::Error::other)?;
+        Ok(Stream::Tls(Box::new(StreamOwned::new(connection, stream))))
+    }
+
+    /// Passe en TLS après STARTTLS.
+    pub fn start_tls(&mut self, config: &Arc<ServerConfig>) -> io::Result<()> {
+        match self {
+            // La copie garde le socket ouvert quand l'ancien flux est remplacé
+            Stream::Plain(stream) => *self = Stream::accept(stream.try_clone()?, config)?,
+            Stream::Tls(_) => return Err(io::Error::other("TLS already active")),
+        }
+        Ok(())
+    }
+
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(stream) => stream,
+            Stream::Tls(stream) => stream.get_ref(),
+        }
+    }
+
+    /// Prévient le client qu'on ferme la connexion (close_notify en TLS).
+    pub fn close(&mut self) {
+        if let Stream::Tls(stream) = self {
+            stream.conn.send_close_notify();
+            let _ = stream.flush();
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.tcp().set_read_timeout(timeout)
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buffer),
+            Stream::Tls(stream) => stream.read(buffer),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buffer),
+            Stream::Tls(stream) => stream.write(buffer),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}